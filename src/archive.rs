@@ -0,0 +1,247 @@
+use std::fs::Metadata;
+use std::io::{self, Read, Seek, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tar::{EntryType, Header};
+use zip::write::FullFileOptions;
+use zip::{CompressionMethod, DateTime, System, ZipWriter};
+
+use crate::config::ArchiveFormat;
+
+// Sizes past this can't be given in zip's 32-bit fields, so a file that might reach it
+// is written with zip64 fields; the margin covers deflate growing incompressible data slightly
+const ZIP64_THRESHOLD: u64 = 0xFFFF_0000;
+
+// A file or directory as the archive lists it
+pub(crate) struct Entry<'a> {
+    // Its path inside the archive, with / between names
+    pub(crate) name: &'a str,
+    pub(crate) size: u64,
+    pub(crate) mode: u32,
+    pub(crate) mtime: SystemTime,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(name: &'a str, metadata: &Metadata) -> io::Result<Self> {
+        Ok(Entry { name, size: metadata.len(), mode: entry_mode(metadata), mtime: metadata.modified()? })
+    }
+}
+
+// Function to give the Unix permission bits an entry is archived with
+fn entry_mode(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        match (metadata.is_dir(), metadata.permissions().readonly()) {
+            (true, _) => 0o755,
+            (false, true) => 0o444,
+            (false, false) => 0o644,
+        }
+    }
+}
+
+// Function to give a time as whole seconds since 1970, counting earlier times as 1970
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+// Function to give a time as the MS-DOS date and time zip headers use, in UTC, to two
+// seconds. Times outside 1980 to 2107 can't be given, and count as 1980.
+fn zip_time(time: SystemTime) -> DateTime {
+    // RFC 3339 in UTC, e.g. 2023-07-14T09:30:12Z
+    let text = humantime::format_rfc3339_seconds(time).to_string();
+    let field = |range: std::ops::Range<usize>| text[range].parse::<u8>().unwrap_or(0);
+    text[0..4].parse::<u16>().ok()
+        .and_then(|year| DateTime::from_date_and_time(year, field(5..7), field(8..10), field(11..13), field(14..16), field(17..19)).ok())
+        .unwrap_or_default()
+}
+
+// Function to give the zip options an entry is added with. Permissions are given the Unix
+// way whatever the platform, and the "UT" extra field keeps the modification time to the
+// second in Unix time, beyond what MS-DOS times can hold.
+fn zip_options(entry: &Entry) -> io::Result<FullFileOptions<'static, 'static>> {
+    let mut options = FullFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .system(System::Unix)
+        .unix_permissions(entry.mode)
+        .last_modified_time(zip_time(entry.mtime))
+        .large_file(entry.size >= ZIP64_THRESHOLD);
+    let mut timestamp = vec![1];
+    timestamp.extend_from_slice(&(unix_seconds(entry.mtime).min(u64::from(u32::MAX)) as u32).to_le_bytes());
+    options.add_extra_field(0x5455, timestamp, false)?;
+    Ok(options)
+}
+
+// Function to give the GNU tar header an entry is added with, which allows any size of
+// file and, through a long-name entry, any length of name
+fn tar_header(entry: &Entry, kind: EntryType, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_size(size);
+    header.set_mode(entry.mode);
+    header.set_mtime(unix_seconds(entry.mtime));
+    header
+}
+
+// Passes reads through, handing each chunk to `on_chunk` and counting the bytes
+struct Tracked<R, F> {
+    data: R,
+    on_chunk: F,
+    read: u64,
+}
+
+impl<R: Read, F: FnMut(&[u8])> Read for Tracked<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        (self.on_chunk)(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+// Function to copy all of `data` into `out` through `buffer`, so no file is ever held
+// in memory whole
+fn stream(data: &mut dyn Read, out: &mut dyn Write, buffer: &mut [u8]) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let read = match data.read(buffer) {
+            Ok(0) => return Ok(total),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        out.write_all(&buffer[..read])?;
+        total += read as u64;
+    }
+}
+
+// One archive at the target, in either format, taking entries by their path inside it.
+// A zip's local headers are filled in once each file's size and CRC are known, so it
+// needs to seek back; a tar.gz is written strictly front to back.
+pub(crate) enum ArchiveWriter<W: Write + Seek> {
+    Zip(Box<ZipWriter<W>>),
+    TarGz(tar::Builder<GzEncoder<W>>),
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub(crate) fn new(format: ArchiveFormat, out: W) -> Self {
+        match format {
+            ArchiveFormat::Zip => ArchiveWriter::Zip(Box::new(ZipWriter::new(out))),
+            ArchiveFormat::TarGz => ArchiveWriter::TarGz(tar::Builder::new(GzEncoder::new(out, Compression::default()))),
+        }
+    }
+
+    pub(crate) fn add_dir(&mut self, entry: &Entry) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Zip(zip) => Ok(zip.add_directory(entry.name, zip_options(entry)?)?),
+            ArchiveWriter::TarGz(tar) => tar.append_data(&mut tar_header(entry, EntryType::Directory, 0), entry.name, io::empty()),
+        }
+    }
+
+    // Function to stream a file of the entry's size into the archive, calling `on_chunk`
+    // with each chunk read, returning the bytes archived. A tar header gives the size up
+    // front, so a file that shrinks while it's read would leave the archive broken, and
+    // fails instead; one that grows is cut off at that size.
+    pub(crate) fn add_file(&mut self, entry: &Entry, data: &mut dyn Read, buffer: &mut [u8], on_chunk: impl FnMut(&[u8])) -> io::Result<u64> {
+        match self {
+            ArchiveWriter::Zip(zip) => {
+                zip.start_file(entry.name, zip_options(entry)?)?;
+                stream(&mut Tracked { data, on_chunk, read: 0 }, zip, buffer)
+            }
+            ArchiveWriter::TarGz(tar) => {
+                let size = entry.size;
+                let mut tracked = Tracked { data: data.take(size), on_chunk, read: 0 };
+                tar.append_data(&mut tar_header(entry, EntryType::Regular, size), entry.name, &mut tracked)?;
+                if tracked.read < size {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("file shrank from {} to {} bytes while it was archived", size, tracked.read)));
+                }
+                Ok(tracked.read)
+            }
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            ArchiveWriter::Zip(zip) => Ok((*zip).finish()?),
+            ArchiveWriter::TarGz(tar) => tar.into_inner()?.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use flate2::read::GzDecoder;
+    use zip::ZipArchive;
+
+    #[test]
+    fn gives_dos_times_in_two_second_steps_from_1980() {
+        let time = zip_time(humantime::parse_rfc3339("2023-07-14T09:30:13Z").unwrap());
+        assert_eq!((time.datepart(), time.timepart()), ((43 << 9) | (7 << 5) | 14, (9 << 11) | (30 << 5) | 6));
+        let time = zip_time(UNIX_EPOCH);
+        assert_eq!((time.datepart(), time.timepart()), ((1 << 5) | 1, 0));
+    }
+
+    #[test]
+    fn writes_long_tar_names_in_full() {
+        let name = format!("{}/file.txt", "nested".repeat(20));
+        let mut tar = ArchiveWriter::new(ArchiveFormat::TarGz, Cursor::new(Vec::new()));
+        let entry = Entry { name: &name, size: 3, mode: 0o644, mtime: UNIX_EPOCH };
+        tar.add_file(&entry, &mut &b"abc"[..], &mut [0; 8], |_| {}).unwrap();
+        let archive = tar.finish().unwrap().into_inner();
+
+        let mut reader = tar::Archive::new(GzDecoder::new(&archive[..]));
+        let mut entries = reader.entries().unwrap();
+        let mut file = entries.next().unwrap().unwrap();
+        assert_eq!(file.path().unwrap().to_str(), Some(name.as_str()));
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "abc");
+    }
+
+    #[test]
+    fn fails_a_tar_entry_whose_file_shrank() {
+        let mut tar = ArchiveWriter::new(ArchiveFormat::TarGz, Cursor::new(Vec::new()));
+        let entry = Entry { name: "a.txt", size: 10, mode: 0o644, mtime: UNIX_EPOCH };
+        let e = tar.add_file(&entry, &mut &b"abc"[..], &mut [0; 8], |_| {}).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn writes_zip64_fields_for_files_that_may_pass_4_gib() {
+        let mut zip = ArchiveWriter::new(ArchiveFormat::Zip, Cursor::new(Vec::new()));
+        // Sized past the threshold when it was listed, so written as if it might still be
+        let large = Entry { name: "large.bin", size: ZIP64_THRESHOLD, mode: 0o644, mtime: UNIX_EPOCH };
+        zip.add_file(&large, &mut &b"abc"[..], &mut [0; 8], |_| {}).unwrap();
+        let small = Entry { name: "small.bin", size: 3, ..large };
+        zip.add_file(&small, &mut &b"def"[..], &mut [0; 8], |_| {}).unwrap();
+        let mut reader = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+        let mut file = reader.by_name("large.bin").unwrap();
+        assert_eq!(file.version_needed(), 45);
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "abc");
+        drop(file);
+        assert_eq!(reader.by_name("small.bin").unwrap().version_needed(), 20);
+    }
+
+    #[test]
+    fn writes_zip64_end_records_past_65535_entries() {
+        let mut zip = ArchiveWriter::new(ArchiveFormat::Zip, Cursor::new(Vec::new()));
+        for i in 0..=u16::MAX as usize {
+            let name = format!("d{}", i);
+            zip.add_dir(&Entry { name: &name, size: 0, mode: 0o755, mtime: UNIX_EPOCH }).unwrap();
+        }
+        let archive = zip.finish().unwrap().into_inner();
+
+        assert!(archive.windows(4).any(|bytes| bytes == b"PK\x06\x06"));
+        assert_eq!(ZipArchive::new(Cursor::new(archive)).unwrap().len(), 65_536);
+    }
+}
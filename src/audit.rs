@@ -0,0 +1,88 @@
+use std::fs::{self, DirEntry};
+use std::io;
+use std::path::PathBuf;
+use tracing::{info, error, warn};
+
+use crate::config::Config;
+use crate::copy::{destination_path, hash_file, long_path_safe};
+use crate::error::MigrateError;
+use crate::stub::expected_size;
+
+/// Result of checking an earlier copy against the source with `verify_existing`
+#[derive(Debug, Default)]
+pub struct Audit {
+    /// Destinations that exist and match their source
+    pub matched: usize,
+    /// Destinations of stubs whose size is unknown, so only their existence was checked
+    pub unchecked: usize,
+    /// Source files with no destination
+    pub missing: Vec<PathBuf>,
+    /// Destinations whose size, or hash with `verify`, differs from the source
+    pub mismatched: Vec<PathBuf>,
+}
+
+// What checking one source file against its destination found
+pub(crate) enum FileAudit {
+    Matched,
+    Unchecked,
+    Missing,
+    Mismatched,
+}
+
+impl Audit {
+    /// Whether every source file has a matching destination
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+
+    pub(crate) fn record(&mut self, source: PathBuf, outcome: FileAudit) {
+        match outcome {
+            FileAudit::Matched => self.matched += 1,
+            FileAudit::Unchecked => self.unchecked += 1,
+            FileAudit::Missing => self.missing.push(source),
+            FileAudit::Mismatched => self.mismatched.push(source),
+        }
+    }
+
+    /// Print the final report
+    pub fn report(&self) {
+        info!("Verification:");
+        info!("  Matching:         {}", self.matched);
+        info!("  Existence only:   {}", self.unchecked);
+        info!("  Missing:          {}", self.missing.len());
+        info!("  Mismatched:       {}", self.mismatched.len());
+
+        for path in &self.missing {
+            error!("  Missing destination for {:?}", path);
+        }
+        for path in &self.mismatched {
+            error!("  Destination differs from {:?}", path);
+        }
+    }
+}
+
+// Function to compare one source file with its destination, by size and, with `verify`, hash
+pub(crate) fn audit_file(file: &DirEntry, config: &Config) -> Result<FileAudit, MigrateError> {
+    let source = file.path();
+    let destination = long_path_safe(destination_path(&source, config)?);
+    let destination_len = match fs::metadata(&destination) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(FileAudit::Missing),
+        Err(e) => return Err(e.into()),
+    };
+
+    if config.verify {
+        // Hashing a stub downloads it, so its real contents are compared
+        let (source_hash, destination_hash) = (hash_file(&source)?, hash_file(&destination)?);
+        return Ok(if source_hash == destination_hash { FileAudit::Matched } else { FileAudit::Mismatched });
+    }
+
+    match expected_size(file, config.stub_max_size) {
+        Some(len) if len == destination_len => Ok(FileAudit::Matched),
+        Some(len) => {
+            warn!("{:?} is {} bytes but {:?} is {} bytes", source, len, destination, destination_len);
+            Ok(FileAudit::Mismatched)
+        }
+        None => Ok(FileAudit::Unchecked),
+    }
+}
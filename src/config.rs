@@ -0,0 +1,628 @@
+use std::collections::BTreeMap;
+use std::fs;
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::error::MigrateError;
+use crate::template::{DestTemplate, RenamePattern};
+#[cfg(feature = "simulate-stubs")]
+use crate::simulate::SimulatedStubs;
+
+/// Default number of stubs hydrated at once by the prefetch phase
+pub const DEFAULT_PREFETCH_CONCURRENCY: usize = 16;
+
+/// Default number of post-copy commands allowed to run at once
+pub const DEFAULT_HOOK_CONCURRENCY: usize = 4;
+
+/// Default buffer size for reading stubs and copying files
+pub const DEFAULT_COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Upper bound for the exponential retry backoff
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Retry settings for files that are locked while they download
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// How many times to retry opening a locked stub file, or a copy that hit a transient error
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each attempt
+    #[serde(with = "humantime_serde")]
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before a retry attempt (starting at 1), doubling up to a cap
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(MAX_RETRY_DELAY)
+    }
+}
+
+/// What to do when a file's destination already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Leave the existing destination alone
+    #[default]
+    Skip,
+    /// Replace the existing destination
+    Overwrite,
+    /// Replace the existing destination only when it differs from the source,
+    /// as judged by `--compare`, making repeated runs cheap
+    OverwriteIfNewer,
+    /// Copy to a free name such as `report (1).pdf`
+    Rename,
+}
+
+/// How `--on-conflict overwrite-if-newer` decides whether an existing destination
+/// still matches its source, trading speed against how many changes are noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+pub enum CompareMode {
+    /// Size alone: the fastest, for one-off top-ups after an interrupted copy,
+    /// but an edit that keeps the size is missed
+    #[serde(rename = "size")]
+    Size,
+    /// Size and modification time, to the second: the usual choice for
+    /// re-running a sync, and safe unless files are edited with their times reset
+    #[default]
+    #[value(name = "size+mtime")]
+    #[serde(rename = "size+mtime")]
+    SizeMtime,
+    /// Size, then a SHA-256 of both files when the sizes match: slow, as every
+    /// file is read in full, but the only mode to trust when the destination may
+    /// have been touched by another tool or times are not preserved
+    #[serde(rename = "hash")]
+    Hash,
+}
+
+/// How a file's contents are copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+pub enum CopyMethod {
+    /// Try reflink, then copy_file_range, then buffered, using the first that
+    /// the source and target filesystems support
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// A copy-on-write clone (btrfs, XFS, APFS): near-instant and sharing the
+    /// source's disk space, but only within one filesystem
+    #[serde(rename = "reflink")]
+    Reflink,
+    /// Let the kernel copy the data (Linux), which may clone it or, on network
+    /// filesystems, copy it on the server
+    #[value(name = "copy_file_range")]
+    #[serde(rename = "copy_file_range")]
+    CopyFileRange,
+    /// Read and write through a buffer, hashing on the way, which saves
+    /// --verify and --checksum-manifest a read of the source
+    #[serde(rename = "buffered")]
+    Buffered,
+}
+
+impl CopyMethod {
+    /// The name used on the command line and in logs
+    pub fn name(self) -> &'static str {
+        match self {
+            CopyMethod::Auto => "auto",
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Buffered => "buffered",
+        }
+    }
+}
+
+/// What to do when a destination differs only in case from an existing file
+/// on a case-insensitive target such as OneDrive or NTFS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseCollisionPolicy {
+    /// Fail the file so the collision is reported
+    #[default]
+    Error,
+    /// Copy to a free name such as `report (1).pdf`
+    Rename,
+    /// Leave the existing file alone and skip this one
+    Skip,
+}
+
+/// What to do with a file whose destination name Windows filesystems (NTFS, and so
+/// OneDrive) refuse, such as `CON`, `a:b` or a name ending in a dot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InvalidNamePolicy {
+    /// Log each one before copying, then copy as usual
+    #[default]
+    Warn,
+    /// Stop before writing anything
+    Error,
+    /// Copy under a valid name, with illegal characters replaced by `_`
+    Sanitize,
+    /// Leave the file out
+    Skip,
+}
+
+/// Which half of the tree a run creates in the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyOnly {
+    /// Directories and files
+    #[default]
+    Both,
+    /// Only the directory skeleton, leaving the files for a later run
+    Dirs,
+    /// Only the files and the directories holding them, leaving out empty ones
+    Files,
+}
+
+/// How `--min-size`/`--max-size` treat stubs whose cloud size is unknown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StubSizeFilter {
+    /// Compare the placeholder size the OS reports (usually 0)
+    #[default]
+    Reported,
+    /// Let stubs through regardless of the limits
+    Bypass,
+}
+
+/// How copy progress is displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProgressMode {
+    /// One bar per file being copied or stub being fetched, below a header bar
+    /// tracking completed files and bytes for the whole run
+    #[default]
+    PerFile,
+    /// A single bar tracking completed files and bytes for the whole run
+    Aggregate,
+}
+
+/// The order files are processed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileOrder {
+    /// Largest first, which keeps the workers busy until the end
+    #[default]
+    Largest,
+    /// Smallest first, to clear many small files quickly
+    Smallest,
+    /// Most recently modified first
+    Newest,
+    /// Least recently modified first
+    Oldest,
+    /// By path
+    Name,
+}
+
+/// The kind of archive `--archive` packs the source into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+pub enum ArchiveFormat {
+    /// A zip of deflated files, which every OS opens without extra tools
+    #[value(name = "zip")]
+    #[serde(rename = "zip")]
+    Zip,
+    /// A gzipped tar, which keeps Unix permissions and suits `tar` and backup tools
+    #[value(name = "tar.gz")]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+/// How `--list-only` prints its inventory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListFormat {
+    /// Aligned columns for reading in a terminal
+    #[default]
+    Table,
+    Csv,
+    /// A JSON array of `{path, size, stub}` objects
+    Json,
+}
+
+/// A timestamp `--preserve-times` can carry over from a source file to its copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreservedTime {
+    /// Modification time
+    Mtime,
+    /// Access time, as it was before the copy read the file
+    Atime,
+    /// Creation time, on Windows and macOS only
+    Btime,
+    /// No timestamps at all; can't be listed with the others
+    None,
+}
+
+/// How symbolic links in the source are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Copy what the link points to, skipping directories already visited
+    #[default]
+    Follow,
+    /// Ignore symlinks entirely
+    Skip,
+    /// Recreate the link itself at the destination
+    CopyAsLink,
+}
+
+/// Everything a [`Migrator`](crate::Migrator) needs to know about a run.
+///
+/// A config can also be loaded from a TOML file with [`Config::load`]. Keys are
+/// the field names in kebab-case (`on-conflict = "rename"`), durations are
+/// strings like `"30s"` and times are RFC 3339.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// The source directory (box folder)
+    pub source: PathBuf,
+    /// The target directory (one-drive folder)
+    pub target: PathBuf,
+    /// Further directories every file is also copied to, laid out like `target`
+    pub extra_targets: Vec<PathBuf>,
+    /// Only copy files matching one of these globs (all files when empty)
+    pub include: Vec<String>,
+    /// Skip files and directories matching any of these globs
+    pub exclude: Vec<String>,
+    /// Never descend into directories matching any of these globs
+    pub exclude_dir: Vec<String>,
+    /// Skip dotfiles, and files with the hidden attribute on Windows
+    pub skip_hidden: bool,
+    /// Only copy files at most this many directories below the source (0 for the source itself)
+    pub max_depth: Option<usize>,
+    /// Only create target directories that receive a file, instead of every directory walked
+    pub prune_empty_dirs: bool,
+    /// Maximum number of files processed in parallel, defaulting to the number of CPUs
+    pub concurrency: Option<usize>,
+    /// Threads used to walk the source, overriding `concurrency` for the traversal
+    pub walk_threads: Option<usize>,
+    /// Threads used to copy files, overriding `concurrency` for the copy phase
+    pub copy_threads: Option<usize>,
+    /// Threads copying into each target from its own queue, so every target takes files
+    /// at its own pace; the copy threads then only fetch. Ignored with `move_source`,
+    /// which needs every copy of a file done before removing it.
+    pub workers_per_target: Option<usize>,
+    /// Most files hashed at once by `verify` and `checksum_manifest`, across all
+    /// copy threads; unset hashes in each copy thread with no limit
+    pub hash_threads: Option<usize>,
+    /// Log what would happen without touching disk
+    pub dry_run: bool,
+    /// Delete each source file after its copy has been verified
+    #[serde(rename = "move")]
+    pub move_source: bool,
+    /// Read back each copy and compare its SHA-256 with the source's, which a buffered copy hashes as it goes
+    pub verify: bool,
+    /// Source timestamps carried over to copied files; empty for none
+    pub preserve_times: Vec<PreservedTime>,
+    /// Stop at the first file that fails instead of attempting every file
+    pub fail_fast: bool,
+    /// Retry settings for locked stub files and transient copy errors
+    pub retry: RetryPolicy,
+    /// Files at or below this size are treated as cloud stubs (ignored on Windows)
+    pub stub_max_size: u64,
+    /// Write a JSONL record of every file processed to this path
+    pub manifest: Option<PathBuf>,
+    /// Flush and fsync the manifest after this many records
+    pub checkpoint_files: Option<usize>,
+    /// Flush and fsync the manifest when a record arrives this long after the last checkpoint
+    #[serde(with = "humantime_serde")]
+    pub checkpoint_interval: Option<Duration>,
+    /// Skip files a previous run's manifest records as copied, unless the
+    /// source size or modification time has changed since
+    pub resume: Option<PathBuf>,
+    /// Carry on partial copies left by an interrupted run instead of starting
+    /// them over, once their contents are checked against the source
+    pub resume_partial: bool,
+    /// Copy only the files a previous run's manifest records as failed, without walking the source
+    pub retry_failed: Option<PathBuf>,
+    /// Copy only the files new or changed since the run that wrote this manifest
+    /// and, with `mirror`, delete the copies of files removed since
+    pub since_manifest: Option<PathBuf>,
+    /// Copy only the files listed (one path relative to the source per line) in this file
+    pub source_list: Option<PathBuf>,
+    /// After copying, delete target files that no source file maps to
+    pub mirror: bool,
+    /// The order files are processed in
+    pub order: FileOrder,
+    /// Only process the first this many files in `order`, for a trial run
+    pub max_files: Option<usize>,
+    /// Hard link files identical to one already copied instead of copying them again
+    pub dedup: bool,
+    /// Check an earlier copy against the source instead of copying
+    pub verify_existing: bool,
+    /// How to handle destinations that already exist
+    pub on_conflict: ConflictPolicy,
+    /// How `OverwriteIfNewer` compares an existing destination with its source
+    pub compare: CompareMode,
+    /// What to do when a destination differs only in case from an existing file
+    pub on_case_collision: CaseCollisionPolicy,
+    /// What to do with files whose destination names Windows filesystems refuse
+    pub on_invalid_name: InvalidNamePolicy,
+    /// Create only the directories, only the files, or both
+    pub only: CopyOnly,
+    /// How renamed copies of colliding files are named
+    pub rename_pattern: RenamePattern,
+    /// Skip files smaller than this many bytes
+    pub min_size: Option<u64>,
+    /// Skip files larger than this many bytes
+    pub max_size: Option<u64>,
+    /// Skip files last modified before this time
+    #[serde(with = "humantime_serde")]
+    pub newer_than: Option<SystemTime>,
+    /// Skip files last modified at or after this time
+    #[serde(with = "humantime_serde")]
+    pub older_than: Option<SystemTime>,
+    /// How the size limits treat stubs of unknown size
+    #[serde(rename = "filter-stubs-by")]
+    pub stub_size_filter: StubSizeFilter,
+    /// How copy progress is displayed
+    pub progress: ProgressMode,
+    /// Draw progress bars at all
+    pub show_progress: bool,
+    /// How symbolic links in the source are handled
+    pub symlinks: SymlinkPolicy,
+    /// Copy POSIX permission bits (and ownership, when running as root) to copied files
+    pub preserve_permissions: bool,
+    /// Copy extended attributes to copied files (macOS and Linux only)
+    pub preserve_xattrs: bool,
+    /// Copy every file straight into the target root, renaming on name clashes
+    pub flatten: bool,
+    /// When flattening, join the relative source path into the file name with this separator
+    pub flatten_separator: Option<String>,
+    /// Download every stub in a separate phase before any file is copied
+    pub prefetch: bool,
+    /// Number of stubs downloaded in parallel during the prefetch phase
+    pub prefetch_concurrency: usize,
+    /// Give up on a stub download or a copy that takes longer than this
+    #[serde(with = "humantime_serde")]
+    pub file_timeout: Option<Duration>,
+    /// Only copy files whose size and modification time hold still for this long
+    #[serde(with = "humantime_serde")]
+    pub settle_time: Option<Duration>,
+    /// Bytes read per system call when fetching stubs and copying files
+    pub copy_buffer_size: usize,
+    /// How file contents are copied; stubs are always read through the buffer
+    pub copy_method: CopyMethod,
+    /// Warn instead of stopping when the target looks too small for the files to copy
+    pub ignore_space: bool,
+    /// Bytes to always leave free on the target: the free-space check counts them
+    /// as needed, and no new copy starts once one would eat into them
+    pub min_free_space: Option<u64>,
+    /// Write newline-delimited JSON progress events to this path ("-" for stdout)
+    pub progress_json: Option<PathBuf>,
+    /// Write a `sha256sum`-compatible list of every copied file to this path
+    pub checksum_manifest: Option<PathBuf>,
+    /// Log files done, bytes done, throughput and ETA at this interval
+    #[serde(with = "humantime_serde")]
+    pub report_interval: Option<Duration>,
+    /// Serve counters for Prometheus at `http://<addr>/metrics` while copying
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Before copying, stop if any two source files map to the same destination
+    pub check_conflicts: bool,
+    /// Cap the combined copy rate of all workers, in bytes per second
+    pub max_rate: Option<u64>,
+    /// Print an inventory of the source instead of copying anything
+    pub list_only: bool,
+    /// Download the source's stubs in place instead of copying anything
+    pub stub_hydrate_only: bool,
+    /// Count the source's stubs and hydrated files instead of copying anything
+    pub probe: bool,
+    /// Pack the files into one archive at the target path instead of copying them loose
+    pub archive: Option<ArchiveFormat>,
+    pub list_format: ListFormat,
+    /// Copy files that turn out to be genuinely empty rather than stubs
+    pub copy_empty_files: bool,
+    /// Program run after each successful copy with the source and destination paths
+    pub post_copy_command: Option<PathBuf>,
+    /// Fail a file whose post-copy command exits unsuccessfully, rather than warning
+    pub hook_fatal: bool,
+    /// Most post-copy commands running at once
+    pub hook_concurrency: usize,
+    /// Place files with these extensions (case-insensitive, without the dot) in
+    /// the given target subdirectory instead of mirroring the source tree
+    #[serde(rename = "route")]
+    pub routes: BTreeMap<String, PathBuf>,
+    /// Place every file where this template says, relative to the target,
+    /// instead of mirroring the source tree
+    pub dest_template: Option<DestTemplate>,
+    /// Make matching files act like slow, locked stubs, for testing
+    #[cfg(feature = "simulate-stubs")]
+    #[serde(skip)]
+    pub simulate_stubs: Option<SimulatedStubs>,
+}
+
+impl Config {
+    /// Create a config with default settings for copying `source` into `target`
+    pub fn new(source: impl Into<PathBuf>, target: impl Into<PathBuf>) -> Self {
+        Config {
+            source: source.into(),
+            target: target.into(),
+            extra_targets: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            exclude_dir: Vec::new(),
+            skip_hidden: false,
+            max_depth: None,
+            prune_empty_dirs: false,
+            concurrency: None,
+            walk_threads: None,
+            copy_threads: None,
+            workers_per_target: None,
+            hash_threads: None,
+            dry_run: false,
+            move_source: false,
+            verify: false,
+            preserve_times: vec![PreservedTime::Mtime],
+            fail_fast: false,
+            retry: RetryPolicy::default(),
+            stub_max_size: 0,
+            manifest: None,
+            checkpoint_files: None,
+            checkpoint_interval: None,
+            resume: None,
+            resume_partial: false,
+            retry_failed: None,
+            since_manifest: None,
+            source_list: None,
+            mirror: false,
+            order: FileOrder::default(),
+            max_files: None,
+            dedup: false,
+            verify_existing: false,
+            on_conflict: ConflictPolicy::default(),
+            compare: CompareMode::default(),
+            on_case_collision: CaseCollisionPolicy::default(),
+            on_invalid_name: InvalidNamePolicy::default(),
+            only: CopyOnly::default(),
+            rename_pattern: RenamePattern::default(),
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            stub_size_filter: StubSizeFilter::default(),
+            progress: ProgressMode::default(),
+            show_progress: true,
+            symlinks: SymlinkPolicy::default(),
+            preserve_permissions: false,
+            preserve_xattrs: false,
+            flatten: false,
+            flatten_separator: None,
+            prefetch: false,
+            prefetch_concurrency: DEFAULT_PREFETCH_CONCURRENCY,
+            file_timeout: None,
+            settle_time: None,
+            copy_buffer_size: DEFAULT_COPY_BUFFER_SIZE,
+            copy_method: CopyMethod::default(),
+            ignore_space: false,
+            min_free_space: None,
+            progress_json: None,
+            checksum_manifest: None,
+            report_interval: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
+            check_conflicts: false,
+            max_rate: None,
+            list_only: false,
+            stub_hydrate_only: false,
+            probe: false,
+            archive: None,
+            list_format: ListFormat::default(),
+            copy_empty_files: true,
+            post_copy_command: None,
+            hook_fatal: false,
+            hook_concurrency: DEFAULT_HOOK_CONCURRENCY,
+            routes: BTreeMap::new(),
+            dest_template: None,
+            #[cfg(feature = "simulate-stubs")]
+            simulate_stubs: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::new(PathBuf::new(), PathBuf::new())
+    }
+}
+
+impl Config {
+    /// Load a config from a TOML file. Settings the file leaves out keep their
+    /// defaults, and unknown keys are rejected so typos don't go unnoticed.
+    pub fn load(path: &Path) -> Result<Self, MigrateError> {
+        let invalid = |message: String| MigrateError::InvalidConfig { path: path.to_path_buf(), message };
+        let contents = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let mut config: Config = toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+
+        if let Some((extension, subdir)) = config.routes.iter().find(|(_, subdir)| !is_inside(subdir)) {
+            return Err(invalid(format!("route for {:?} must be a relative path inside the target, not {:?}", extension, subdir)));
+        }
+        if config.dest_template.is_some() && (config.flatten || !config.routes.is_empty()) {
+            return Err(invalid("dest-template decides where every file goes, so it can't be combined with flatten or route".to_string()));
+        }
+        // The same limits the command line puts on these: a zero would stall or empty every copy
+        let zero = [
+            ("copy-buffer-size", config.copy_buffer_size == 0),
+            ("max-rate", config.max_rate == Some(0)),
+            ("workers-per-target", config.workers_per_target == Some(0)),
+            ("report-interval", config.report_interval == Some(Duration::ZERO)),
+        ];
+        if let Some((key, _)) = zero.iter().find(|(_, is_zero)| *is_zero) {
+            return Err(invalid(format!("{} must be greater than zero", key)));
+        }
+        if config.preserve_times.contains(&PreservedTime::None) {
+            if config.preserve_times.len() > 1 {
+                return Err(invalid("preserve-times can't list \"none\" alongside other timestamps".to_string()));
+            }
+            config.preserve_times.clear();
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Every directory files are copied to: `target`, then `extra_targets`
+    pub fn targets(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.target.as_path()).chain(self.extra_targets.iter().map(PathBuf::as_path))
+    }
+}
+
+/// Check that a relative path stays inside the directory it is joined to, as
+/// `--route` subdirectories must
+pub fn is_inside(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_rejects_zero_where_the_flags_do() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("xerox.toml");
+        for line in ["copy-buffer-size = 0", "max-rate = 0", "workers-per-target = 0", "report-interval = \"0s\""] {
+            fs::write(&path, format!("source = \"box\"\ntarget = \"onedrive\"\n{}\n", line)).unwrap();
+            let error = Config::load(&path).unwrap_err();
+            assert!(matches!(&error, MigrateError::InvalidConfig { message, .. } if message.ends_with("must be greater than zero")), "{}: {}", line, error);
+        }
+
+        fs::write(&path, "source = \"box\"\ntarget = \"onedrive\"\ncopy-buffer-size = 4096\nmax-rate = 1000\n").unwrap();
+        let config = Config::load(&path).unwrap();
+        assert_eq!((config.copy_buffer_size, config.max_rate), (4096, Some(1000)));
+    }
+
+    #[test]
+    fn load_takes_none_as_no_timestamps_but_only_on_its_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("xerox.toml");
+        fs::write(&path, "source = \"box\"\ntarget = \"onedrive\"\npreserve-times = [\"none\"]\n").unwrap();
+        assert!(Config::load(&path).unwrap().preserve_times.is_empty());
+
+        fs::write(&path, "source = \"box\"\ntarget = \"onedrive\"\npreserve-times = [\"mtime\", \"none\"]\n").unwrap();
+        let error = Config::load(&path).unwrap_err();
+        assert!(matches!(&error, MigrateError::InvalidConfig { message, .. } if message.contains("\"none\"")), "{}", error);
+    }
+}
@@ -0,0 +1,1164 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, DirEntry, File, FileTimes, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use filetime::FileTime;
+use rayon::ThreadPool;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, error, warn};
+
+use crate::config::{CaseCollisionPolicy, CompareMode, Config, ConflictPolicy, CopyMethod, CopyOnly, InvalidNamePolicy, PreservedTime, SymlinkPolicy};
+use crate::dedup::{Claim, DedupIndex};
+use crate::error::MigrateError;
+use crate::fastcopy::{copy_file_range, reflink};
+use crate::get_file_size;
+use crate::hook::PostCopyHook;
+use crate::names::sanitize_path;
+use crate::progress::{FileProgress, Progress};
+use crate::summary::FileOutcome;
+use crate::template::RenamePattern;
+use crate::throttle::RateLimiter;
+use crate::timeout::{with_timeout, Cancel};
+
+/// Suffix of the temp files copies are written to before being renamed into place
+pub(crate) const PART_SUFFIX: &str = ".xerox.part";
+
+// Longest path plain Windows APIs accept: MAX_PATH (260) less the 12 characters a
+// directory must leave for an 8.3 file name
+#[cfg(any(windows, test))]
+const MAX_PLAIN_PATH: usize = 248;
+
+// Function to spell an absolute drive path (`C:\...`) in extended-length form. Windows
+// skips all normalization for such paths, so `/` separators are replaced here. Other
+// paths are returned unchanged; UNC roots are canonicalized in extended form already.
+#[cfg(any(windows, test))]
+fn extended_length(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let is_drive_path = bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/');
+    if is_drive_path {
+        format!(r"\\?\{}", path.replace('/', "\\"))
+    } else {
+        path.to_string()
+    }
+}
+
+// Function to switch a target path to extended-length form once it is too long for
+// plain Windows APIs, which otherwise fail on deep trees with unhelpful errors
+#[cfg(windows)]
+pub(crate) fn long_path_safe(path: PathBuf) -> PathBuf {
+    match path.to_str() {
+        Some(text) if text.encode_utf16().count() >= MAX_PLAIN_PATH => PathBuf::from(extended_length(text)),
+        _ => path,
+    }
+}
+
+// Function to prepare a target path for file system calls; nothing to do outside Windows
+#[cfg(not(windows))]
+pub(crate) fn long_path_safe(path: PathBuf) -> PathBuf {
+    path
+}
+
+// Function to drop the `\\?\` prefix from drive paths on Windows, as added by canonicalize
+// or long_path_safe, keeping logged and recorded paths readable and comparable
+#[cfg(windows)]
+pub(crate) fn plain_path(path: PathBuf) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) if matches!(prefix.kind(), Prefix::VerbatimDisk(_)) => {
+            let text = path.to_string_lossy();
+            PathBuf::from(&text[4..])
+        }
+        _ => path,
+    }
+}
+
+// Function to tidy a path for logging and records; it is already plain outside Windows
+#[cfg(not(windows))]
+pub(crate) fn plain_path(path: PathBuf) -> PathBuf {
+    path
+}
+
+// Function to get a file or directory's path relative to the source root, which it must be below
+fn relative_to_source<'a>(source: &'a Path, source_root: &Path) -> Result<&'a Path, MigrateError> {
+    match source.strip_prefix(source_root) {
+        Ok(relative_path) if !relative_path.as_os_str().is_empty() => Ok(relative_path),
+        _ => Err(MigrateError::OutsideSource { path: source.to_path_buf(), source_root: source_root.to_path_buf() }),
+    }
+}
+
+// Function to compute the target (one-drive) directory a source file or directory is placed in
+pub(crate) fn target_parent(source: &Path, target: &Path, source_root: &Path) -> Result<PathBuf, MigrateError> {
+    let mut components = relative_to_source(source, source_root)?.components();
+    // Drop the entry's own name, leaving the directories above it
+    components.next_back();
+    let parent = components.as_path();
+
+    // Entries directly in the source root go straight into the target root
+    if parent.as_os_str().is_empty() {
+        Ok(target.to_path_buf())
+    } else {
+        Ok(target.join(parent))
+    }
+}
+
+// Function to list every target directory a run needs, once each: those holding a file
+// and, unless pruning or copying only files, every source directory walked. create_dir_all makes the
+// ancestors too, so only the deepest directories are kept.
+pub(crate) fn target_directories(files: &[DirEntry], dirs: &[PathBuf], config: &Config) -> Result<Vec<PathBuf>, MigrateError> {
+    let mut needed = BTreeSet::new();
+    for file in files {
+        if let Some(parent) = destination_path(&file.path(), config)?.parent() {
+            needed.insert(parent.to_path_buf());
+        }
+    }
+    // Only a mirrored tree has a place for the source's own folders
+    if !config.flatten && !config.prune_empty_dirs && config.dest_template.is_none() && config.only != CopyOnly::Files {
+        for dir in dirs {
+            needed.insert(directory_destination(dir, config)?);
+        }
+    }
+
+    // Sorted paths list a directory's descendants right after it, so a directory
+    // is a leaf unless the next path is inside it
+    let needed: Vec<PathBuf> = needed.into_iter().collect();
+    Ok(needed.iter().enumerate()
+        .filter(|(i, dir)| needed.get(i + 1).is_none_or(|next| !next.starts_with(dir)))
+        .map(|(_, dir)| long_path_safe(dir.clone()))
+        .collect())
+}
+
+// Function to create directory structure in the target (one-drive) location. Workers may
+// create overlapping ancestors at the same time, so a directory that already exists is fine.
+pub(crate) fn create_target_directory_structure(target_dir: &Path, dry_run: bool) -> io::Result<()> {
+    if target_dir.is_dir() {
+        return Ok(());
+    }
+
+    if dry_run {
+        info!("Would create directory: {:?}", target_dir);
+        return Ok(());
+    }
+
+    match fs::create_dir_all(target_dir) {
+        Ok(()) => {
+            info!("Created directory: {:?}", target_dir);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && target_dir.is_dir() => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Function to compute the name a source file gets in the target root when flattening
+fn flat_file_name(relative_path: &Path, config: &Config) -> OsString {
+    match &config.flatten_separator {
+        Some(separator) => {
+            let mut name = OsString::new();
+            for (i, component) in relative_path.iter().enumerate() {
+                if i > 0 {
+                    name.push(separator);
+                }
+                name.push(component);
+            }
+            name
+        }
+        None => relative_path.file_name().unwrap_or_default().to_os_string(),
+    }
+}
+
+// Function to find the --route subdirectory for a source file's extension, if any
+fn route_for<'a>(source: &Path, config: &'a Config) -> Option<&'a Path> {
+    let extension = source.extension()?.to_str()?;
+    config.routes.iter()
+        .find(|(route_extension, _)| route_extension.trim_start_matches('.').eq_ignore_ascii_case(extension))
+        .map(|(_, subdir)| subdir.as_path())
+}
+
+// Function to compute where a source file lands in the target
+pub(crate) fn destination_path(source: &Path, config: &Config) -> Result<PathBuf, MigrateError> {
+    Ok(in_target(unsanitized_destination(source, config)?, config))
+}
+
+// Function to compute where a source file lands in the target before any names are sanitized
+pub(crate) fn unsanitized_destination(source: &Path, config: &Config) -> Result<PathBuf, MigrateError> {
+    let relative_path = relative_to_source(source, &config.source)?;
+    let file_name = relative_path.file_name().unwrap_or_default();
+
+    if let Some(template) = &config.dest_template {
+        return Ok(config.target.join(template.render(relative_path, source)?));
+    }
+    // Routed files go straight into their subdirectory instead of mirroring the source
+    if let Some(subdir) = route_for(source, config) {
+        return Ok(config.target.join(subdir).join(file_name));
+    }
+    if config.flatten {
+        return Ok(config.target.join(flat_file_name(relative_path, config)));
+    }
+    Ok(target_parent(source, &config.target, &config.source)?.join(file_name))
+}
+
+// Function to compute where a source directory lands in a mirrored target
+pub(crate) fn directory_destination(dir: &Path, config: &Config) -> Result<PathBuf, MigrateError> {
+    Ok(in_target(config.target.join(relative_to_source(dir, &config.source)?), config))
+}
+
+// Function to rewrite the names below the target root that Windows filesystems refuse,
+// when sanitizing, so every path into the target agrees
+fn in_target(path: PathBuf, config: &Config) -> PathBuf {
+    match (config.on_invalid_name, path.strip_prefix(&config.target)) {
+        (InvalidNamePolicy::Sanitize, Ok(relative_path)) => config.target.join(sanitize_path(relative_path)),
+        _ => path,
+    }
+}
+
+// Function to name the temp file a copy is written to before it is renamed into place. Routed
+// and templated files from different folders can share a destination, so the name also
+// carries a hash of `source`, their path within the source, which stays the same across runs
+// for --resume-partial to find.
+pub(crate) fn part_path(path: &Path, source: &Path) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(source.to_string_lossy().as_bytes());
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}{}", &to_hex(hasher)[..16], PART_SUFFIX));
+    path.with_file_name(name)
+}
+
+// Names in each target directory by their lowercase form, so a re-run over a tree that is
+// already copied lists each directory once rather than once per existing destination
+#[derive(Default)]
+struct Listings(Mutex<HashMap<PathBuf, HashMap<String, Vec<OsString>>>>);
+
+// Function to find an existing entry whose name differs from `path`'s only by case. On a
+// case-insensitive target such an entry makes `path` look like it already exists. A listing
+// read earlier settles the usual case, the exact name being there; anything else is checked
+// against a fresh listing, since this run may have added names since.
+fn case_variant(path: &Path, listings: &Listings) -> Option<OsString> {
+    let (parent, file_name) = (path.parent()?, path.file_name()?);
+    let name = file_name.to_string_lossy().to_lowercase();
+    let exact = |listing: &HashMap<String, Vec<OsString>>| listing.get(&name).is_some_and(|names| names.iter().any(|existing| existing == file_name));
+    if listings.0.lock().unwrap().get(parent).is_some_and(exact) {
+        return None;
+    }
+
+    let mut listing: HashMap<String, Vec<OsString>> = HashMap::new();
+    for entry in fs::read_dir(parent).ok()?.flatten() {
+        let existing = entry.file_name();
+        listing.entry(existing.to_string_lossy().to_lowercase()).or_default().push(existing);
+    }
+    // The exact name is there, so this is an ordinary conflict
+    let variant = if exact(&listing) { None } else { listing.get(&name).and_then(|names| names.first().cloned()) };
+    listings.0.lock().unwrap().insert(parent.to_path_buf(), listing);
+    variant
+}
+
+// Function to find a free name next to `path` from the rename pattern
+pub(crate) fn free_path(path: &Path, pattern: &RenamePattern) -> PathBuf {
+    pattern.candidates(path)
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+// Function to claim `path`, or its first free renamed variant, by creating an empty placeholder.
+// Flattened, routed and templated files from different folders can race for the same name,
+// so the check and the claim must be one atomic step. Returns None when `path` or one of its
+// variants left by an earlier run already holds an unchanged copy of `source`, so a re-run
+// changes nothing.
+fn claim_free_path(path: &Path, source: &Path, target: &Target) -> io::Result<Option<PathBuf>> {
+    let mut claimed = target.claimed.lock().unwrap();
+    let candidates = std::iter::once(path.to_path_buf()).chain(target.config.rename_pattern.candidates(path));
+    for candidate in candidates {
+        match File::create_new(&candidate) {
+            Ok(_) => {
+                claimed.insert(candidate.clone());
+                return Ok(Some(candidate));
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if !claimed.contains(&candidate) && !needs_copy(source, &candidate, target.config.compare)? {
+                    return Ok(None);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("the candidate names never run out")
+}
+
+// Whether a destination that other files may also go to was free, existed before, or was
+// already taken by another file in this run
+#[derive(Debug, PartialEq, Eq)]
+enum Destination {
+    Free,
+    Exists,
+    Taken,
+}
+
+// Function to take `path` for one file of this run, in one step, so the first file to reach
+// a shared destination is the only one to copy there. A free path gets an empty placeholder.
+fn claim_destination(path: &Path, target: &Target) -> io::Result<Destination> {
+    let mut claimed = target.claimed.lock().unwrap();
+    if !claimed.insert(path.to_path_buf()) {
+        return Ok(Destination::Taken);
+    }
+    match File::create_new(path) {
+        Ok(_) => Ok(Destination::Free),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(Destination::Exists),
+        Err(e) => Err(e),
+    }
+}
+
+// An empty file claiming a destination until the copy replaces it, removed if the copy fails
+// so a later run doesn't take it for an earlier copy
+struct Placeholder(Option<PathBuf>);
+
+impl Placeholder {
+    // Function to leave the destination be, once the copy is in place
+    fn keep(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for Placeholder {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// Function to decide whether an existing destination differs from its source under the
+// given comparison. Times are compared to the second, since target filesystems may
+// store them with less precision than the source.
+pub(crate) fn needs_copy(src: &Path, dst: &Path, compare: CompareMode) -> io::Result<bool> {
+    let src_metadata = fs::metadata(src)?;
+    let dst_metadata = match fs::metadata(dst) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    };
+
+    if src_metadata.len() != dst_metadata.len() {
+        return Ok(true);
+    }
+
+    match compare {
+        CompareMode::Size => Ok(false),
+        CompareMode::SizeMtime => {
+            let src_mtime = FileTime::from_last_modification_time(&src_metadata);
+            let dst_mtime = FileTime::from_last_modification_time(&dst_metadata);
+            Ok(src_mtime.unix_seconds() != dst_mtime.unix_seconds())
+        }
+        CompareMode::Hash => Ok(hash_file(src)? != hash_file(dst)?),
+    }
+}
+
+/// Check that a file is not still being written by statting it twice, `settle`
+/// apart, and comparing its size and modification time.
+pub fn is_stable(path: &Path, settle: Duration) -> io::Result<bool> {
+    let before = fs::metadata(path)?;
+    thread::sleep(settle);
+    let after = fs::metadata(path)?;
+
+    Ok(before.len() == after.len() && before.modified()? == after.modified()?)
+}
+
+// Function to recreate a symlink at the destination, pointing where the source link points
+fn copy_symlink(source: &Path, target: &Path) -> io::Result<()> {
+    let link_target = fs::read_link(source)?;
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(&link_target, target)
+    }
+
+    #[cfg(windows)]
+    {
+        if fs::metadata(source).is_ok_and(|metadata| metadata.is_dir()) {
+            std::os::windows::fs::symlink_dir(&link_target, target)
+        } else {
+            std::os::windows::fs::symlink_file(&link_target, target)
+        }
+    }
+}
+
+// Function to copy the permission bits, and ownership when running as root, onto a copied file
+#[cfg(unix)]
+fn copy_permissions(source: &Path, target: &Path) -> io::Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let metadata = fs::metadata(source)?;
+    fs::set_permissions(target, fs::Permissions::from_mode(metadata.mode()))?;
+
+    // Only root may hand files to other users
+    if unsafe { libc::geteuid() } == 0 {
+        std::os::unix::fs::chown(target, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    Ok(())
+}
+
+// Function to carry the chosen timestamps of a source file, from metadata read before
+// the copy touched its access time, over to the copy
+fn copy_times(metadata: &Metadata, target: &Path, times: &[PreservedTime]) -> io::Result<()> {
+    let mut file_times = FileTimes::new();
+    if times.contains(&PreservedTime::Mtime) {
+        file_times = file_times.set_modified(metadata.modified()?);
+    }
+    if times.contains(&PreservedTime::Atime) {
+        file_times = file_times.set_accessed(metadata.accessed()?);
+    }
+    if times.contains(&PreservedTime::Btime) {
+        file_times = with_created(file_times, metadata);
+    }
+    File::options().write(true).open(target)?.set_times(file_times)
+}
+
+// Function to add the source's creation time to the times set on a copy
+#[cfg(any(windows, target_os = "macos"))]
+fn with_created(times: FileTimes, metadata: &Metadata) -> FileTimes {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    match metadata.created() {
+        Ok(created) => times.set_created(created),
+        Err(e) => {
+            warn!("Could not read a creation time to preserve: {}", e);
+            times
+        }
+    }
+}
+
+// Function to add the source's creation time to the times set on a copy; other platforms
+// offer no way to set it, so warn once
+#[cfg(not(any(windows, target_os = "macos")))]
+fn with_created(times: FileTimes, _metadata: &Metadata) -> FileTimes {
+    static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+    WARN_ONCE.call_once(|| warn!("Creation times can't be set on this platform, so btime is not preserved"));
+    times
+}
+
+// Function to copy permissions onto a copied file; Windows has no POSIX modes, so warn once
+#[cfg(not(unix))]
+fn copy_permissions(_source: &Path, _target: &Path) -> io::Result<()> {
+    static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+    WARN_ONCE.call_once(|| warn!("--preserve-permissions has no effect on this platform"));
+    Ok(())
+}
+
+// Function to copy extended attributes (Finder tags, quarantine flags...) onto a copied
+// file. An attribute that can't be read or written is reported and the rest still copied.
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, target: &Path) {
+    let names = match xattr::list(source) {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("Failed to list extended attributes of {:?}: {}", source, e);
+            return;
+        }
+    };
+    for name in names {
+        let copied = xattr::get(source, &name)
+            .and_then(|value| xattr::set(target, &name, &value.unwrap_or_default()));
+        if let Err(e) = copied {
+            warn!("Failed to copy extended attribute {:?} of {:?}: {}", name, source, e);
+        }
+    }
+}
+
+// Function to copy extended attributes onto a copied file. Alternate data streams on
+// Windows are not copied, so warn once.
+#[cfg(not(unix))]
+fn copy_xattrs(_source: &Path, _target: &Path) {
+    static WARN_ONCE: std::sync::Once = std::sync::Once::new();
+    WARN_ONCE.call_once(|| warn!("--preserve-xattrs has no effect on this platform; alternate data streams are not copied"));
+}
+
+// Function to check that a copied file matches its source before the source is removed
+fn verify_copy_size(source: &Path, target: &Path) -> Result<(), MigrateError> {
+    let source_len = fs::metadata(source)?.len();
+    let target_len = fs::metadata(target)?.len();
+
+    if source_len != target_len {
+        error!("Size mismatch: {:?} is {} bytes but {:?} is {} bytes", source, source_len, target, target_len);
+        return Err(MigrateError::VerificationFailed { path: target.to_path_buf() });
+    }
+
+    Ok(())
+}
+
+// Function to tell whether a copy error may go away if the copy is simply tried again
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Function to check whether a partial copy at `part` holds the start of `source`, by
+// reading both back. Returns its length and the hash of those bytes so far if so.
+fn matching_prefix(source: &Path, part: &Path, buffer_size: usize) -> io::Result<Option<(u64, Sha256)>> {
+    let (Ok(part_metadata), Ok(source_metadata)) = (fs::metadata(part), fs::metadata(source)) else {
+        return Ok(None);
+    };
+    let length = part_metadata.len();
+    if length == 0 || length > source_metadata.len() {
+        return Ok(None);
+    }
+
+    let (mut source_file, mut part_file) = (File::open(source)?, File::open(part)?);
+    let (mut source_buffer, mut part_buffer) = (vec![0; buffer_size], vec![0; buffer_size]);
+    let mut hasher = Sha256::new();
+    let mut remaining = length;
+    while remaining > 0 {
+        let chunk = remaining.min(buffer_size as u64) as usize;
+        source_file.read_exact(&mut source_buffer[..chunk])?;
+        part_file.read_exact(&mut part_buffer[..chunk])?;
+        if source_buffer[..chunk] != part_buffer[..chunk] {
+            info!("Partial copy of {:?} differs from the source, copying it again", source);
+            return Ok(None);
+        }
+        hasher.update(&source_buffer[..chunk]);
+        remaining -= chunk as u64;
+    }
+    Ok(Some((length, hasher)))
+}
+
+// Function to copy a file through a buffer of `buffer_size` bytes, reporting progress and
+// waiting on the rate limiter, if any, before each write. Each chunk is hashed as it is
+// written, so the source is read only once. Like fs::copy it carries the permission bits
+// over; it returns the bytes copied and the SHA-256 of the whole file. `resume_from` carries
+// on from a matching partial copy already at `target`, with the hash of its contents.
+fn buffered_copy(source: &Path, target: &Path, buffer_size: usize, progress: &mut FileProgress, rate_limiter: Option<&RateLimiter>, resume_from: Option<(u64, Sha256)>) -> io::Result<(u64, String)> {
+    let mut reader = File::open(source)?;
+    let (mut writer, mut hasher, offset) = match resume_from {
+        Some((offset, hasher)) => {
+            reader.seek(SeekFrom::Start(offset))?;
+            (OpenOptions::new().append(true).open(target)?, hasher, offset)
+        }
+        None => (File::create(target)?, Sha256::new(), 0),
+    };
+    let mut buffer = vec![0; buffer_size];
+    let mut total_copied = offset;
+    progress.set_position(total_copied);
+
+    loop {
+        progress.check_cancelled()?;
+        let bytes_read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire(bytes_read);
+        }
+        writer.write_all(&buffer[..bytes_read])?;
+        progress.wrote(bytes_read as u64);
+        hasher.update(&buffer[..bytes_read]);
+        total_copied += bytes_read as u64;
+        progress.set_position(total_copied);
+    }
+
+    fs::set_permissions(target, reader.metadata()?.permissions())?;
+    Ok((total_copied - offset, to_hex(hasher)))
+}
+
+// Function to copy a file's contents by one method, returning the bytes copied and, when the
+// data passed through this process, its SHA-256
+fn copy_by(method: CopyMethod, source: &Path, target: &Path, buffer_size: usize, progress: &mut FileProgress, rate_limiter: Option<&RateLimiter>) -> io::Result<(u64, Option<String>)> {
+    let copied = match method {
+        CopyMethod::Reflink => reflink(source, target).and_then(|()| {
+            let bytes = fs::metadata(target)?.len();
+            progress.cloned(bytes);
+            progress.set_position(bytes);
+            Ok((bytes, None))
+        }),
+        CopyMethod::CopyFileRange => copy_file_range(source, target, buffer_size, progress, rate_limiter).map(|bytes| (bytes, None)),
+        CopyMethod::Buffered | CopyMethod::Auto => buffered_copy(source, target, buffer_size, progress, rate_limiter, None).map(|(bytes, hash)| (bytes, Some(hash))),
+    }?;
+    debug!("Copied {:?} by {}", source, method.name());
+    Ok(copied)
+}
+
+// Function to copy a file's contents by the configured method. With `Auto`, a method the
+// filesystems refuse falls back to the next, ending with a buffered copy, which always works.
+// With `resume`, a partial copy already at `target` whose contents match the start of the
+// source is carried on from where it stopped, by a buffered copy, whatever the method; the
+// bytes returned are then only those copied this time.
+fn copy_data(source: &Path, target: &Path, method: CopyMethod, resume: bool, buffer_size: usize, progress: &mut FileProgress, rate_limiter: Option<&RateLimiter>) -> io::Result<(u64, Option<String>)> {
+    if resume {
+        if let Some(prefix) = matching_prefix(source, target, buffer_size)? {
+            info!("Resuming {:?} from byte {}", source, prefix.0);
+            return buffered_copy(source, target, buffer_size, progress, rate_limiter, Some(prefix)).map(|(bytes, hash)| (bytes, Some(hash)));
+        }
+    }
+    let methods: &[CopyMethod] = match method {
+        CopyMethod::Auto => &[CopyMethod::Reflink, CopyMethod::CopyFileRange, CopyMethod::Buffered],
+        CopyMethod::Reflink => &[CopyMethod::Reflink],
+        CopyMethod::CopyFileRange => &[CopyMethod::CopyFileRange],
+        CopyMethod::Buffered => &[CopyMethod::Buffered],
+    };
+    let (last, fallbacks) = methods.split_last().unwrap();
+    for &method in fallbacks {
+        match copy_by(method, source, target, buffer_size, progress, rate_limiter) {
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                debug!("Could not copy {:?} by {} ({}), trying the next method", source, method.name(), e);
+            }
+            copied => return copied,
+        }
+    }
+    copy_by(*last, source, target, buffer_size, progress, rate_limiter)
+}
+
+// Function to finish a hash as the lowercase hex string manifests and sha256sum use
+fn to_hex(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Function to compute the SHA-256 of a file, streaming it in 8KB chunks
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 8192];
+
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 { break; }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    Ok(to_hex(hasher))
+}
+
+// Function to check that the volume a file is copied to has room for it, and for the
+// --min-free-space margin after it. The temp file is written next to any existing
+// destination, so that space doesn't count as free. Parallel copies draw on the same
+// space, so this catches single files too large to fit rather than guaranteeing the
+// rest of the run does.
+fn check_room(source: &Path, target: &Path, size: u64, margin: Option<u64>) -> Result<(), MigrateError> {
+    let volume = target.parent().unwrap_or(target);
+    let available = fs2::available_space(volume)?;
+    if let Some(margin) = margin.filter(|margin| size.saturating_add(*margin) > available) {
+        return Err(MigrateError::FreeSpaceMargin { path: source.to_path_buf(), size, available, margin });
+    }
+    if size > available {
+        return Err(MigrateError::NoRoomForFile { path: source.to_path_buf(), size, available });
+    }
+    Ok(())
+}
+
+// Function to check that a copied file has the same contents as its source, returning the
+// hash. When the source was hashed while being copied only the copy is read back.
+fn verify_copy_hash(source: &Path, source_hash: Option<&str>, target: &Path, hash_pool: Option<&ThreadPool>) -> Result<String, MigrateError> {
+    let hash_source = || source_hash.map_or_else(|| hash_file(source), |hash| Ok(hash.to_string()));
+    let (source_hash, target_hash) = match hash_pool {
+        Some(hash_pool) => hash_pool.install(|| rayon::join(hash_source, || hash_file(target))),
+        None => (hash_source(), hash_file(target)),
+    };
+    let (source_hash, target_hash) = (source_hash?, target_hash?);
+
+    if source_hash != target_hash {
+        error!("Hash mismatch: {:?} is {} but {:?} is {}", source, source_hash, target, target_hash);
+        return Err(MigrateError::VerificationFailed { path: target.to_path_buf() });
+    }
+
+    Ok(target_hash)
+}
+
+// Function to carry metadata over to a freshly copied temp file and check its contents
+// against the hash taken while copying, if any. Returns the copy's hash when it was
+// checked, or for --checksum-manifest, which lists what was written.
+fn finish_copy(source: &Path, source_metadata: &Metadata, source_hash: Option<&str>, part: &Path, config: &Config, hash_pool: Option<&ThreadPool>) -> Result<Option<String>, MigrateError> {
+    // Before the permissions, which may make the file read-only
+    if config.preserve_xattrs {
+        copy_xattrs(source, part);
+    }
+    if !config.preserve_times.is_empty() {
+        copy_times(source_metadata, part, &config.preserve_times)?;
+    }
+
+    if config.preserve_permissions {
+        copy_permissions(source, part)?;
+    }
+
+    if config.verify {
+        Ok(Some(verify_copy_hash(source, source_hash, part, hash_pool)?))
+    } else if config.checksum_manifest.is_some() {
+        let hash = match (source_hash, hash_pool) {
+            (Some(hash), _) => hash.to_string(),
+            (None, Some(hash_pool)) => hash_pool.install(|| hash_file(part))?,
+            (None, None) => hash_file(part)?,
+        };
+        Ok(Some(hash))
+    } else {
+        Ok(None)
+    }
+}
+
+// Function to put a hard link to an earlier copy at `target`, through a temp name so an
+// existing destination is replaced in one step
+fn link_duplicate(existing: &Path, target: &Path, source: &Path) -> io::Result<()> {
+    let part_path = part_path(target, source);
+    let _ = fs::remove_file(&part_path);
+    fs::hard_link(existing, &part_path)?;
+    fs::rename(&part_path, target)?;
+    // Renaming onto another link to the same file leaves both names in place
+    if part_path.symlink_metadata().is_ok() {
+        fs::remove_file(&part_path)?;
+    }
+    Ok(())
+}
+
+// One root files are copied into, with the settings for it
+pub(crate) struct Target {
+    // The run's config with `target` set to this root
+    pub(crate) config: Config,
+    // Copies by content for --dedup, kept per target since hard links can't cross volumes
+    pub(crate) dedup: Option<DedupIndex>,
+    // Destinations claimed by this run where files can collide, so a file copied earlier in
+    // the run is never taken for an earlier run's copy of another source
+    claimed: Mutex<HashSet<PathBuf>>,
+    listings: Listings,
+}
+
+impl Target {
+    pub(crate) fn new(config: Config) -> Self {
+        let dedup = (config.dedup && !config.dry_run).then(DedupIndex::default);
+        Target { config, dedup, claimed: Mutex::default(), listings: Listings::default() }
+    }
+}
+
+// Function to move file to each one-drive directory, preserving folder structure, giving
+// an outcome per target. With move_source the source goes along with the last copy, and
+// only once every earlier target holds a complete copy.
+pub(crate) fn move_file<'a>(entry: &DirEntry, targets: &[&'a Target], progress: &Progress, rate_limiter: Option<&Arc<RateLimiter>>, hash_pool: Option<&ThreadPool>, hook: Option<&PostCopyHook>, move_source: bool) -> Vec<(&'a Target, Result<FileOutcome, MigrateError>)> {
+    let source_path = entry.path();
+    let mut outcomes: Vec<(&Target, Result<FileOutcome, MigrateError>)> = Vec::with_capacity(targets.len());
+    for (i, target) in targets.iter().enumerate() {
+        let copied_everywhere = outcomes.iter().all(|(target, outcome)| match outcome {
+            Ok(FileOutcome::Copied { destination, .. }) => target.config.dry_run || verify_copy_size(&source_path, destination).is_ok(),
+            _ => false,
+        });
+        let remove_source = move_source && copied_everywhere && i + 1 == targets.len();
+        outcomes.push((*target, copy_to_target(entry, target, progress, rate_limiter, hash_pool, hook, remove_source)));
+    }
+    outcomes
+}
+
+// Function to copy a file into one target, removing the source afterwards if asked to
+pub(crate) fn copy_to_target(entry: &DirEntry, target: &Target, progress: &Progress, rate_limiter: Option<&Arc<RateLimiter>>, hash_pool: Option<&ThreadPool>, hook: Option<&PostCopyHook>, remove_source: bool) -> Result<FileOutcome, MigrateError> {
+    let config = &target.config;
+    let dedup = target.dedup.as_ref();
+    let source_path = entry.path();
+    let relative_path = relative_to_source(&source_path, &config.source)?;
+
+    // Target directories were all created before the copy started
+    let target_path = long_path_safe(destination_path(&source_path, config)?);
+
+    info!("Moving file from {:?} to {:?}", source_path, target_path);
+
+    let copy_as_link = config.symlinks == SymlinkPolicy::CopyAsLink && entry.file_type()?.is_symlink();
+
+    // Flattened files always get a free name, whatever the conflict policy
+    let on_conflict = if config.flatten { ConflictPolicy::Rename } else { config.on_conflict };
+
+    // Flattened, routed and templated files from different folders can share a destination,
+    // so it's claimed in one step before copying rather than checked and then written
+    let collides = !config.dry_run && (config.flatten || config.dest_template.is_some() || route_for(&source_path, config).is_some());
+    let mut placeholder = Placeholder(None);
+
+    // Check if the target file already exists and resolve the conflict
+    let destination = match (collides, on_conflict) {
+        (true, ConflictPolicy::Rename) => None,
+        (true, _) => Some(claim_destination(&target_path, target)?),
+        (false, _) if target_path.symlink_metadata().is_ok() => Some(Destination::Exists),
+        (false, _) => Some(Destination::Free),
+    };
+    let (target_path, conflict) = if destination.is_none() {
+        let Some(claimed) = claim_free_path(&target_path, &source_path, target)? else {
+            info!("{:?} is unchanged, skipping", entry.file_name());
+            return Ok(FileOutcome::Skipped);
+        };
+        placeholder.0 = Some(claimed.clone());
+        if claimed == target_path {
+            (claimed, None)
+        } else {
+            warn!("{:?} exists, copying to {:?}", target_path.file_name().unwrap_or_default(), claimed);
+            (claimed, Some(ConflictPolicy::Rename))
+        }
+    } else if destination == Some(Destination::Taken) {
+        // Replacing another source's copy from this run would lose that file
+        warn!("{:?} goes where another file in this run does, skipping (see --on-conflict rename)", entry.file_name());
+        return Ok(FileOutcome::Skipped);
+    } else if destination == Some(Destination::Exists) {
+        match case_variant(&target_path, &target.listings) {
+            // A case-insensitive target matched a file that differs only in case,
+            // so this is a different source file, not an earlier copy of this one
+            Some(existing) => match config.on_case_collision {
+                CaseCollisionPolicy::Error => {
+                    error!("{:?} differs only in case from existing {:?}", entry.file_name(), existing);
+                    return Err(MigrateError::CaseCollision { path: source_path, existing: target_path.with_file_name(existing) });
+                }
+                CaseCollisionPolicy::Skip => {
+                    warn!("{:?} differs only in case from existing {:?}, skipping", entry.file_name(), existing);
+                    return Ok(FileOutcome::Skipped);
+                }
+                CaseCollisionPolicy::Rename => {
+                    let renamed = if collides {
+                        let Some(claimed) = claim_free_path(&target_path, &source_path, target)? else {
+                            info!("{:?} is unchanged, skipping", entry.file_name());
+                            return Ok(FileOutcome::Skipped);
+                        };
+                        placeholder.0 = Some(claimed.clone());
+                        claimed
+                    } else {
+                        free_path(&target_path, &config.rename_pattern)
+                    };
+                    warn!("{:?} differs only in case from existing {:?}, copying to {:?}", entry.file_name(), existing, renamed);
+                    (renamed, Some(ConflictPolicy::Rename))
+                }
+            },
+            None => match on_conflict {
+                ConflictPolicy::Skip => {
+                    warn!("{:?} exists", entry.file_name());
+                    return Ok(FileOutcome::Skipped);
+                }
+                ConflictPolicy::Overwrite => {
+                    warn!("{:?} exists, overwriting", entry.file_name());
+                    (target_path, Some(ConflictPolicy::Overwrite))
+                }
+                ConflictPolicy::OverwriteIfNewer => {
+                    if !needs_copy(&source_path, &target_path, config.compare)? {
+                        info!("{:?} is unchanged, skipping", entry.file_name());
+                        return Ok(FileOutcome::Skipped);
+                    }
+                    warn!("{:?} differs from source, overwriting", entry.file_name());
+                    (target_path, Some(ConflictPolicy::OverwriteIfNewer))
+                }
+                ConflictPolicy::Rename => {
+                    let renamed = free_path(&target_path, &config.rename_pattern);
+                    warn!("{:?} exists, copying to {:?}", entry.file_name(), renamed);
+                    (renamed, Some(ConflictPolicy::Rename))
+                }
+            },
+        }
+    } else {
+        if collides {
+            placeholder.0 = Some(target_path.clone());
+        }
+        (target_path, None)
+    };
+
+    if config.dry_run {
+        info!("Would copy {:?} to {:?}", source_path, target_path);
+        // Before the source is removed, so a fatal hook failure leaves it in place
+        if let Some(hook) = hook {
+            hook.run(&source_path, &target_path)?;
+        }
+        if remove_source {
+            info!("Would remove source file: {:?}", source_path);
+        }
+        Ok(FileOutcome::Copied { bytes: get_file_size(entry).unwrap_or(0), sha256: None, source_sha256: None, destination: plain_path(target_path), conflict, linked: false })
+    } else if copy_as_link {
+        // A link can't be overwritten in place, so clear the way first
+        if placeholder.0.is_some() || (conflict.is_some() && conflict != Some(ConflictPolicy::Rename)) {
+            fs::remove_file(&target_path)?;
+        }
+        copy_symlink(&source_path, &target_path)?;
+        placeholder.keep();
+        info!("Recreated symlink {:?}", target_path);
+
+        if let Some(hook) = hook {
+            hook.run(&source_path, &target_path)?;
+        }
+        if remove_source {
+            fs::remove_file(&source_path)?;
+            info!("Removed source symlink: {:?}", source_path);
+        }
+        Ok(FileOutcome::Copied { bytes: 0, sha256: None, source_sha256: None, destination: plain_path(target_path), conflict, linked: false })
+    } else {
+        // With --dedup, a file identical to one already copied becomes a hard link to that copy.
+        // The first file with some contents claims them, and identical ones wait to link to it.
+        let content_key = match dedup {
+            Some(_) => Some(DedupIndex::key(&source_path)?),
+            None => None,
+        };
+        let claim = match (dedup, &content_key) {
+            (Some(dedup), Some(key)) => Some(dedup.claim(key.clone())),
+            _ => None,
+        };
+        let claim = match claim {
+            Some(Claim::Copy(claim)) => Some(claim),
+            Some(Claim::Link(existing)) => match link_duplicate(&existing, &target_path, relative_path) {
+                Ok(()) => {
+                    placeholder.keep();
+                    info!("Linked {:?} to identical copy {:?}", target_path, existing);
+                    if let Some(hook) = hook {
+                        hook.run(&source_path, &target_path)?;
+                    }
+                    if remove_source {
+                        verify_copy_size(&source_path, &target_path)?;
+                        fs::remove_file(&source_path)?;
+                        info!("Removed source file: {:?}", source_path);
+                    }
+                    let (bytes, hash) = content_key.unwrap();
+                    let sha256 = (config.verify || config.checksum_manifest.is_some()).then(|| hash.clone());
+                    return Ok(FileOutcome::Copied { bytes, sha256, source_sha256: Some(hash), destination: plain_path(target_path), conflict, linked: true });
+                }
+                // e.g. a filesystem without hard links, such as FAT
+                Err(e) => {
+                    warn!("Could not link {:?} to {:?} ({}), copying instead", target_path, existing, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // A file that can't fit fails up front rather than filling the disk part way,
+        // which also keeps --move from removing its source
+        let source_metadata = fs::metadata(&source_path)?;
+        let size = source_metadata.len();
+        check_room(&source_path, &target_path, size, config.min_free_space)?;
+
+        // Copy into a sibling temp file with a bar sized to its real length, giving up if it
+        // takes longer than the per-file timeout. The destination only appears once complete.
+        let part_path = part_path(&target_path, relative_path);
+        let file_progress = progress.file(&source_path, Some(size));
+        let mut retries = 0;
+        let (copied, attempt) = loop {
+            let attempt = file_progress.next_attempt();
+            let copy_job = {
+                let (source_path, part_path, mut file_progress) = (source_path.clone(), part_path.clone(), attempt.clone());
+                let rate_limiter = rate_limiter.cloned();
+                let (method, resume, buffer_size) = (config.copy_method, config.resume_partial, config.copy_buffer_size);
+                move |cancel: &Cancel| {
+                    file_progress.cancel_on(cancel);
+                    copy_data(&source_path, &part_path, method, resume, buffer_size, &mut file_progress, rate_limiter.as_deref())
+                }
+            };
+            match with_timeout(&source_path, config.file_timeout, copy_job) {
+                // A network blip mid-copy is worth another go from the start
+                Err(MigrateError::Io(e)) if is_transient(&e) && retries < config.retry.max_retries => {
+                    retries += 1;
+                    let delay = config.retry.delay(retries);
+                    warn!("Copy of {:?} failed ({}), retrying in {:?}... (attempt {})", source_path, e, delay, retries);
+                    if !config.resume_partial {
+                        let _ = fs::remove_file(&part_path);
+                        attempt.discard();
+                    }
+                    file_progress.bar.set_position(0);
+                    thread::sleep(delay);
+                }
+                result => break (result, attempt),
+            }
+        };
+        // Finished copies are cleared so only files still in flight keep a bar
+        file_progress.bar.finish_and_clear();
+
+        let finished = copied.and_then(|(bytes, source_sha256)| {
+            info!("Successfully copied file: {:?}", entry.file_name());
+            let sha256 = finish_copy(&source_path, &source_metadata, source_sha256.as_deref(), &part_path, config, hash_pool)?;
+            fs::rename(&part_path, &target_path)?;
+            placeholder.keep();
+            // A verified copy matched its source, so its hash stands for a source that was
+            // copied without passing through this process
+            let source_sha256 = source_sha256.or_else(|| sha256.clone().filter(|_| config.verify));
+            Ok((bytes, sha256, source_sha256))
+        });
+        match finished {
+            Ok((bytes, sha256, source_sha256)) => {
+                // Recorded as soon as the copy is in place, so duplicates waiting on it link
+                // to it even if the hook or removing the source fails below
+                if let Some(claim) = claim {
+                    claim.finish(target_path.clone());
+                }
+                if let (true, Some(hash)) = (config.verify, &sha256) {
+                    info!("Verified {:?} (sha256 {})", target_path, hash);
+                }
+
+                // Only remove the source once the destination is confirmed complete
+                if let Some(hook) = hook {
+                    hook.run(&source_path, &target_path)?;
+                }
+                if remove_source {
+                    verify_copy_size(&source_path, &target_path)?;
+                    fs::remove_file(&source_path)?;
+                    info!("Removed source file: {:?}", source_path);
+                }
+                Ok(FileOutcome::Copied { bytes, sha256, source_sha256, destination: plain_path(target_path), conflict, linked: false })
+            }
+            Err(e) => {
+                // A timed-out copy may still be writing the temp file, so this is best effort;
+                // anything left behind is removed at the start of the next run. With
+                // --resume-partial the next run carries on from it instead, unless the
+                // finished copy turned out wrong.
+                if !config.resume_partial || matches!(e, MigrateError::VerificationFailed { .. }) {
+                    let _ = fs::remove_file(&part_path);
+                    attempt.discard();
+                }
+                error!("Failed to move file {:?}: {}", entry.file_name(), e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    #[test]
+    fn target_parent_of_a_root_file_is_the_target_root() {
+        let parent = target_parent(Path::new("box/top.txt"), Path::new("onedrive"), Path::new("box")).unwrap();
+        assert_eq!(parent, Path::new("onedrive"));
+        // Not "onedrive/", as joining an empty relative path would give
+        assert_eq!(parent.as_os_str(), Path::new("onedrive").as_os_str());
+    }
+
+    #[test]
+    fn target_parent_one_level_deep() {
+        let parent = target_parent(Path::new("box/docs/a.txt"), Path::new("onedrive"), Path::new("box")).unwrap();
+        assert_eq!(parent, Path::new("onedrive").join("docs"));
+    }
+
+    #[test]
+    fn target_parent_deeply_nested() {
+        let parent = target_parent(Path::new("box/a/b/c/d/e.txt"), Path::new("onedrive"), Path::new("box")).unwrap();
+        assert_eq!(parent, Path::new("onedrive").join("a").join("b").join("c").join("d"));
+    }
+
+    #[test]
+    fn target_parent_rejects_paths_outside_the_source() {
+        let outside = target_parent(Path::new("elsewhere/a.txt"), Path::new("onedrive"), Path::new("box"));
+        assert!(matches!(outside, Err(MigrateError::OutsideSource { .. })));
+        let root = target_parent(Path::new("box"), Path::new("onedrive"), Path::new("box"));
+        assert!(matches!(root, Err(MigrateError::OutsideSource { .. })));
+    }
+
+    // Function to write a source and destination of the same size but different contents,
+    // both with the same modification time
+    fn same_size_pair(dir: &Path) -> (PathBuf, PathBuf) {
+        let (src, dst) = (dir.join("src.txt"), dir.join("dst.txt"));
+        fs::write(&src, "new contents").unwrap();
+        fs::write(&dst, "old contents").unwrap();
+        let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&src, mtime).unwrap();
+        filetime::set_file_mtime(&dst, mtime).unwrap();
+        (src, dst)
+    }
+
+    #[test]
+    fn only_hash_comparison_catches_same_size_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = same_size_pair(dir.path());
+        assert!(!needs_copy(&src, &dst, CompareMode::Size).unwrap());
+        assert!(!needs_copy(&src, &dst, CompareMode::SizeMtime).unwrap());
+        assert!(needs_copy(&src, &dst, CompareMode::Hash).unwrap());
+    }
+
+    #[test]
+    fn mtime_comparison_catches_touched_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = same_size_pair(dir.path());
+        fs::copy(&src, &dst).unwrap();
+        filetime::set_file_mtime(&dst, FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+        filetime::set_file_mtime(&src, FileTime::from_unix_time(1_700_000_000, 0)).unwrap();
+        assert!(!needs_copy(&src, &dst, CompareMode::Size).unwrap());
+        assert!(needs_copy(&src, &dst, CompareMode::SizeMtime).unwrap());
+        assert!(!needs_copy(&src, &dst, CompareMode::Hash).unwrap());
+    }
+
+    // A case-sensitive temp directory can hold both names, standing in for the moment a
+    // case-insensitive target gains one of them
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn case_variants_are_listed_once_and_rechecked_when_unsure() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("Report.txt"), "").unwrap();
+        let listings = Listings::default();
+
+        assert_eq!(case_variant(&dir.path().join("report.txt"), &listings), Some("Report.txt".into()));
+        assert_eq!(case_variant(&dir.path().join("Report.txt"), &listings), None);
+        // Added after the listing was read, and seen because a variant is always rechecked
+        fs::write(dir.path().join("report.txt"), "").unwrap();
+        assert_eq!(case_variant(&dir.path().join("report.txt"), &listings), None);
+    }
+
+    #[test]
+    fn counts_bytes_as_written_and_takes_back_discarded_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src.bin"), dir.path().join("dst.bin"));
+        fs::write(&src, vec![7; 10_000]).unwrap();
+        let progress = Progress::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None);
+        let file_progress = progress.file(&src, Some(10_000));
+
+        // A buffer that doesn't divide the file leaves a short last chunk
+        for method in [CopyMethod::Buffered, CopyMethod::Auto] {
+            let mut attempt = file_progress.next_attempt();
+            let (bytes, _) = copy_data(&src, &dst, method, false, 4096, &mut attempt, None).unwrap();
+            assert_eq!(bytes, 10_000);
+            assert_eq!(progress.written().get(), 10_000);
+            attempt.discard();
+            assert_eq!(progress.written().get(), 0);
+        }
+    }
+
+    #[test]
+    fn counts_reflinked_bytes_as_cloned_rather_than_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src.bin"), dir.path().join("dst.bin"));
+        fs::write(&src, vec![7; 10_000]).unwrap();
+        let progress = Progress::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None);
+        let file_progress = progress.file(&src, Some(10_000));
+
+        let attempt = file_progress.next_attempt();
+        attempt.cloned(10_000);
+        assert_eq!((progress.written().get(), progress.written().cloned()), (0, 10_000));
+        attempt.discard();
+        assert_eq!(progress.written().cloned(), 0);
+
+        // Only checked where the filesystem the test runs on can reflink
+        let mut attempt = file_progress.next_attempt();
+        if copy_data(&src, &dst, CopyMethod::Reflink, false, 4096, &mut attempt, None).is_ok() {
+            assert_eq!((progress.written().get(), progress.written().cloned()), (0, 10_000));
+        }
+    }
+
+    #[test]
+    fn every_mode_copies_missing_or_resized_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = same_size_pair(dir.path());
+        fs::write(&dst, "short").unwrap();
+        let missing = dir.path().join("missing.txt");
+        for compare in [CompareMode::Size, CompareMode::SizeMtime, CompareMode::Hash] {
+            assert!(needs_copy(&src, &dst, compare).unwrap());
+            assert!(needs_copy(&src, &missing, compare).unwrap());
+        }
+    }
+
+    #[test]
+    fn long_drive_paths_get_the_extended_length_prefix() {
+        let long = format!("C:\\box{}\\file.txt", "/nested".repeat(50));
+        assert!(long.len() > 300);
+        let extended = extended_length(&long);
+        assert!(extended.starts_with(r"\\?\C:\box\nested\"));
+        assert!(!extended.contains('/'));
+        assert_eq!(extended.len(), long.len() + 4);
+        assert!(extended.len() > MAX_PLAIN_PATH);
+    }
+
+    #[test]
+    fn other_paths_keep_their_form() {
+        assert_eq!(extended_length(r"\\?\UNC\server\share\a"), r"\\?\UNC\server\share\a");
+        assert_eq!(extended_length("relative/path"), "relative/path");
+    }
+
+    #[test]
+    fn destination_of_a_root_file() {
+        let config = Config::new("box", "onedrive");
+        assert_eq!(destination_path(Path::new("box/top.txt"), &config).unwrap(), Path::new("onedrive").join("top.txt"));
+    }
+}
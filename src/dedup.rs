@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use crate::copy::hash_file;
+
+// Size and SHA-256 of a file's contents; files with equal keys are byte-identical
+pub(crate) type ContentKey = (u64, String);
+
+// Where some contents stand: one worker copying them, or a finished copy to link to
+enum Slot {
+    Copying,
+    Copied(PathBuf),
+}
+
+// Copies made so far in a --dedup run, by content, so later identical files can be
+// hard linked to them instead of copied again
+#[derive(Default)]
+pub(crate) struct DedupIndex {
+    copies: Mutex<HashMap<ContentKey, Slot>>,
+    // Signalled whenever a copy finishes or gives up its claim
+    settled: Condvar,
+}
+
+// What a worker should do with a file after claiming its contents
+pub(crate) enum Claim<'a> {
+    // Nothing else has these contents: copy the file, then finish the claim
+    Copy(CopyClaim<'a>),
+    // An identical copy already exists to link to
+    Link(PathBuf),
+}
+
+// The right to make the one copy of some contents. Dropped without finishing, e.g. when
+// the copy fails, it lets the next worker with the same contents copy them instead.
+pub(crate) struct CopyClaim<'a> {
+    index: &'a DedupIndex,
+    key: Option<ContentKey>,
+}
+
+impl DedupIndex {
+    // Function to compute a source file's content key
+    pub(crate) fn key(path: &Path) -> io::Result<ContentKey> {
+        Ok((fs::metadata(path)?.len(), hash_file(path)?))
+    }
+
+    // Function to claim some contents in one step under the lock, so two workers hashing
+    // identical files at once can't both miss and both copy. A worker whose contents are
+    // already being copied waits for that copy and links to it.
+    pub(crate) fn claim(&self, key: ContentKey) -> Claim<'_> {
+        let mut copies = self.copies.lock().unwrap();
+        loop {
+            match copies.get(&key) {
+                None => break,
+                Some(Slot::Copied(copy)) => return Claim::Link(copy.clone()),
+                Some(Slot::Copying) => copies = self.settled.wait(copies).unwrap(),
+            }
+        }
+        copies.insert(key.clone(), Slot::Copying);
+        Claim::Copy(CopyClaim { index: self, key: Some(key) })
+    }
+}
+
+impl CopyClaim<'_> {
+    // Function to record the finished copy, which every later duplicate links to
+    pub(crate) fn finish(mut self, copy: PathBuf) {
+        if let Some(key) = self.key.take() {
+            self.index.copies.lock().unwrap().insert(key, Slot::Copied(copy));
+            self.index.settled.notify_all();
+        }
+    }
+}
+
+impl Drop for CopyClaim<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.index.copies.lock().unwrap().remove(&key);
+            self.index.settled.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    fn key() -> ContentKey {
+        (5, "same".to_string())
+    }
+
+    #[test]
+    fn only_one_of_many_identical_files_is_copied() {
+        let index = DedupIndex::default();
+        let copies = AtomicUsize::new(0);
+        let links = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| match index.claim(key()) {
+                    Claim::Copy(claim) => {
+                        copies.fetch_add(1, Ordering::Relaxed);
+                        thread::sleep(Duration::from_millis(20));
+                        claim.finish(PathBuf::from("first"));
+                    }
+                    Claim::Link(copy) => links.lock().unwrap().push(copy),
+                });
+            }
+        });
+
+        assert_eq!(copies.load(Ordering::Relaxed), 1);
+        assert_eq!(links.into_inner().unwrap(), vec![PathBuf::from("first"); 7]);
+    }
+
+    #[test]
+    fn a_failed_copy_hands_its_claim_on() {
+        let index = DedupIndex::default();
+        let Claim::Copy(claim) = index.claim(key()) else { panic!("nothing was copied yet") };
+        drop(claim);
+        assert!(matches!(index.claim(key()), Claim::Copy(_)));
+    }
+}
@@ -0,0 +1,105 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use indicatif::HumanBytes;
+use thiserror::Error;
+
+/// Errors produced while migrating a tree or a single file
+#[derive(Debug, Error)]
+pub enum MigrateError {
+    /// A stub file stayed locked after every retry
+    #[error("stub file {path:?} was still locked after {attempts} attempts")]
+    StubLockTimeout { path: PathBuf, attempts: u32 },
+
+    /// Fetching or copying a file did not finish within the per-file timeout
+    #[error("{path:?} did not finish within {timeout:?}")]
+    FileTimeout { path: PathBuf, timeout: Duration },
+
+    /// A copied file does not match its source
+    #[error("copy of {path:?} does not match its source")]
+    VerificationFailed { path: PathBuf },
+
+    /// The post-copy command exited unsuccessfully, or could not be started
+    #[error("post-copy command failed for {path:?}: {reason}")]
+    HookFailed { path: PathBuf, reason: String },
+
+    /// The source path is missing or is not a directory
+    #[error("source {path:?} is not a directory")]
+    SourceNotDirectory { path: PathBuf },
+
+    /// The source and target are the same directory or one contains the other
+    #[error("source {source_root:?} and target {target_root:?} overlap; neither may contain the other")]
+    OverlappingRoots { source_root: PathBuf, target_root: PathBuf },
+
+    /// A path that should be below the source root is not
+    #[error("{path:?} is not inside the source {source_root:?}")]
+    OutsideSource { path: PathBuf, source_root: PathBuf },
+
+    /// The target can't be created or written to
+    #[error("target {path:?} is not writable: {source}")]
+    TargetNotWritable { path: PathBuf, source: io::Error },
+
+    /// The target volume has less free space than the files to copy need
+    #[error("target {path:?} has {} free but {} is needed (use --ignore-space to copy anyway)", HumanBytes(*available), HumanBytes(*needed))]
+    InsufficientSpace { path: PathBuf, needed: u64, available: u64 },
+
+    /// A single file is larger than the free space left on the target
+    #[error("{path:?} needs {} but the target has only {} free", HumanBytes(*size), HumanBytes(*available))]
+    NoRoomForFile { path: PathBuf, size: u64, available: u64 },
+
+    /// Copying a file would leave less free space on the target than `--min-free-space`
+    #[error("copying {path:?} ({}) would leave less than {} free on the target ({} free now)", HumanBytes(*size), HumanBytes(*margin), HumanBytes(*available))]
+    FreeSpaceMargin { path: PathBuf, size: u64, available: u64, margin: u64 },
+
+    /// A `--config` file could not be read or has invalid settings
+    #[error("invalid config file {path:?}: {message}")]
+    InvalidConfig { path: PathBuf, message: String },
+
+    /// A file's destination differs only in case from an existing file on a
+    /// case-insensitive target
+    #[error("{path:?} differs only in case from existing {existing:?}")]
+    CaseCollision { path: PathBuf, existing: PathBuf },
+
+    /// `--check-conflicts` found source files that share a destination
+    #[error("{count} destinations would receive more than one source file")]
+    DestinationConflicts { count: usize },
+
+    /// `--on-invalid-name error` found destination names Windows filesystems refuse
+    #[error("{count} files have names the target filesystem may refuse (see --on-invalid-name)")]
+    InvalidNames { count: usize },
+
+    /// An `--include` or `--exclude` glob failed to compile
+    #[error("invalid glob pattern: {0}")]
+    InvalidPattern(#[from] globset::Error),
+
+    /// The worker thread pool could not be created
+    #[error("failed to build thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
+    /// Any other I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl MigrateError {
+    /// A short label for the kind of failure, used to group failures in the summary
+    pub fn category(&self) -> &'static str {
+        match self {
+            MigrateError::StubLockTimeout { .. } => "stub still locked",
+            MigrateError::FileTimeout { .. } => "timed out",
+            MigrateError::VerificationFailed { .. } => "verification failed",
+            MigrateError::CaseCollision { .. } => "case collision",
+            MigrateError::HookFailed { .. } => "post-copy command failed",
+            MigrateError::NoRoomForFile { .. } => "no room on target",
+            MigrateError::FreeSpaceMargin { .. } => "free-space margin reached",
+            MigrateError::Io(e) => match e.kind() {
+                io::ErrorKind::PermissionDenied => "permission denied",
+                io::ErrorKind::NotFound => "not found",
+                io::ErrorKind::TimedOut => "timed out",
+                io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => "disk full",
+                _ => "other I/O error",
+            },
+            _ => "other",
+        }
+    }
+}
@@ -0,0 +1,112 @@
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crate::progress::FileProgress;
+use crate::throttle::RateLimiter;
+
+// Errors meaning the filesystem or OS can't do this kind of copy, rather than that it failed
+#[cfg(unix)]
+const REFUSED: [i32; 6] = [libc::EOPNOTSUPP, libc::ENOTSUP, libc::EXDEV, libc::EINVAL, libc::ENOSYS, libc::ENOTTY];
+
+// Function to report a refused fast copy as ErrorKind::Unsupported, so the caller can
+// fall back to a slower method
+#[cfg(unix)]
+fn unsupported_if_refused(e: io::Error, method: &str) -> io::Error {
+    match e.raw_os_error() {
+        Some(code) if REFUSED.contains(&code) => io::Error::new(io::ErrorKind::Unsupported, format!("{} not supported here: {}", method, e)),
+        _ => e,
+    }
+}
+
+// Function to make `target` a copy-on-write clone of `source` (btrfs, XFS), sharing its data
+// until either file changes, so no data is read or written
+#[cfg(target_os = "linux")]
+pub(crate) fn reflink(source: &Path, target: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let reader = File::open(source)?;
+    let writer = File::create(target)?;
+    if unsafe { libc::ioctl(writer.as_raw_fd(), libc::FICLONE, reader.as_raw_fd()) } == -1 {
+        return Err(unsupported_if_refused(io::Error::last_os_error(), "reflink"));
+    }
+    fs::set_permissions(target, reader.metadata()?.permissions())
+}
+
+// Function to make `target` a copy-on-write clone of `source` on APFS. clonefile creates
+// the target itself and carries the permissions over.
+#[cfg(target_os = "macos")]
+pub(crate) fn reflink(source: &Path, target: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let source_name = CString::new(source.as_os_str().as_bytes())?;
+    let target_name = CString::new(target.as_os_str().as_bytes())?;
+    match fs::remove_file(target) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    if unsafe { libc::clonefile(source_name.as_ptr(), target_name.as_ptr(), 0) } == -1 {
+        return Err(unsupported_if_refused(io::Error::last_os_error(), "reflink"));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn reflink(_source: &Path, _target: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "reflink not supported on this platform"))
+}
+
+// Function to copy a file with copy_file_range, so the kernel moves the data without it
+// passing through this process, `chunk_size` bytes per call. The kernel may itself clone
+// the data or, on network filesystems, copy it on the server. Progress and the rate
+// limiter are updated after each chunk.
+#[cfg(target_os = "linux")]
+pub(crate) fn copy_file_range(source: &Path, target: &Path, chunk_size: usize, progress: &mut FileProgress, rate_limiter: Option<&RateLimiter>) -> io::Result<u64> {
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+
+    let reader = File::open(source)?;
+    let writer = File::create(target)?;
+    let size = reader.metadata()?.len();
+    let mut total_copied = 0;
+
+    loop {
+        progress.check_cancelled()?;
+        let copied = unsafe { libc::copy_file_range(reader.as_raw_fd(), ptr::null_mut(), writer.as_raw_fd(), ptr::null_mut(), chunk_size, 0) };
+        if copied == -1 {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => continue,
+                // Only a refusal before any data moved leaves a clean file to fall back from
+                _ if total_copied == 0 => return Err(unsupported_if_refused(e, "copy_file_range")),
+                _ => return Err(e),
+            }
+        }
+        if copied == 0 {
+            break;
+        }
+        let copied = copied as usize;
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire(copied);
+        }
+        progress.wrote(copied as u64);
+        total_copied += copied as u64;
+        progress.set_position(total_copied);
+    }
+
+    // Files whose size is made up on the fly, as in /proc, read as empty this way
+    if total_copied == 0 && size > 0 {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("copy_file_range read nothing from {:?}", source)));
+    }
+    fs::set_permissions(target, reader.metadata()?.permissions())?;
+    Ok(total_copied)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn copy_file_range(_source: &Path, _target: &Path, _chunk_size: usize, _progress: &mut FileProgress, _rate_limiter: Option<&RateLimiter>) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "copy_file_range not supported on this platform"))
+}
@@ -0,0 +1,293 @@
+use std::fs::DirEntry;
+use std::path::Path;
+use std::sync::{Arc, Once};
+use std::time::SystemTime;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use tracing::warn;
+
+use crate::config::{Config, StubSizeFilter};
+use crate::get_file_size;
+use crate::stub::{is_stub, stub_expected_size};
+
+// Function to compile glob patterns once, case-insensitively on Windows
+fn build_globset(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(cfg!(windows))
+            .build()?;
+        builder.add(glob);
+    }
+    builder.build()
+}
+
+/// Name of the gitignore-style file honored in the source root and any directory below it
+pub const IGNORE_FILE_NAME: &str = ".xeroxignore";
+
+// The .xeroxignore files that apply to a directory, innermost first. Cheap to clone,
+// since every subdirectory shares its parent's chain.
+#[derive(Clone, Default)]
+pub(crate) struct IgnoreChain(Option<Arc<IgnoreLevel>>);
+
+struct IgnoreLevel {
+    matcher: Gitignore,
+    parent: IgnoreChain,
+}
+
+impl IgnoreChain {
+    // Function to extend the chain with the ignore file in `dir`, if it has one
+    pub(crate) fn enter(&self, dir: &Path) -> IgnoreChain {
+        let path = dir.join(IGNORE_FILE_NAME);
+        if !path.is_file() {
+            return self.clone();
+        }
+        // Bad lines are reported and the rest of the file still applies
+        let (matcher, error) = Gitignore::new(&path);
+        if let Some(e) = error {
+            warn!("Problem reading {:?}: {}", path, e);
+        }
+        IgnoreChain(Some(Arc::new(IgnoreLevel { matcher, parent: self.clone() })))
+    }
+
+    // Function to check a path against the chain. As in git, the innermost file with a
+    // matching rule decides, so a `!` rule can re-include what an outer file ignored.
+    pub(crate) fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let mut level = &self.0;
+        while let Some(current) = level {
+            match current.matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => level = &current.parent.0,
+            }
+        }
+        false
+    }
+}
+
+// Windows attribute marking files hidden from Explorer
+#[cfg(windows)]
+const FILE_ATTRIBUTE_HIDDEN: u32 = 0x0000_0002;
+
+// Function to check whether a file or directory is hidden: by its hidden attribute on Windows
+#[cfg(windows)]
+pub(crate) fn is_hidden(entry: &DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    entry.metadata().is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+// Function to check whether a file or directory is hidden: by a leading `.` in its name elsewhere
+#[cfg(not(windows))]
+pub(crate) fn is_hidden(entry: &DirEntry) -> bool {
+    entry.file_name().as_encoded_bytes().starts_with(b".")
+}
+
+// Include/exclude patterns and size and date limits applied while walking the source
+pub(crate) struct FileFilter {
+    includes: Option<GlobSet>,
+    excludes: GlobSet,
+    excluded_dirs: GlobSet,
+    skip_hidden: bool,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    stub_size_filter: StubSizeFilter,
+    stub_max_size: u64,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+}
+
+impl FileFilter {
+    pub(crate) fn new(config: &Config) -> Result<Self, globset::Error> {
+        let includes = if config.include.is_empty() { None } else { Some(build_globset(&config.include)?) };
+        Ok(FileFilter {
+            includes,
+            excludes: build_globset(&config.exclude)?,
+            excluded_dirs: build_globset(&config.exclude_dir)?,
+            skip_hidden: config.skip_hidden,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            stub_size_filter: config.stub_size_filter,
+            stub_max_size: config.stub_max_size,
+            newer_than: config.newer_than,
+            older_than: config.older_than,
+        })
+    }
+
+    // Function to check a path (relative to the source root) against a set by full path or file name
+    fn matches(set: &GlobSet, relative_path: &Path) -> bool {
+        set.is_match(relative_path) || relative_path.file_name().is_some_and(|name| set.is_match(name))
+    }
+
+    // Function to decide whether a file or directory should be kept. Includes only
+    // restrict files, so directories are always descended unless excluded.
+    pub(crate) fn allows(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if Self::matches(&self.excludes, relative_path) {
+            return false;
+        }
+        match &self.includes {
+            Some(includes) if !is_dir => Self::matches(includes, relative_path),
+            _ => true,
+        }
+    }
+
+    // Function to decide whether a file or directory is dropped for being hidden
+    pub(crate) fn skips_hidden(&self, entry: &DirEntry) -> bool {
+        self.skip_hidden && is_hidden(entry)
+    }
+
+    // Function to decide whether a directory's whole subtree should be skipped
+    pub(crate) fn prunes(&self, relative_path: &Path) -> bool {
+        Self::matches(&self.excluded_dirs, relative_path)
+    }
+
+    // Function to check a file against the size limits. Stubs are measured by their
+    // cloud size when the OS exposes it, otherwise according to `stub_size_filter`.
+    pub(crate) fn allows_size(&self, entry: &DirEntry) -> bool {
+        if self.min_size.is_none() && self.max_size.is_none() {
+            return true;
+        }
+
+        let size = if is_stub(entry, self.stub_max_size) {
+            match (stub_expected_size(entry), self.stub_size_filter) {
+                (Some(expected_size), _) => expected_size,
+                (None, StubSizeFilter::Reported) => get_file_size(entry).unwrap_or(0),
+                (None, StubSizeFilter::Bypass) => return true,
+            }
+        } else {
+            get_file_size(entry).unwrap_or(0)
+        };
+
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    // Function to check a file's modification time against the date limits. A stub's
+    // local time may be a placeholder set when it was synced, so warn once about that.
+    pub(crate) fn allows_modified(&self, entry: &DirEntry) -> bool {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+
+        if is_stub(entry, self.stub_max_size) {
+            static WARN_ONCE: Once = Once::new();
+            WARN_ONCE.call_once(|| warn!("Stub modification times may reflect when they were synced, so date filters can be unreliable for them"));
+        }
+
+        // A file whose time can't be read is kept rather than silently dropped
+        let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) else {
+            return true;
+        };
+        self.newer_than.is_none_or(|newer| modified >= newer) && self.older_than.is_none_or(|older| modified < older)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Function to build a filter from include, exclude and exclude-dir patterns
+    fn filter(include: &[&str], exclude: &[&str], exclude_dir: &[&str]) -> FileFilter {
+        let mut config = Config::new("box", "onedrive");
+        config.include = include.iter().map(|pattern| pattern.to_string()).collect();
+        config.exclude = exclude.iter().map(|pattern| pattern.to_string()).collect();
+        config.exclude_dir = exclude_dir.iter().map(|pattern| pattern.to_string()).collect();
+        FileFilter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn includes_restrict_files_but_not_directories() {
+        let filter = filter(&["*.pdf", "docs/**/*.txt"], &[], &[]);
+        for (path, is_dir, allowed) in [
+            ("report.pdf", false, true),
+            ("deep/down/report.pdf", false, true),
+            ("docs/a/notes.txt", false, true),
+            ("notes.txt", false, false),
+            ("photo.jpg", false, false),
+            ("photos", true, true),
+        ] {
+            assert_eq!(filter.allows(Path::new(path), is_dir), allowed, "{}", path);
+        }
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filter = filter(&["*.pdf"], &["draft*", "tmp"], &[]);
+        for (path, is_dir, allowed) in [
+            ("final.pdf", false, true),
+            ("draft.pdf", false, false),
+            ("old/draft-2.pdf", false, false),
+            ("tmp", true, false),
+            ("tmp.pdf", false, true),
+        ] {
+            assert_eq!(filter.allows(Path::new(path), is_dir), allowed, "{}", path);
+        }
+    }
+
+    #[test]
+    fn directory_patterns_prune_whole_subtrees() {
+        let filter = filter(&[], &[], &["node_modules", "build/cache"]);
+        for (path, pruned) in [
+            ("node_modules", true),
+            ("app/node_modules", true),
+            ("build/cache", true),
+            ("build", false),
+            ("src", false),
+        ] {
+            assert_eq!(filter.prunes(Path::new(path)), pruned, "{}", path);
+        }
+    }
+
+    #[test]
+    fn patterns_ignore_case_only_on_windows() {
+        let filter = filter(&[], &["*.TMP"], &["Cache"]);
+        assert!(!filter.allows(Path::new("scratch.TMP"), false));
+        assert_eq!(filter.allows(Path::new("scratch.tmp"), false), !cfg!(windows));
+        assert_eq!(filter.prunes(Path::new("cache")), cfg!(windows));
+    }
+
+    // Function to read a directory's entries and whether each one is hidden, by name
+    fn hidden_by_name(dir: &Path) -> Vec<(String, bool)> {
+        let mut hidden: Vec<(String, bool)> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap())
+            .map(|entry| (entry.file_name().to_string_lossy().into_owned(), is_hidden(&entry)))
+            .collect();
+        hidden.sort();
+        hidden
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn dot_names_are_hidden() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".env"), "").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        std::fs::create_dir(dir.path().join(".git")).unwrap();
+        std::fs::create_dir(dir.path().join("docs.d")).unwrap();
+
+        assert_eq!(hidden_by_name(dir.path()), [
+            (".env".to_string(), true),
+            (".git".to_string(), true),
+            ("docs.d".to_string(), false),
+            ("notes.txt".to_string(), false),
+        ]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn the_hidden_attribute_hides_and_dot_names_do_not() {
+        use std::os::windows::fs::OpenOptionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::OpenOptions::new().write(true).create_new(true)
+            .attributes(FILE_ATTRIBUTE_HIDDEN)
+            .open(dir.path().join("desktop.ini"))
+            .unwrap();
+        std::fs::write(dir.path().join(".env"), "").unwrap();
+
+        assert_eq!(hidden_by_name(dir.path()), [
+            (".env".to_string(), false),
+            ("desktop.ini".to_string(), true),
+        ]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Condvar, Mutex};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::error::MigrateError;
+
+// The --post-copy-command, run once per successful copy. Only so many run at a time,
+// so a fast copy phase can't spawn a process per worker per file without bound.
+pub(crate) struct PostCopyHook {
+    command: PathBuf,
+    fatal: bool,
+    dry_run: bool,
+    free_slots: Mutex<usize>,
+    slot_freed: Condvar,
+}
+
+// A claim on one of the hook's slots, given back when dropped
+struct Slot<'a>(&'a PostCopyHook);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free_slots.lock().unwrap() += 1;
+        self.0.slot_freed.notify_one();
+    }
+}
+
+impl PostCopyHook {
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        let command = config.post_copy_command.clone()?;
+        Some(PostCopyHook {
+            command,
+            fatal: config.hook_fatal,
+            dry_run: config.dry_run,
+            free_slots: Mutex::new(config.hook_concurrency.max(1)),
+            slot_freed: Condvar::new(),
+        })
+    }
+
+    // Function to wait for a free slot
+    fn slot(&self) -> Slot<'_> {
+        let mut free_slots = self.free_slots.lock().unwrap();
+        while *free_slots == 0 {
+            free_slots = self.slot_freed.wait(free_slots).unwrap();
+        }
+        *free_slots -= 1;
+        Slot(self)
+    }
+
+    // Function to run the command for a file copied from `source` to `destination`. The
+    // paths are passed as its two arguments and as XEROX_SOURCE / XEROX_DESTINATION.
+    // A failure only fails the file with --hook-fatal; otherwise it is logged.
+    pub(crate) fn run(&self, source: &Path, destination: &Path) -> Result<(), MigrateError> {
+        if self.dry_run {
+            info!("Would run {:?} for {:?}", self.command, destination);
+            return Ok(());
+        }
+
+        let status = {
+            let _slot = self.slot();
+            Command::new(&self.command)
+                .arg(source)
+                .arg(destination)
+                .env("XEROX_SOURCE", source)
+                .env("XEROX_DESTINATION", destination)
+                .stdin(Stdio::null())
+                .status()
+        };
+        let reason = match status {
+            Ok(status) if status.success() => return Ok(()),
+            Ok(status) => status.to_string(),
+            Err(e) => format!("could not start {:?}: {}", self.command, e),
+        };
+
+        let failure = MigrateError::HookFailed { path: destination.to_path_buf(), reason };
+        if self.fatal {
+            Err(failure)
+        } else {
+            warn!("{}", failure);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs::DirEntry;
+use std::path::Path;
+
+use crate::manifest::{completed_records, ManifestRecord};
+
+// How the source has changed since the run that wrote a manifest, for --since-manifest
+pub(crate) struct SourceDiff {
+    // Files that run did not copy to every target
+    pub(crate) added: Vec<DirEntry>,
+    // Files it copied whose size or modification time has changed since
+    pub(crate) changed: Vec<DirEntry>,
+    // Files still as they were copied, with the latest record for each target
+    pub(crate) unchanged: Vec<(DirEntry, Vec<ManifestRecord>)>,
+    // Copies, in one of the targets, of files no longer in the source
+    pub(crate) removed: Vec<ManifestRecord>,
+}
+
+// Function to compare a fresh walk of the source with a previous run's manifest. Only
+// copies count: a file the earlier run skipped or failed is treated as new. A file
+// copied to some targets but not all is new for the rest, so it is copied again;
+// targets that already have it then decide by the conflict policy.
+pub(crate) fn diff_since(records: Vec<ManifestRecord>, files: Vec<DirEntry>, targets: &[&Path]) -> SourceDiff {
+    let mut previous = completed_records(records, false);
+    let mut diff = SourceDiff { added: Vec::new(), changed: Vec::new(), unchanged: Vec::new(), removed: Vec::new() };
+
+    for file in files {
+        let records = previous.remove(&file.path()).unwrap_or_default();
+        // Later records supersede earlier ones for the same target
+        let latest: Vec<Option<&ManifestRecord>> = targets.iter()
+            .map(|target| records.iter().rev().find(|record| record.destination.starts_with(target)))
+            .collect();
+
+        if latest.iter().flatten().any(|record| !record.matches_source(&file)) {
+            diff.changed.push(file);
+        } else if latest.iter().any(Option::is_none) {
+            diff.added.push(file);
+        } else {
+            let latest = latest.into_iter().flatten().cloned().collect();
+            diff.unchanged.push((file, latest));
+        }
+    }
+
+    // Whatever is left was copied from a file that has since gone
+    let mut removed: Vec<ManifestRecord> = previous.into_values()
+        .flatten()
+        .filter(|record| targets.iter().any(|target| record.destination.starts_with(target)))
+        .collect();
+    removed.sort_by(|a, b| a.destination.cmp(&b.destination));
+    removed.dedup_by(|a, b| a.destination == b.destination);
+    diff.removed = removed;
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::manifest::Outcome;
+
+    // Function to look up the entry for each named file in `dir`
+    fn entries(dir: &Path, names: &[&str]) -> Vec<DirEntry> {
+        fs::read_dir(dir).unwrap()
+            .map(Result::unwrap)
+            .filter(|entry| names.iter().any(|name| entry.file_name() == **name))
+            .collect()
+    }
+
+    fn copied(entry: &DirEntry, target: &Path) -> ManifestRecord {
+        ManifestRecord::new(entry, target.join(entry.file_name()), None, Outcome::Copied)
+    }
+
+    fn names(files: &[DirEntry]) -> Vec<String> {
+        let mut names: Vec<String> = files.iter().map(|file| file.file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn sorts_files_into_added_changed_unchanged_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = Path::new("/target");
+        for name in ["same.txt", "edited.txt", "gone.txt"] {
+            fs::write(dir.path().join(name), "before").unwrap();
+        }
+        let records = entries(dir.path(), &["same.txt", "edited.txt", "gone.txt"]).iter()
+            .map(|entry| copied(entry, target))
+            .collect();
+
+        fs::write(dir.path().join("edited.txt"), "after, and longer").unwrap();
+        fs::remove_file(dir.path().join("gone.txt")).unwrap();
+        fs::write(dir.path().join("new.txt"), "new").unwrap();
+
+        let diff = diff_since(records, entries(dir.path(), &["same.txt", "edited.txt", "new.txt"]), &[target]);
+        assert_eq!(names(&diff.added), ["new.txt"]);
+        assert_eq!(names(&diff.changed), ["edited.txt"]);
+        assert_eq!(diff.unchanged.len(), 1);
+        assert_eq!(diff.unchanged[0].0.file_name(), "same.txt");
+        let removed: Vec<&Path> = diff.removed.iter().map(|record| record.destination.as_path()).collect();
+        assert_eq!(removed, [target.join("gone.txt")]);
+    }
+
+    #[test]
+    fn files_missing_from_a_target_count_as_added() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file.txt"), "contents").unwrap();
+        let files = entries(dir.path(), &["file.txt"]);
+        let records = vec![copied(&files[0], Path::new("/first"))];
+
+        let diff = diff_since(records, files, &[Path::new("/first"), Path::new("/second")]);
+        assert_eq!(names(&diff.added), ["file.txt"]);
+        assert!(diff.unchanged.is_empty() && diff.removed.is_empty());
+    }
+}
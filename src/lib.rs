@@ -0,0 +1,1201 @@
+//! The migration engine behind the xerox CLI.
+//!
+//! Build a [`Config`], hand it to a [`Migrator`] and call [`Migrator::run`] to
+//! copy (fetching cloud stubs as needed) every file from the source tree into
+//! the target, preserving its folder structure.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, DirEntry, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::prelude::*;
+use rayon::ThreadPool;
+use tracing::{info, info_span, error, warn, Span};
+
+mod archive;
+mod audit;
+mod config;
+mod copy;
+mod dedup;
+mod error;
+mod fastcopy;
+mod filter;
+mod hook;
+mod incremental;
+mod listing;
+mod manifest;
+mod names;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mirror;
+mod preflight;
+mod progress;
+mod queue;
+#[cfg(feature = "simulate-stubs")]
+mod simulate;
+mod stub;
+mod summary;
+mod template;
+mod throttle;
+mod timeout;
+mod walk;
+
+pub use audit::Audit;
+pub use config::{is_inside, DEFAULT_COPY_BUFFER_SIZE, DEFAULT_HOOK_CONCURRENCY, DEFAULT_PREFETCH_CONCURRENCY, ArchiveFormat, CaseCollisionPolicy, CompareMode, Config, ConflictPolicy, CopyMethod, CopyOnly, FileOrder, InvalidNamePolicy, ListFormat, PreservedTime, ProgressMode, RetryPolicy, StubSizeFilter, SymlinkPolicy};
+pub use error::MigrateError;
+pub use filter::IGNORE_FILE_NAME;
+pub use manifest::{ManifestRecord, Outcome};
+pub use copy::is_stable;
+#[cfg(feature = "simulate-stubs")]
+pub use simulate::SimulatedStubs;
+pub use stub::{is_stub, Hydration, Probe};
+pub use summary::{RunStatus, Summary, TargetSummary};
+pub use template::{DestTemplate, RenamePattern};
+
+use archive::{ArchiveWriter, Entry};
+use audit::{audit_file, FileAudit};
+use copy::{copy_to_target, create_target_directory_structure, destination_path, directory_destination, free_path, move_file, part_path, target_directories, Target};
+use filter::FileFilter;
+use hook::PostCopyHook;
+use incremental::diff_since;
+use listing::{write_listing, ListEntry};
+use manifest::{completed_records, failed_sources, read_manifest, write_checksums, ManifestWriter};
+use mirror::{delete_extraneous, delete_removed};
+use preflight::{canonical_root, check_conflicts, check_names, check_overlap, check_free_space, check_source, check_target, remove_partial_files};
+use progress::{spawn_event_writer, Counters, EventKind, Heartbeat, Progress};
+use queue::{FileJob, TargetQueue};
+use stub::{expected_size, fetch_file_with_progress, is_genuinely_empty, Fetch};
+use summary::FileOutcome;
+use throttle::RateLimiter;
+use walk::{entries_for, read_source_list, sort_files, visit_dirs, WalkContext};
+
+// Function to make the span every log line about one file is recorded in, so lines
+// from parallel copies can be told apart and filtered by path
+fn file_span(file: &DirEntry) -> Span {
+    info_span!("file", path = %file.path().display())
+}
+
+// Function to find a previous run's record of copying a file into a target, if it has one
+fn previous_copy<'a>(resume: &'a HashMap<PathBuf, Vec<ManifestRecord>>, file: &DirEntry, target: &Config) -> Option<&'a ManifestRecord> {
+    // Later records supersede earlier ones for the same destination
+    resume.get(&file.path())?.iter().rev().find(|record| record.destination.starts_with(&target.target))
+}
+
+// Function to get file size
+pub(crate) fn get_file_size(entry: &DirEntry) -> Option<u64> {
+    entry.metadata().ok().map(|metadata| metadata.len())
+}
+
+// Function to keep only the first `max_files` files for a trial run, returning how many
+// were left out
+fn apply_max_files(files: &mut Vec<DirEntry>, max_files: Option<usize>) -> usize {
+    match max_files {
+        Some(max_files) if files.len() > max_files => {
+            info!("Processing only the first {} of {} files (--max-files)", max_files, files.len());
+            let over_limit = files.len() - max_files;
+            files.truncate(max_files);
+            over_limit
+        }
+        _ => 0,
+    }
+}
+
+// Function to find names a Windows target would refuse before they fail the copy, leaving
+// them out if `on_invalid_name` skips them. Returns how many files had one. Everything
+// after works names out the same way, through destination_path.
+fn handle_invalid_names(files: &mut Vec<DirEntry>, dirs: &mut Vec<PathBuf>, config: &Config) -> Result<usize, MigrateError> {
+    let invalid_names = check_names(files, dirs, config)?;
+    let invalid_files = files.iter().filter(|file| invalid_names.contains(&file.path())).count();
+    if config.on_invalid_name == InvalidNamePolicy::Skip {
+        files.retain(|file| !invalid_names.contains(&file.path()));
+        dirs.retain(|dir| !invalid_names.contains(dir));
+    }
+    Ok(invalid_files)
+}
+
+// Function to give a path inside an archive, with / between names whatever the platform
+fn archive_name(path: &Path) -> String {
+    path.iter().map(|name| name.to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Copies a source tree into a target according to a [`Config`]
+pub struct Migrator {
+    config: Config,
+    filter: FileFilter,
+    walk_pool: ThreadPool,
+    copy_pool: ThreadPool,
+    prefetch_pool: Option<ThreadPool>,
+    hash_pool: Option<ThreadPool>,
+    shutdown: Arc<AtomicBool>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    hook: Option<PostCopyHook>,
+}
+
+impl Migrator {
+    /// Validate the config and prepare the filters and worker pools
+    pub fn new(mut config: Config) -> Result<Self, MigrateError> {
+        // Canonical roots keep the source-to-target mapping right for relative
+        // paths, `..` and symlinked roots
+        config.source = canonical_root(&config.source)?;
+        config.target = canonical_root(&config.target)?;
+        let mut extra_targets = Vec::new();
+        for target in &config.extra_targets {
+            let target = canonical_root(target)?;
+            if target == config.target || extra_targets.contains(&target) {
+                warn!("{:?} is given as a target more than once, copying to it once", target);
+            } else {
+                extra_targets.push(target);
+            }
+        }
+        config.extra_targets = extra_targets;
+
+        // An incremental sync replaces the earlier copies of changed files
+        if config.since_manifest.is_some() && config.on_conflict == ConflictPolicy::Skip {
+            config.on_conflict = ConflictPolicy::OverwriteIfNewer;
+        }
+
+        let filter = FileFilter::new(&config)?;
+
+        // Traversal and copying run on separate pools so each can be sized for its
+        // bottleneck; both fall back to `concurrency`, and 0 means one per CPU
+        let walk_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.walk_threads.or(config.concurrency).unwrap_or(0))
+            .build()?;
+        let copy_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.copy_threads.or(config.concurrency).unwrap_or(0))
+            .build()?;
+
+        // Downloads are network-bound, so the prefetch phase gets its own, wider pool
+        let prefetch_pool = if config.prefetch {
+            Some(rayon::ThreadPoolBuilder::new()
+                .num_threads(config.prefetch_concurrency)
+                .build()?)
+        } else {
+            None
+        };
+
+        // Hashing gets its own pool when capped, so copy threads queue for it
+        let hash_pool = match config.hash_threads {
+            Some(hash_threads) => Some(rayon::ThreadPoolBuilder::new().num_threads(hash_threads).build()?),
+            None => None,
+        };
+
+        // One bucket for every worker, so the limit bounds the run's total rate
+        let rate_limiter = config.max_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        let hook = PostCopyHook::new(&config);
+
+        Ok(Migrator {
+            config,
+            filter,
+            walk_pool,
+            copy_pool,
+            prefetch_pool,
+            hash_pool,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            rate_limiter,
+            hook,
+        })
+    }
+
+    /// The config this migrator was built from
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // Function to make a config per target root, the primary target first, so everything
+    // that maps a source file to its destination works on one target at a time
+    fn target_configs(&self) -> Vec<Config> {
+        self.config.targets()
+            .map(|target| Config { target: target.to_path_buf(), extra_targets: Vec::new(), ..self.config.clone() })
+            .collect()
+    }
+
+    /// A flag that, once set (e.g. from a Ctrl-C handler), stops the run from
+    /// starting new files. Files already in flight finish, the manifest is
+    /// still written, and [`Summary::not_started`] counts the files left over.
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutdown)
+    }
+
+    /// Write an inventory of every file the run would consider (its path
+    /// relative to the source, size and whether it is a stub) to `out` in
+    /// `list_format`, without copying or touching the target. Returns how many
+    /// files were listed.
+    pub fn list(&self, out: &mut dyn Write) -> Result<usize, MigrateError> {
+        let config = &self.config;
+        check_source(&config.source)?;
+
+        let (files, _, _) = self.select_files()?;
+        let mut entries: Vec<ListEntry> = files.iter()
+            .map(|file| ListEntry::new(file, &config.source, config.stub_max_size))
+            .collect();
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+        write_listing(&entries, config.list_format, out)?;
+        Ok(entries.len())
+    }
+
+    /// Check an earlier copy against the source without copying anything: every
+    /// source file's destination in each target must exist and match its size,
+    /// or its SHA-256 hash when `verify` is set.
+    pub fn verify_existing(&self) -> Result<Audit, MigrateError> {
+        let config = &self.config;
+        let targets = self.target_configs();
+        for target in &targets {
+            info!("Checking {} against {}", target.target.display(), config.source.display());
+        }
+        check_source(&config.source)?;
+
+        let (files, _, _) = self.select_files()?;
+        let audit = Mutex::new(Audit::default());
+        self.copy_pool.install(|| files.par_iter().for_each(|file| {
+            let _span = file_span(file).entered();
+            for target in &targets {
+                let outcome = audit_file(file, target).unwrap_or_else(|e| {
+                    warn!("Failed to check {:?}: {}", file.path(), e);
+                    FileAudit::Mismatched
+                });
+                audit.lock().unwrap().record(file.path(), outcome);
+            }
+        }));
+
+        let mut audit = audit.into_inner().unwrap();
+        audit.missing.sort();
+        audit.mismatched.sort();
+        Ok(audit)
+    }
+
+    /// Download every stub in the source in place, so the cloud client keeps
+    /// the contents local, without copying anything or touching the target.
+    /// Stubs are fetched as in a run, with the same retries and timeouts.
+    pub fn hydrate(&self) -> Result<Hydration, MigrateError> {
+        let config = &self.config;
+        info!("Fetching stubs in {} without copying", config.source.display());
+        check_source(&config.source)?;
+
+        let (files, _, _) = self.select_files()?;
+        let (multi_progress, header) = if config.show_progress {
+            let multi_progress = MultiProgress::new();
+            let header = multi_progress.insert(0, ProgressBar::new(files.len() as u64));
+            header.set_style(ProgressStyle::default_bar()
+                .template("{wide_bar} {pos}/{len} files ({eta})")
+                .progress_chars("##-"));
+            (multi_progress, Some(header))
+        } else {
+            (MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None)
+        };
+        let progress = Progress::new(multi_progress, None);
+        let drawer = header.is_some().then(|| progress.spawn_drawer());
+
+        let hydration = Mutex::new(Hydration::default());
+        self.copy_pool.install(|| files.par_iter().for_each(|file| {
+            if self.shutdown.load(Ordering::Relaxed) {
+                hydration.lock().unwrap().not_started += 1;
+                return;
+            }
+            let _span = file_span(file).entered();
+            let fetch = fetch_file_with_progress(file, &progress, config);
+            let mut hydration = hydration.lock().unwrap();
+            match fetch {
+                Ok(Fetch { fetched: true, retries }) => {
+                    hydration.fetched += 1;
+                    if retries > 0 {
+                        hydration.retried += 1;
+                    }
+                }
+                Ok(_) => hydration.local += 1,
+                Err(e) => {
+                    if matches!(e, MigrateError::StubLockTimeout { .. }) {
+                        hydration.retried += 1;
+                    }
+                    hydration.failed.push(file.path());
+                }
+            }
+            if let Some(header) = &header {
+                header.inc(1);
+            }
+        }));
+
+        if let Some(header) = &header {
+            header.finish();
+        }
+        if let Some(drawer) = drawer {
+            drawer.join().expect("progress bar drawer panicked")?;
+        }
+        let mut hydration = hydration.into_inner().unwrap();
+        hydration.failed.sort();
+        Ok(hydration)
+    }
+
+    /// Count the source's stubs and hydrated files, and how many bytes each
+    /// hold, from their metadata alone: nothing is opened or downloaded, so
+    /// this shows how much a copy would have to fetch before it starts.
+    pub fn probe(&self) -> Result<Probe, MigrateError> {
+        let config = &self.config;
+        info!("Probing {} for stubs", config.source.display());
+        check_source(&config.source)?;
+
+        let (files, _, _) = self.select_files()?;
+        let probe = self.walk_pool.install(|| files.par_iter().fold(Probe::default, |mut probe, file| {
+            probe.add(file, config);
+            probe
+        }).reduce(Probe::default, |mut total, probe| {
+            total.stubs += probe.stubs;
+            total.hydrated += probe.hydrated;
+            total.stub_bytes += probe.stub_bytes;
+            total.unsized_stubs += probe.unsized_stubs;
+            total.hydrated_bytes += probe.hydrated_bytes;
+            total
+        }));
+        Ok(probe)
+    }
+
+    /// Pack every selected file into one archive at the target path, in
+    /// `format`, instead of copying them loose. Files are streamed in a buffer
+    /// at a time, under their paths in the target and with their modification
+    /// times; stubs are fetched first. The archive is written beside the target
+    /// as a partial file and only renamed into place once complete.
+    pub fn archive(&self, format: ArchiveFormat) -> Result<Summary, MigrateError> {
+        let config = &self.config;
+        info!("Archiving {} into {} as {}", config.source.display(), config.target.display(), format.extension());
+        if !config.extra_targets.is_empty() {
+            warn!("Only the first target is archived into");
+        }
+        check_source(&config.source)?;
+        check_overlap(&config.source, &config.target)?;
+
+        // The target is the archive itself, so an existing one is a conflict like any other
+        let not_writable = |source| MigrateError::TargetNotWritable { path: config.target.clone(), source };
+        let archive_path = match config.on_conflict {
+            _ if config.target.is_dir() => return Err(not_writable(io::Error::other("target is a directory, not an archive file"))),
+            _ if !config.target.exists() => config.target.clone(),
+            ConflictPolicy::Overwrite | ConflictPolicy::OverwriteIfNewer => config.target.clone(),
+            ConflictPolicy::Rename => free_path(&config.target, &config.rename_pattern),
+            ConflictPolicy::Skip => return Err(not_writable(io::Error::new(io::ErrorKind::AlreadyExists,
+                "the archive already exists (see --on-conflict)"))),
+        };
+
+        let (mut files, mut dirs, excluded) = self.select_files()?;
+        let over_limit = apply_max_files(&mut files, config.max_files);
+        let invalid_files = handle_invalid_names(&mut files, &mut dirs, config)?;
+        let mut summary = Summary {
+            excluded,
+            over_limit,
+            sanitized: if config.on_invalid_name == InvalidNamePolicy::Sanitize { invalid_files } else { 0 },
+            skipped_invalid: if config.on_invalid_name == InvalidNamePolicy::Skip { invalid_files } else { 0 },
+            ..Summary::default()
+        };
+        // Names inside the archive are the paths files would have in a target at the archive's path
+        let name_of = |destination: PathBuf| archive_name(destination.strip_prefix(&config.target).unwrap_or(&destination));
+
+        if config.dry_run {
+            for file in &files {
+                info!("Would archive {:?} as {:?}", file.path(), name_of(destination_path(&file.path(), config)?));
+                summary.copied += 1;
+                summary.bytes_transferred += get_file_size(file).unwrap_or(0);
+            }
+            return Ok(summary);
+        }
+
+        if let Some(parent) = archive_path.parent() {
+            fs::create_dir_all(parent).map_err(not_writable)?;
+        }
+        let part = part_path(&archive_path, &config.source);
+        let mut archive = ArchiveWriter::new(format, BufWriter::new(File::create(&part).map_err(not_writable)?));
+
+        let overall = config.show_progress.then(|| {
+            let pb = ProgressBar::new(files.iter().map(|file| get_file_size(file).unwrap_or(0)).sum());
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{wide_bar} {bytes}/{total_bytes} {msg} ({eta})")
+                .progress_chars("##-"));
+            pb
+        });
+        let progress = Progress::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None);
+        let mut buffer = vec![0; config.copy_buffer_size];
+
+        let written = (|| -> Result<(), MigrateError> {
+            // Only a mirrored tree has a place for the source's own folders
+            if !config.flatten && !config.prune_empty_dirs && config.dest_template.is_none() {
+                for dir in &dirs {
+                    let name = name_of(directory_destination(dir, config)?);
+                    archive.add_dir(&Entry::new(&name, &fs::metadata(dir)?)?)?;
+                }
+            }
+
+            for file in &files {
+                if self.shutdown.load(Ordering::Relaxed) {
+                    summary.not_started += 1;
+                    continue;
+                }
+                let _span = file_span(file).entered();
+                let name = name_of(destination_path(&file.path(), config)?);
+
+                // Failing to fetch or open a file leaves it out; failing part way
+                // through its data would leave a broken entry, so ends the archive
+                let opened = fetch_file_with_progress(file, &progress, config).and_then(|fetch| {
+                    if fetch.fetched {
+                        summary.stubs_fetched += 1;
+                    }
+                    let reader = File::open(file.path())?;
+                    let metadata = reader.metadata()?;
+                    Ok((reader, metadata))
+                });
+                let (mut reader, metadata) = match opened {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        error!("Failed to archive {:?}: {}", file.path(), e);
+                        summary.failed += 1;
+                        summary.failures.entry(e.category()).or_default().push(file.path());
+                        if config.fail_fast {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                info!("Archiving {:?} as {:?}", file.path(), name);
+                let bytes = archive.add_file(&Entry::new(&name, &metadata)?, &mut reader, &mut buffer, |chunk| {
+                    if let Some(rate_limiter) = &self.rate_limiter {
+                        rate_limiter.acquire(chunk.len());
+                    }
+                    if let Some(pb) = &overall {
+                        pb.inc(chunk.len() as u64);
+                    }
+                }).map_err(|e| io::Error::new(e.kind(), format!("failed to archive {:?}: {}", file.path(), e)))?;
+                summary.copied += 1;
+                summary.bytes_transferred += bytes;
+            }
+
+            let mut out = archive.finish()?;
+            out.flush()?;
+            out.get_ref().sync_all()?;
+            Ok(())
+        })();
+        if let Some(pb) = &overall {
+            pb.finish_and_clear();
+        }
+        if let Err(e) = written {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+        fs::rename(&part, &archive_path)?;
+        info!("Wrote {} files to {:?}", summary.copied, archive_path);
+
+        let per_target = summary.targets.entry(archive_path).or_default();
+        per_target.copied = summary.copied;
+        per_target.failed = summary.failed;
+        per_target.bytes_transferred = summary.bytes_transferred;
+        Ok(summary)
+    }
+
+    // Function to find the files a run works on, in processing order: the whole source,
+    // or only the files a previous run failed on or a source list names. Also returns
+    // the directories walked and how many files the filters dropped.
+    fn select_files(&self) -> Result<(Vec<DirEntry>, Vec<PathBuf>, usize), MigrateError> {
+        let config = &self.config;
+        let mut walk = WalkContext::new(&config.source, &self.filter, config.symlinks);
+        walk.max_depth = config.max_depth;
+        // A big tree can take minutes to walk, so show that it is getting somewhere
+        if config.show_progress {
+            let spinner = ProgressBar::new_spinner();
+            spinner.set_style(ProgressStyle::default_spinner().template("{spinner} {msg} ({elapsed})"));
+            spinner.set_message("Scanning source");
+            spinner.enable_steady_tick(100);
+            walk.spinner = Some(spinner);
+        }
+
+        let (mut files, dirs) = match (&config.retry_failed, &config.source_list) {
+            (Some(manifest_path), _) => {
+                let failed = failed_sources(read_manifest(manifest_path)?);
+                info!("Retrying {} files that failed in {:?}", failed.len(), manifest_path);
+                (self.walk_pool.install(|| entries_for(&failed, &config.source)), Vec::new())
+            }
+            (None, Some(list_path)) => {
+                let listed = read_source_list(list_path, &config.source)?;
+                info!("Copying {} files listed in {:?}", listed.len(), list_path);
+                (self.walk_pool.install(|| entries_for(&listed, &config.source)), Vec::new())
+            }
+            (None, None) => match self.walk_pool.install(|| visit_dirs(&config.source, &walk)) {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Failed to read box directory: {:?}", e);
+                    return Err(e);
+                }
+            },
+        };
+        if let Some(spinner) = &walk.spinner {
+            spinner.finish_and_clear();
+        }
+        info!("Found {} files in {} directories", files.len(), dirs.len());
+
+        sort_files(&mut files, config.order);
+        Ok((files, dirs, walk.excluded.into_inner()))
+    }
+
+    /// Run the migration, returning a summary of every file's outcome.
+    ///
+    /// Individual file failures are counted in the summary rather than returned
+    /// as errors; with `fail_fast` set the run stops after the first one.
+    pub fn run(&self) -> Result<Summary, MigrateError> {
+        let config = &self.config;
+        let targets: Vec<Target> = self.target_configs().into_iter().map(Target::new).collect();
+        for target in &targets {
+            info!("Copying from {} to {}", config.source.display(), target.config.target.display());
+        }
+        if config.dry_run {
+            info!("Dry run: no files or directories will be written");
+        }
+
+        // Fail early on a bad source or unusable target, before the slow traversal
+        check_source(&config.source)?;
+        for target in &targets {
+            check_overlap(&config.source, &target.config.target)?;
+            check_target(&target.config.target, &target.config)?;
+            // Partial copies are kept to be carried on from, if they still match
+            if !config.resume_partial {
+                remove_partial_files(&target.config.target, config.dry_run)?;
+            }
+        }
+
+        // Files finished by a previous run are skipped without re-reading them
+        let resume = match &config.resume {
+            Some(resume_path) => {
+                let completed = completed_records(read_manifest(resume_path)?, config.verify);
+                info!("Resuming from {:?}: {} files already copied", resume_path, completed.len());
+                completed
+            }
+            None => HashMap::new(),
+        };
+        // Read before the manifest is created, in case this run writes to the same file
+        let since = match &config.since_manifest {
+            Some(since_path) => {
+                info!("Copying only what changed since {:?}", since_path);
+                Some(read_manifest(since_path)?)
+            }
+            None => None,
+        };
+
+        // Records are appended as files finish, so a run that dies part way still leaves
+        // a manifest to resume from. Resuming into the manifest being written replaces
+        // it, so the records carried over are written again first.
+        let carried_over = config.resume.as_ref()
+            .zip(config.manifest.as_ref())
+            .is_some_and(|(resume_path, manifest_path)| fs::canonicalize(resume_path).ok() == fs::canonicalize(manifest_path).ok());
+        let manifest = match (&config.manifest, config.dry_run) {
+            (Some(manifest_path), false) => Some(ManifestWriter::create(manifest_path, config.checkpoint_files, config.checkpoint_interval)?),
+            _ => None,
+        };
+        if let (Some(manifest), true) = (&manifest, carried_over) {
+            for record in resume.values().flatten() {
+                manifest.append(record)?;
+            }
+        }
+
+        let (files, mut dirs, excluded) = self.select_files()?;
+
+        // With --since-manifest only what changed since that run is copied. Unchanged
+        // files keep their earlier records, so the next run can start from this one.
+        let (mut files, unchanged, removed) = match since {
+            Some(records) => {
+                let roots: Vec<&Path> = targets.iter().map(|target| target.config.target.as_path()).collect();
+                let diff = diff_since(records, files, &roots);
+                info!("{} new, {} changed and {} unchanged files; {} copies of removed files",
+                    diff.added.len(), diff.changed.len(), diff.unchanged.len(), diff.removed.len());
+                let mut files = diff.added;
+                files.extend(diff.changed);
+                sort_files(&mut files, config.order);
+                (files, diff.unchanged, Some(diff.removed))
+            }
+            None => (files, Vec::new(), None),
+        };
+        if let Some(manifest) = &manifest {
+            for record in unchanged.iter().flat_map(|(_, records)| records) {
+                manifest.append(record)?;
+            }
+        }
+
+        // A trial run takes only the first files in processing order
+        let over_limit = apply_max_files(&mut files, config.max_files);
+
+        let invalid_files = handle_invalid_names(&mut files, &mut dirs, config)?;
+
+        for target in &targets {
+            // Stop before writing anything if two files would end up in the same place
+            if config.check_conflicts {
+                check_conflicts(&files, &target.config)?;
+            }
+
+            // Stop before writing anything if the target volume is too small
+            if config.only != CopyOnly::Dirs {
+                check_free_space(&target.config.target, &files, &target.config)?;
+            }
+        }
+
+        // Create all directories in the target locations up front, each one once, so
+        // workers never race to create the same path while copying
+        let mut target_dirs = Vec::new();
+        for target in &targets {
+            target_dirs.extend(target_directories(&files, &dirs, &target.config)?);
+        }
+        self.walk_pool.install(|| target_dirs.par_iter()
+            .try_for_each(|dir| create_target_directory_structure(dir, config.dry_run)))?;
+
+        // With --only dirs the skeleton is all this run creates; the files wait for a later one
+        let left_for_later = if config.only == CopyOnly::Dirs {
+            info!("Created the directories only; leaving {} files for a later run (--only dirs)", files.len());
+            std::mem::take(&mut files)
+        } else {
+            Vec::new()
+        };
+
+        // Sizes known before copying; stubs whose cloud size is unknown start at zero and
+        // are added to the byte total once fetched
+        let expected_sizes: HashMap<PathBuf, u64> = files.iter()
+            .map(|file| (file.path(), expected_size(file, config.stub_max_size).unwrap_or(0)))
+            .collect();
+        let total_bytes: u64 = expected_sizes.values().sum();
+
+        // Create a MultiProgress instance for per-file bars below a header for the whole run,
+        // or just the single bar for the whole run. The overall bar counts bytes, so its ETA
+        // isn't thrown by files of very different sizes.
+        let overall_bar = || {
+            let pb = ProgressBar::new(total_bytes);
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{wide_bar} {bytes}/{total_bytes} {msg} ({eta})")
+                .progress_chars("##-"));
+            pb.set_message(format!("(0/{} files)", files.len()));
+            pb
+        };
+        let (multi_progress, overall) = match config.progress {
+            _ if !config.show_progress => (MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None),
+            ProgressMode::PerFile => {
+                let multi_progress = MultiProgress::new();
+                // Inserted first, the header stays on top however many file bars come and go
+                let header = multi_progress.insert(0, overall_bar());
+                (multi_progress, Some(header))
+            }
+            ProgressMode::Aggregate => (MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), Some(overall_bar())),
+        };
+
+        // With --progress-json, a writer thread turns events from every worker into JSON lines
+        let (events, event_writer) = match &config.progress_json {
+            Some(path) => {
+                let (sender, handle) = spawn_event_writer(path)?;
+                (Some(sender), Some(handle))
+            }
+            None => (None, None),
+        };
+        let progress = Progress::new(multi_progress, events);
+        let drawer = (config.show_progress && config.progress == ProgressMode::PerFile).then(|| progress.spawn_drawer());
+        let counters = Arc::new(Counters::new(files.len(), total_bytes)
+            .with_targets(targets.iter().map(|target| target.config.target.as_path()))
+            .with_bytes_written(progress.written()));
+        #[cfg(feature = "metrics")]
+        let metrics_server = match config.metrics_addr {
+            Some(addr) => Some(metrics::MetricsServer::start(std::net::TcpListener::bind(addr)?, Arc::clone(&counters))?),
+            None => None,
+        };
+
+        // Per-file outcomes are accumulated here so a report is always printed
+        let summary = Mutex::new(Summary {
+            excluded,
+            over_limit,
+            left_for_later: left_for_later.len(),
+            sanitized: if config.on_invalid_name == InvalidNamePolicy::Sanitize { invalid_files } else { 0 },
+            skipped_invalid: if config.on_invalid_name == InvalidNamePolicy::Skip { invalid_files } else { 0 },
+            unchanged: unchanged.iter().map(|(_, records)| records.len()).sum(),
+            targets: targets.iter().map(|target| (target.config.target.clone(), TargetSummary::default())).collect(),
+            ..Summary::default()
+        });
+
+        // Hydrate every stub up front so downloads overlap instead of queueing behind copies.
+        // A stub that fails here is fetched again, with retries, during the copy phase.
+        // Each fetched stub keeps its retry count for the manifest.
+        let mut prefetched = HashMap::new();
+        if let (Some(prefetch_pool), false) = (&self.prefetch_pool, config.dry_run) {
+            let stubs: Vec<&DirEntry> = files.iter()
+                .filter(|file| is_stub(file, config.stub_max_size))
+                .filter(|file| targets.iter().any(|target| previous_copy(&resume, file, &target.config).is_none_or(|record| !record.matches_source(file))))
+                .collect();
+            info!("Prefetching {} stub files", stubs.len());
+
+            let fetched = Mutex::new(HashMap::new());
+            prefetch_pool.install(|| stubs.par_iter().for_each(|file| {
+                if self.shutdown.load(Ordering::Relaxed) {
+                    return;
+                }
+                let _span = file_span(file).entered();
+                match fetch_file_with_progress(file, &progress, config) {
+                    Ok(Fetch { fetched: true, retries }) => { fetched.lock().unwrap().insert(file.path(), retries); }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to prefetch {:?}, retrying during copy: {}", file.path(), e),
+                }
+            }));
+            prefetched = fetched.into_inner().unwrap();
+            summary.lock().unwrap().stubs_fetched += prefetched.len();
+        }
+
+        // Hashes of copied files for the checksum manifest, sorted before writing
+        let checksums = Mutex::new(Vec::new());
+        // Where files actually landed, which differs from destination_path when renamed
+        let destinations = Mutex::new(HashSet::new());
+        // Set once a copy would eat into the --min-free-space margin; no new copies start after
+        let space_exhausted = AtomicBool::new(false);
+
+        // Record how copying a file into one target went, in the manifest and summary
+        let record_outcome = |file: &DirEntry, target: &Config, result: Result<&FileOutcome, &MigrateError>, retries: u32| {
+            if let Some(manifest) = &manifest {
+                let mut record = match result {
+                    Ok(FileOutcome::Copied { sha256, source_sha256, destination, .. }) => {
+                        let mut record = ManifestRecord::new(file, destination.clone(), sha256.clone(), Outcome::Copied);
+                        record.source_sha256 = source_sha256.clone();
+                        record
+                    }
+                    // A file whose destination can't be worked out is recorded without one
+                    Ok(FileOutcome::Skipped) | Err(MigrateError::FreeSpaceMargin { .. }) => {
+                        ManifestRecord::new(file, destination_path(&file.path(), target).unwrap_or_default(), None, Outcome::Skipped)
+                    }
+                    Err(_) => ManifestRecord::new(file, destination_path(&file.path(), target).unwrap_or_default(), None, Outcome::Failed),
+                };
+                record.retries = retries;
+                if let Err(e) = manifest.append(&record) {
+                    error!("Failed to write manifest record for {:?}: {}", file.path(), e);
+                }
+            }
+
+            if config.mirror {
+                if let Ok(FileOutcome::Copied { destination, .. }) = result {
+                    destinations.lock().unwrap().insert(destination.clone());
+                }
+            }
+
+            // Checksum paths are relative to a single root, so only the primary target's are listed
+            if config.checksum_manifest.is_some() && target.target == config.target {
+                if let Ok(FileOutcome::Copied { sha256: Some(sha256), destination, .. }) = result {
+                    let relative_path = destination.strip_prefix(&target.target).unwrap_or(destination);
+                    checksums.lock().unwrap().push((relative_path.to_path_buf(), sha256.clone()));
+                }
+            }
+
+            let mut summary = summary.lock().unwrap();
+            let summary = &mut *summary;
+            let per_target = summary.targets.entry(target.target.clone()).or_default();
+            match result {
+                Ok(FileOutcome::Copied { bytes, conflict, linked, .. }) => {
+                    summary.copied += 1;
+                    per_target.copied += 1;
+                    counters.files_copied.fetch_add(1, Ordering::Relaxed);
+                    if *linked {
+                        summary.linked += 1;
+                        summary.bytes_saved += bytes;
+                    } else {
+                        summary.bytes_transferred += bytes;
+                        per_target.bytes_transferred += bytes;
+                        counters.bytes_copied.fetch_add(*bytes, Ordering::Relaxed);
+                        if let Some(target_counters) = counters.target(&target.target) {
+                            target_counters.bytes_copied.fetch_add(*bytes, Ordering::Relaxed);
+                        }
+                    }
+                    match conflict {
+                        Some(ConflictPolicy::Overwrite | ConflictPolicy::OverwriteIfNewer) => summary.overwritten += 1,
+                        Some(ConflictPolicy::Rename) => summary.renamed += 1,
+                        _ => {}
+                    }
+                }
+                Ok(FileOutcome::Skipped) => {
+                    summary.skipped += 1;
+                    per_target.skipped += 1;
+                }
+                Err(e @ MigrateError::FreeSpaceMargin { .. }) => {
+                    if !space_exhausted.swap(true, Ordering::Relaxed) {
+                        warn!("{}; not starting any more copies", e);
+                    }
+                    summary.skipped_for_space += 1;
+                    per_target.skipped += 1;
+                }
+                Err(e) => {
+                    summary.failed += 1;
+                    per_target.failed += 1;
+                    counters.failures.fetch_add(1, Ordering::Relaxed);
+                    summary.failures.entry(e.category()).or_default().push(file.path());
+                    // Verification mismatches need no more detail than the report gives
+                    if !matches!(e, MigrateError::VerificationFailed { .. }) {
+                        error!("Failed to process file {:?}: {}", file.path(), e);
+                    }
+                }
+            }
+        };
+
+        // With --workers-per-target each target copies from its own queue, fed as files are
+        // fetched. Moving the source has to wait for every copy, so it keeps targets in step.
+        let workers_per_target = config.workers_per_target.filter(|&workers| workers > 0 && !config.move_source);
+        let queues: Vec<TargetQueue> = match workers_per_target {
+            Some(_) => targets.iter().map(|target| TargetQueue::new(target.config.target.clone())).collect(),
+            None => Vec::new(),
+        };
+        // Queued files are borrowed from the file list for as long as the workers run
+        let entries: HashMap<PathBuf, &DirEntry> = match workers_per_target {
+            Some(_) => files.iter().map(|file| (file.path(), file)).collect(),
+            None => HashMap::new(),
+        };
+        // Set by a target's worker on the first failure with fail_fast, as the copy pool
+        // no longer sees copy errors
+        let halted = AtomicBool::new(false);
+
+        // Fetch a single file and move it to every target, recording the outcomes. Returns
+        // false if the file was queued for the targets' workers, which finish it instead.
+        let process_file = |file: &'_ DirEntry| -> Result<bool, MigrateError> {
+            let _span = file_span(file).entered();
+
+            // Targets a previous run already finished this file for are left alone
+            let mut pending = Vec::with_capacity(targets.len());
+            for target in &targets {
+                match previous_copy(&resume, file, &target.config) {
+                    Some(record) if record.matches_source(file) => {
+                        info!("Already copied, skipping: {:?}", record.destination);
+                        let mut summary = summary.lock().unwrap();
+                        summary.resumed += 1;
+                        summary.targets.entry(target.config.target.clone()).or_default().resumed += 1;
+                        if let (Some(manifest), false) = (&manifest, carried_over) {
+                            if let Err(e) = manifest.append(record) {
+                                error!("Failed to write manifest record for {:?}: {}", file.path(), e);
+                            }
+                        }
+                        if config.mirror {
+                            destinations.lock().unwrap().insert(record.destination.clone());
+                        }
+                    }
+                    Some(record) => {
+                        info!("Source changed since last run, copying again: {:?}", record.destination);
+                        pending.push(target);
+                    }
+                    None => pending.push(target),
+                }
+            }
+            if pending.is_empty() {
+                return Ok(true);
+            }
+
+            let size = get_file_size(file);
+            progress.emit(EventKind::Started, &file.path(), 0, size, None);
+
+            // Fetch the file with progress (this will trigger download if it's a stub)
+            let fetch = match prefetched.get(&file.path()) {
+                Some(&retries) => Ok(Fetch { fetched: false, retries }),
+                None => fetch_file_with_progress(file, &progress, config),
+            };
+            let retries = match &fetch {
+                Ok(fetch) => fetch.retries,
+                Err(MigrateError::StubLockTimeout { attempts, .. }) => attempts.saturating_sub(1),
+                Err(_) => 0,
+            };
+            if retries > 0 {
+                counters.retries.fetch_add(u64::from(retries), Ordering::Relaxed);
+                let mut summary = summary.lock().unwrap();
+                summary.retried += 1;
+                summary.max_attempts = summary.max_attempts.max(retries + 1);
+            }
+            // None once the file is ready to copy
+            let outcomes = match fetch {
+                Ok(Fetch { fetched, .. }) if is_genuinely_empty(file, fetched, config) => {
+                    summary.lock().unwrap().empty_files += 1;
+                    if fetched {
+                        info!("Fetched file is empty, so it was an empty file rather than a stub: {:?}", file.path());
+                    }
+                    if config.copy_empty_files {
+                        info!("Copying empty file: {:?}", file.path());
+                        Ok(None)
+                    } else {
+                        info!("Skipping empty file: {:?}", file.path());
+                        Ok(Some(pending.iter().map(|target| (*target, Ok(FileOutcome::Skipped))).collect()))
+                    }
+                }
+                Ok(Fetch { fetched, .. }) => {
+                    if fetched {
+                        summary.lock().unwrap().stubs_fetched += 1;
+                    }
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            };
+
+            // A failed fetch fails the file for every target
+            let outcomes = match outcomes {
+                Ok(Some(outcomes)) => outcomes,
+                // Move the file to the one-drive directories, preserving folder structure
+                Ok(None) if queues.is_empty() => {
+                    move_file(file, &pending, &progress, self.rate_limiter.as_ref(), self.hash_pool.as_ref(), self.hook.as_ref(), config.move_source)
+                }
+                // Or leave it to each target's workers
+                Ok(None) => {
+                    let job = Arc::new(FileJob::new(pending.len(), size, retries));
+                    let file = entries[&file.path()];
+                    for (queue, target_counters) in queues.iter().zip(&counters.targets) {
+                        if pending.iter().any(|target| target.config.target == queue.root) {
+                            target_counters.queued.fetch_add(1, Ordering::Relaxed);
+                            queue.push(file, Arc::clone(&job));
+                        }
+                    }
+                    return Ok(false);
+                }
+                Err(e) => {
+                    for target in &pending {
+                        record_outcome(file, &target.config, Err(&e), retries);
+                    }
+                    progress.emit(EventKind::Error, &file.path(), 0, size, Some(e.to_string()));
+                    return Err(e);
+                }
+            };
+
+            let mut bytes = 0;
+            let mut first_error = None;
+            for (target, result) in outcomes {
+                record_outcome(file, &target.config, result.as_ref(), retries);
+                match result {
+                    Ok(FileOutcome::Copied { bytes: copied, .. }) => bytes += copied,
+                    Ok(FileOutcome::Skipped) => {}
+                    Err(e) => { first_error.get_or_insert(e); }
+                }
+            }
+
+            match first_error {
+                None => {
+                    progress.emit(EventKind::Completed, &file.path(), bytes, size, None);
+                    Ok(true)
+                }
+                Some(e) => {
+                    progress.emit(EventKind::Error, &file.path(), 0, size, Some(e.to_string()));
+                    Err(e)
+                }
+            }
+        };
+
+        // Count a file as finished and advance the overall bar, whatever its outcome
+        let track = |file: &DirEntry| {
+            let expected = expected_sizes.get(&file.path()).copied().unwrap_or(0);
+            let (done, total) = counters.finish_file(expected, get_file_size(file).unwrap_or(0));
+            if let Some(pb) = &overall {
+                pb.set_length(total);
+                pb.set_position(done);
+                pb.set_message(format!("({}/{} files)", counters.files_done.load(Ordering::Relaxed), counters.total_files));
+            }
+        };
+        let process_and_track = |file: &'_ DirEntry| -> Result<(), MigrateError> {
+            match process_file(file) {
+                Ok(false) => Ok(()),
+                result => {
+                    track(file);
+                    result.map(|_| ())
+                }
+            }
+        };
+
+        // A target's worker copies queued files into it, and whichever target finishes a
+        // file last reports it and counts it as done. Files still queued once the run is
+        // stopped are left uncopied.
+        let target_worker = |target: &Target, queue: &TargetQueue| {
+            while let Some((file, job)) = queue.pop() {
+                if let Some(target_counters) = counters.target(&queue.root) {
+                    target_counters.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                let _span = file_span(file).entered();
+                let result = if halted.load(Ordering::Relaxed) || self.shutdown.load(Ordering::Relaxed) {
+                    None
+                } else {
+                    let result = copy_to_target(file, target, &progress, self.rate_limiter.as_ref(), self.hash_pool.as_ref(), self.hook.as_ref(), false);
+                    record_outcome(file, &target.config, result.as_ref(), job.retries);
+                    Some(result)
+                };
+                let Some(outcome) = job.finish(result) else { continue };
+                match outcome.first_error {
+                    Some(e) => {
+                        progress.emit(EventKind::Error, &file.path(), 0, job.size, Some(e.to_string()));
+                        if config.fail_fast {
+                            halted.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    None if outcome.not_started => {
+                        if self.shutdown.load(Ordering::Relaxed) {
+                            summary.lock().unwrap().not_started += 1;
+                        }
+                    }
+                    None => progress.emit(EventKind::Completed, &file.path(), outcome.bytes, job.size, None),
+                }
+                track(file);
+            }
+        };
+
+        // Once shutdown is requested, or the free-space margin reached, remaining files
+        // are counted but not started
+        let interrupted = |file: &DirEntry| -> bool {
+            // As with a failed try_for_each, files after a fail-fast stop are left uncounted
+            if halted.load(Ordering::Relaxed) {
+                return true;
+            }
+            if space_exhausted.load(Ordering::Relaxed) {
+                summary.lock().unwrap().skipped_for_space += 1;
+            } else if self.shutdown.load(Ordering::Relaxed) {
+                summary.lock().unwrap().not_started += 1;
+            } else {
+                return false;
+            }
+            track(file);
+            true
+        };
+
+        // With a settle time, files still being written are deferred to a second pass
+        let deferred = Mutex::new(HashSet::new());
+        let first_pass = |file: &DirEntry| -> Result<(), MigrateError> {
+            if interrupted(file) {
+                return Ok(());
+            }
+            if let Some(settle) = config.settle_time {
+                // Stat errors are left for process_file to report
+                if !is_stable(&file.path(), settle).unwrap_or(true) {
+                    info!("Still being written, deferring: {:?}", file.path());
+                    deferred.lock().unwrap().insert(file.path());
+                    return Ok(());
+                }
+            }
+            process_and_track(file)
+        };
+
+        // A periodic log line shows the run is alive even without progress bars
+        let heartbeat = config.report_interval.map(|interval| Heartbeat::start(Arc::clone(&counters), interval));
+
+        // The targets' workers, if any, run alongside the copy pool until both passes are over
+        let stopped = thread::scope(|scope| {
+            for (target, queue) in targets.iter().zip(&queues) {
+                for _ in 0..workers_per_target.unwrap_or(0) {
+                    let target_worker = &target_worker;
+                    scope.spawn(move || target_worker(target, queue));
+                }
+            }
+
+            // Iterate through the files, sorted by size. Failures are already
+            // recorded, so without fail_fast keep going with the remaining files.
+            let stopped = self.copy_pool.install(|| if config.fail_fast {
+                files.par_iter().try_for_each(first_pass).is_err()
+            } else {
+                files.par_iter().for_each(|file| { let _ = first_pass(file); });
+                false
+            }) || halted.load(Ordering::Relaxed);
+
+            // Give deferred files one more settle period, then skip any still changing
+            let deferred = std::mem::take(&mut *deferred.lock().unwrap());
+            if let (Some(settle), false, false) = (config.settle_time, stopped, deferred.is_empty()) {
+                info!("Retrying {} files that were still being written", deferred.len());
+                let deferred: Vec<&DirEntry> = files.iter().filter(|file| deferred.contains(&file.path())).collect();
+
+                let second_pass = |file: &&DirEntry| -> Result<(), MigrateError> {
+                    if interrupted(file) {
+                        return Ok(());
+                    }
+                    if !is_stable(&file.path(), settle).unwrap_or(true) {
+                        warn!("Still being written, skipping: {:?}", file.path());
+                        summary.lock().unwrap().unstable += 1;
+                        track(file);
+                        return Ok(());
+                    }
+                    process_and_track(file)
+                };
+                self.copy_pool.install(|| if config.fail_fast {
+                    let _ = deferred.par_iter().try_for_each(second_pass);
+                } else {
+                    deferred.par_iter().for_each(|file| { let _ = second_pass(file); });
+                });
+            }
+
+            // Every file is fetched and queued, so the workers stop once their queues are drained
+            for queue in &queues {
+                queue.close();
+            }
+            stopped
+        }) || halted.load(Ordering::Relaxed);
+
+        if let Some(heartbeat) = heartbeat {
+            heartbeat.stop();
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.stop();
+        }
+        if let Some(pb) = &overall {
+            pb.finish();
+        }
+        // Every file bar is finished by now, so the drawer is on its last frame
+        if let Some(drawer) = drawer {
+            drawer.join().expect("progress bar drawer panicked")?;
+        }
+
+        // Dropping the last sender lets the event writer drain and finish
+        drop(progress);
+        if let Some(handle) = event_writer {
+            handle.join().expect("progress event writer panicked")?;
+        }
+
+        if let Some(manifest_path) = &config.manifest {
+            match manifest {
+                Some(manifest) => {
+                    let written = manifest.finish()?;
+                    info!("Wrote {} manifest records to {:?}", written, manifest_path);
+                }
+                None => info!("Dry run: not writing manifest {:?}", manifest_path),
+            }
+        }
+
+        if let Some(checksum_path) = &config.checksum_manifest {
+            if config.dry_run {
+                info!("Dry run: not writing checksum manifest {:?}", checksum_path);
+            } else {
+                let mut checksums = checksums.into_inner().unwrap();
+                write_checksums(checksum_path, &mut checksums)?;
+                info!("Wrote {} checksums to {:?}", checksums.len(), checksum_path);
+            }
+        }
+
+        let mut summary = summary.into_inner().unwrap();
+        if !config.dry_run {
+            for (target, counts) in summary.targets.iter_mut() {
+                counts.free_space = fs2::available_space(target).ok();
+            }
+        }
+
+        // Only a complete run over the whole source says which target files are extraneous
+        if config.mirror {
+            if stopped || self.shutdown.load(Ordering::Relaxed) {
+                warn!("Not deleting files missing from the source: the run did not finish");
+            } else if config.retry_failed.is_some() || config.source_list.is_some() || over_limit > 0 || !left_for_later.is_empty() {
+                warn!("Not deleting files missing from the source: only some files were copied");
+            } else {
+                let mut keep = destinations.into_inner().unwrap();
+                for target in &targets {
+                    keep.extend(files.iter().filter_map(|file| destination_path(&file.path(), &target.config).ok()));
+                }
+                match &removed {
+                    // An incremental run knows what was removed, so the target isn't walked
+                    Some(removed) => {
+                        keep.extend(unchanged.iter().flat_map(|(_, records)| records).map(|record| record.destination.clone()));
+                        let removed: Vec<PathBuf> = removed.iter().map(|record| record.destination.clone()).collect();
+                        summary.deleted += delete_removed(&removed, &keep, config.dry_run);
+                    }
+                    None => {
+                        let protected: Vec<PathBuf> = [&config.manifest, &config.checksum_manifest, &config.progress_json]
+                            .into_iter()
+                            .flatten()
+                            .cloned()
+                            .collect();
+                        for target in &targets {
+                            summary.deleted += delete_extraneous(&target.config.target, &keep, &protected, config.dry_run)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+}
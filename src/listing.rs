@@ -0,0 +1,75 @@
+use std::fs::DirEntry;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+
+use crate::config::ListFormat;
+use crate::get_file_size;
+use crate::stub::is_stub;
+
+// One row of the --list-only inventory
+#[derive(Debug, Serialize)]
+pub(crate) struct ListEntry {
+    // Relative to the source directory
+    path: PathBuf,
+    size: Option<u64>,
+    stub: bool,
+}
+
+impl ListEntry {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn new(entry: &DirEntry, source_root: &Path, stub_max_size: u64) -> Self {
+        let path = entry.path();
+        ListEntry {
+            path: path.strip_prefix(source_root).map(Path::to_path_buf).unwrap_or(path),
+            size: get_file_size(entry),
+            stub: is_stub(entry, stub_max_size),
+        }
+    }
+}
+
+// Function to write the inventory to `out` in the chosen format
+pub(crate) fn write_listing(entries: &[ListEntry], format: ListFormat, out: &mut dyn Write) -> io::Result<()> {
+    match format {
+        ListFormat::Table => {
+            let size_width = entries.iter()
+                .map(|entry| size_string(entry.size).len())
+                .max()
+                .unwrap_or(0)
+                .max("SIZE".len());
+            writeln!(out, "{:>size_width$}  STUB  PATH", "SIZE")?;
+            for entry in entries {
+                writeln!(out, "{:>size_width$}  {:<4}  {}",
+                    size_string(entry.size), if entry.stub { "yes" } else { "no" }, entry.path.display())?;
+            }
+        }
+        ListFormat::Csv => {
+            writeln!(out, "path,size,stub")?;
+            for entry in entries {
+                writeln!(out, "{},{},{}", csv_field(&entry.path.to_string_lossy()), size_string(entry.size), entry.stub)?;
+            }
+        }
+        ListFormat::Json => {
+            serde_json::to_writer_pretty(&mut *out, entries)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+// Function to format a size, leaving it blank when it could not be read
+fn size_string(size: Option<u64>) -> String {
+    size.map(|size| size.to_string()).unwrap_or_default()
+}
+
+// Function to quote a CSV field when it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -1,233 +1,785 @@
-use std::fs::{self, DirEntry, File};
-use std::io::{self, Read, ErrorKind};
-use std::path::{Path, PathBuf};
-use std::fs::copy;
-use std::thread;
-use std::time::Duration;
-use std::sync::Arc;
-use indicatif::{ProgressBar, ProgressStyle, MultiProgress};
-use tracing::{info, error, warn, Level};
-use tracing_subscriber::FmtSubscriber;
-use clap::Parser;
-use rayon::prelude::*;
+use std::fs::OpenOptions;
+use std::io::{self, IsTerminal};
+#[cfg(feature = "metrics")]
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+use byte_unit::Byte;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use xerox::{is_inside, DEFAULT_COPY_BUFFER_SIZE, DEFAULT_HOOK_CONCURRENCY, DEFAULT_PREFETCH_CONCURRENCY, ArchiveFormat, CaseCollisionPolicy, CompareMode, Config, ConflictPolicy, CopyMethod, CopyOnly, DestTemplate, FileOrder, InvalidNamePolicy, ListFormat, MigrateError, PreservedTime, Migrator, RunStatus, Summary, ProgressMode, RenamePattern, StubSizeFilter, SymlinkPolicy};
+
+// Exit codes, kept stable for scripts
+const EXIT_SKIPPED: i32 = 2;
+const EXIT_FAILED: i32 = 3;
+const EXIT_FATAL: i32 = 4;
+const EXIT_INTERRUPTED: i32 = 130;
+
+const EXIT_CODES: &str = "Exit codes:
+  0    every file was copied or already done
+  2    some files were skipped (e.g. existing, or still being written), none failed
+  3    some files failed, or --verify-existing found missing or differing files
+  4    the run could not start or stopped on an error (bad arguments, source or target)
+  130  interrupted before every file was started";
 
 /// Command-line arguments structure
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
     /// The source directory (box folder)
+    #[arg(short, long, required_unless_present = "config")]
+    source: Option<PathBuf>,
+
+    /// The target directory (one-drive folder). Repeat to copy every file to
+    /// several targets in a single pass over the source; all of them share
+    /// the same settings, such as --on-conflict.
+    #[arg(short, long, required_unless_present_any = ["config", "list_only", "stub_hydrate_only", "probe"])]
+    target: Vec<PathBuf>,
+
+    /// Load settings from a TOML file. Keys are the long flag names
+    /// (`on-conflict = "rename"`), durations are strings such as "30s", and
+    /// flags given on the command line override the file.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Check that every source file already has a matching destination (by
+    /// size, or by hash with --verify) and report what is missing or differs,
+    /// without copying. Exits with 3 if anything doesn't match.
+    #[arg(long, conflicts_with = "list_only")]
+    verify_existing: bool,
+
+    /// Print each file's path, size and stub status, then exit without copying
+    #[arg(long)]
+    list_only: bool,
+
+    /// Download every stub in the source in place, so the cloud client keeps
+    /// it local, then exit without copying or needing a target. Exits with 3
+    /// if any stub could not be fetched.
+    #[arg(long, conflicts_with_all = ["list_only", "verify_existing"])]
+    stub_hydrate_only: bool,
+
+    /// Count the source's stubs and hydrated files, with their total sizes
+    /// where known, then exit without copying or needing a target. Only
+    /// metadata is read, so it's quick and downloads nothing.
+    #[arg(long, conflicts_with_all = ["list_only", "verify_existing", "stub_hydrate_only"])]
+    probe: bool,
+
+    /// Pack the files into one archive at the target path (e.g. --target
+    /// backup.zip) instead of copying them loose, keeping their paths and
+    /// modification times. Files are streamed in, so any size fits. An
+    /// existing archive is replaced only with --on-conflict overwrite.
+    #[arg(long, value_enum, value_name = "FORMAT", conflicts_with_all = ["list_only", "verify_existing", "stub_hydrate_only", "probe", "move_source", "mirror"])]
+    archive: Option<ArchiveFormat>,
+
+    /// Format of the --list-only inventory
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    list_format: ListFormat,
+
+    /// Preview all operations without touching disk
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Only log warnings and errors, and hide progress bars
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log debug output
     #[arg(short, long)]
-    source: PathBuf,
+    verbose: bool,
+
+    /// Hide progress bars but keep INFO logs. Bars are also hidden
+    /// automatically when stderr is not a terminal.
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Also append plain-text logs to this file. The file receives INFO
+    /// (or DEBUG with --verbose) logs even when --quiet limits the terminal.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Delete each source file after its copy has been verified
+    #[arg(long = "move")]
+    move_source: bool,
+
+    /// Read back each copy and compare its SHA-256 with the source's, which a buffered copy hashes as it goes
+    #[arg(long)]
+    verify: bool,
+
+    /// Stop at the first file that fails instead of attempting every file
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// How many times to retry opening a locked stub file, or a copy that hit a transient error
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+
+    /// Initial delay between retries, doubled on each attempt
+    #[arg(long, default_value_t = 2)]
+    retry_delay_secs: u64,
+
+    /// Give up on a file whose stub download or copy takes longer than this
+    /// many seconds, counting it as failed and moving on. Timeouts are not
+    /// retried: --max-retries only covers stubs that are locked when opened,
+    /// and each retry of a locked stub gets the full timeout once it opens.
+    /// A read stuck inside the provider keeps its file open in the background
+    /// until it returns; once 16 are stuck, further files fail at once.
+    #[arg(long, value_name = "SECS")]
+    file_timeout_secs: Option<u64>,
+
+    /// Before copying a file, check that its size and modification time stay
+    /// the same for this many seconds. Files still being written are retried
+    /// after the other files, then skipped with a warning if still changing.
+    #[arg(long, value_name = "SECS")]
+    settle_time_secs: Option<u64>,
+
+    /// Buffer size for downloading stubs and copying files (e.g. 64KiB, 4MiB).
+    /// Larger buffers mean fewer system calls on big files.
+    #[arg(long, value_parser = parse_buffer_size, default_value_t = DEFAULT_COPY_BUFFER_SIZE)]
+    copy_buffer_size: usize,
+
+    /// How file contents are copied. auto tries a reflink (near-instant on
+    /// btrfs, XFS and APFS within one volume), then copy_file_range, then a
+    /// buffered copy; the method used for each file is logged at debug level.
+    /// An explicit method fails files it can't copy.
+    #[arg(long, value_enum, default_value_t = CopyMethod::Auto)]
+    copy_method: CopyMethod,
+
+    /// Copy even when the files to copy look larger than the free space on
+    /// the target, warning instead of stopping. A file too large for the
+    /// space left when its turn comes still fails, without being copied.
+    #[arg(long)]
+    ignore_space: bool,
+
+    /// Always leave this much free on the target (e.g. 5GB). The free-space
+    /// check counts it as needed, and once copying a file would eat into it,
+    /// no new copies start and the remaining files are skipped for space.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Limit the total copy rate (e.g. 50MB/s). The limit is shared by all
+    /// copy threads, so with N threads each file gets roughly 1/N of it;
+    /// raising --copy-threads does not raise the total.
+    #[arg(long, alias = "max-bytes-per-sec", value_name = "RATE", value_parser = parse_rate)]
+    max_rate: Option<u64>,
+
+    /// Hash every file and hard link byte-identical duplicates in the target
+    /// to the first copy instead of copying them again. Linked files share
+    /// their contents, so editing one changes them all. Falls back to copying
+    /// where the target does not support hard links.
+    #[arg(long)]
+    dedup: bool,
+
+    /// The order files are processed in
+    #[arg(long, value_enum, default_value_t = FileOrder::Largest)]
+    order: FileOrder,
+
+    /// Only process the first N files in --order, to try a config out on a
+    /// huge tree (e.g. --order largest --max-files 100 for the 100 largest)
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Maximum number of files processed in parallel (1 runs sequentially).
+    /// Each worker drives at most one progress bar, so this also caps how
+    /// many bars are shown at once. Defaults to the number of CPUs.
+    #[arg(long)]
+    concurrency: Option<usize>,
+
+    /// Threads used to walk the source tree (defaults to --concurrency).
+    /// Traversal is metadata-bound, so it rarely benefits from as many
+    /// threads as copying.
+    #[arg(long)]
+    walk_threads: Option<usize>,
+
+    /// Threads used to copy files (defaults to --concurrency). Raise this
+    /// for slow, high-latency targets without oversubscribing the walk.
+    #[arg(long)]
+    copy_threads: Option<usize>,
+
+    /// Threads copying into each target, each target from its own queue, so a
+    /// slow target falls behind without holding up the others. The copy
+    /// threads then only fetch files and queue them.
+    #[arg(long, value_name = "N", value_parser = parse_worker_count, conflicts_with = "move_source")]
+    workers_per_target: Option<usize>,
+
+    /// Threads that hash copies for --verify and --checksum-manifest, shared
+    /// by all copy threads. Hashing rereads files, so capping it below
+    /// --copy-threads stops it crowding out copies on the same disk. A
+    /// verified file's source and copy are hashed side by side. By default
+    /// each copy thread hashes its own files.
+    #[arg(long, value_name = "N")]
+    hash_threads: Option<usize>,
+
+    /// Files at or below this size are treated as cloud stubs (ignored on
+    /// Windows, where the file attributes identify stubs)
+    #[arg(long, default_value_t = 0)]
+    stub_max_size: u64,
+
+    /// Source timestamps to carry over to copied files, comma-separated:
+    /// mtime, atime and btime (creation time, which can only be set on
+    /// Windows and macOS; elsewhere it is skipped with a warning), or none
+    #[arg(long, value_enum, value_name = "TIMES", value_delimiter = ',', default_value = "mtime")]
+    preserve_times: Vec<PreservedTime>,
+
+    /// Carry over no timestamps; the same as --preserve-times none
+    #[arg(long = "no-preserve-timestamps", action = ArgAction::SetFalse, conflicts_with = "preserve_times")]
+    preserve_timestamps: bool,
+
+    /// Copy files that are genuinely empty, as opposed to stubs. Where stubs are
+    /// detected by size, an empty file is only known to be empty once fetched.
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    copy_empty_files: bool,
+
+    /// Run this program after each file is copied, with the source and
+    /// destination paths as its two arguments (also set as XEROX_SOURCE and
+    /// XEROX_DESTINATION). It is run directly, not through a shell, so wrap
+    /// anything more involved in a script. A failure is logged as a warning
+    /// unless --hook-fatal is given.
+    #[arg(long, value_name = "CMD")]
+    post_copy_command: Option<PathBuf>,
+
+    /// Count a file as failed when its post-copy command exits unsuccessfully.
+    /// The command runs before --move removes the source, so a failed file
+    /// keeps its source.
+    #[arg(long, requires = "post_copy_command")]
+    hook_fatal: bool,
+
+    /// Most post-copy commands running at once
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_HOOK_CONCURRENCY)]
+    hook_concurrency: usize,
+
+    /// Copy POSIX permission bits to copied files, and ownership when run as
+    /// root (no effect on Windows)
+    #[arg(long)]
+    preserve_permissions: bool,
+
+    /// Copy extended attributes such as Finder tags and quarantine flags
+    /// (macOS and Linux). Windows alternate data streams are not copied.
+    #[arg(long)]
+    preserve_xattrs: bool,
+
+    /// Skip files and directories matching this glob (repeatable). Patterns
+    /// are matched against the path relative to the source and the file name.
+    /// Rules in `.xeroxignore` files (gitignore syntax, read from the source
+    /// and every directory below it) apply too; a `!` rule there can't bring
+    /// back anything excluded here.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Never descend into directories matching this glob (repeatable), e.g.
+    /// `node_modules` or `.git`. Matched against the directory name and its
+    /// path relative to the source.
+    #[arg(long, value_name = "GLOB")]
+    exclude_dir: Vec<String>,
+
+    /// Skip hidden files and directories: names starting with `.`, or with
+    /// the hidden attribute on Windows
+    #[arg(long)]
+    skip_hidden: bool,
+
+    /// Only copy files at most this many directories deep; 0 copies just the
+    /// files directly in the source
+    #[arg(long, value_name = "N")]
+    max_depth: Option<usize>,
+
+    /// Copy hidden files and directories, overriding `skip-hidden` in a
+    /// config file
+    #[arg(long, conflicts_with = "skip_hidden")]
+    include_hidden: bool,
+
+    /// Only create directories that end up holding at least one copied
+    /// file. By default every directory walked is created, even when it is
+    /// empty or all of its files were filtered out.
+    #[arg(long)]
+    prune_empty_dirs: bool,
+
+    /// Create every directory walked, even empty ones, overriding
+    /// `prune-empty-dirs` in a config file
+    #[arg(long, conflicts_with = "prune_empty_dirs")]
+    preserve_empty_dirs: bool,
+
+    /// Only copy files matching this glob (repeatable). Excludes take
+    /// precedence over includes.
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Write a JSONL record of every file processed (source, destination,
+    /// size, hash when verified, outcome, timestamp) to this path
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<PathBuf>,
+
+    /// Flush the manifest to disk (with fsync) every N records, so a run
+    /// that dies part way leaves recent records to --resume from. Records
+    /// since the last checkpoint may be lost.
+    #[arg(long, value_name = "N", requires = "manifest")]
+    checkpoint_files: Option<usize>,
+
+    /// Flush the manifest to disk (with fsync) when a record is added this
+    /// many seconds after the last flush
+    #[arg(long, value_name = "SECS", requires = "manifest")]
+    checkpoint_secs: Option<u64>,
+
+    /// Write a sha256sum-compatible listing of every copied file to this
+    /// path, relative to the target, so the copy can later be checked with
+    /// `sha256sum -c` from the target directory
+    #[arg(long, value_name = "PATH")]
+    checksum_manifest: Option<PathBuf>,
+
+    /// Skip files that a previous run's manifest records as copied, unless
+    /// their size or modification time has since changed
+    #[arg(long, value_name = "MANIFEST")]
+    resume: Option<PathBuf>,
+
+    /// Carry on large copies that an interrupted run left part-written
+    /// (*.xerox.part) instead of starting them over. Each partial is read back
+    /// and compared with the start of its source first; only use this if the
+    /// sources haven't been edited in place since.
+    #[arg(long)]
+    resume_partial: bool,
+
+    /// Copy only the files that a previous run's manifest records as failed,
+    /// without walking the rest of the source. Filters are not applied again.
+    #[arg(long, value_name = "MANIFEST")]
+    retry_failed: Option<PathBuf>,
+
+    /// Copy only the files listed in this file, one path relative to the
+    /// source per line, without walking the source. Filters are not applied.
+    #[arg(long, value_name = "FILE", conflicts_with = "retry_failed")]
+    source_list: Option<PathBuf>,
+
+    /// Sync incrementally from a previous run's manifest: copy only files
+    /// added or changed (by size or modification time) since, and with
+    /// --mirror delete the copies of files removed since. Changed files
+    /// replace their earlier copies, so the default --on-conflict skip acts
+    /// as overwrite-if-newer. Pair with --manifest to record this run for the next.
+    #[arg(long, value_name = "MANIFEST", conflicts_with_all = ["resume", "retry_failed", "source_list"])]
+    since_manifest: Option<PathBuf>,
+
+    /// After copying, delete files in the target that no source file maps
+    /// to, making the target an exact mirror. Files excluded by filters count
+    /// as missing. Nothing is deleted if the run stops early.
+    #[arg(long, visible_alias = "delete", conflicts_with_all = ["retry_failed", "source_list"])]
+    mirror: bool,
+
+    /// Before copying anything, list source files that would share a
+    /// destination (e.g. with --flatten, or names differing only in case on
+    /// a case-insensitive target) and stop if there are any
+    #[arg(long)]
+    check_conflicts: bool,
+
+    /// What to do when a destination file already exists
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+    on_conflict: ConflictPolicy,
+
+    /// How --on-conflict overwrite-if-newer tells whether a destination is up to
+    /// date: size (fastest), size+mtime (re-running a sync) or hash (slow, but
+    /// catches same-size edits and destinations whose times were not preserved)
+    #[arg(long, value_enum, default_value_t = CompareMode::SizeMtime)]
+    compare: CompareMode,
+
+    /// What to do when a file's name differs only in case from one already in
+    /// a case-insensitive target (e.g. Report.pdf and report.pdf on OneDrive)
+    #[arg(long, value_enum, default_value_t = CaseCollisionPolicy::Error)]
+    on_case_collision: CaseCollisionPolicy,
+
+    /// What to do with files whose names Windows filesystems, and so OneDrive,
+    /// refuse: reserved names like CON or aux, characters like : or ?, or a
+    /// trailing dot or space. Sanitizing replaces the bad characters with _
+    /// and logs each new name.
+    #[arg(long, value_enum, default_value_t = InvalidNamePolicy::Warn)]
+    on_invalid_name: InvalidNamePolicy,
+
+    /// Create only the directory skeleton (dirs), so the structure is there
+    /// to see before anything is copied; only the files and the directories
+    /// holding them (files); or both
+    #[arg(long, value_enum, default_value_t = CopyOnly::Both, conflicts_with = "archive")]
+    only: CopyOnly,
+
+    /// How renamed files are named when their destination is taken, e.g.
+    /// `{stem}_dup{n}{ext}`. Tokens: {stem}, {ext} (with the dot), {n}, counting
+    /// up from 1 until a name is free, and {timestamp} of the copy. Must
+    /// contain {n}.
+    #[arg(long, value_name = "PATTERN", default_value_t = RenamePattern::default())]
+    rename_pattern: RenamePattern,
+
+    /// Skip files smaller than this size (e.g. 100MB, 2GiB)
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this size (e.g. 100MB, 2GiB)
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only copy files modified at or after this time: a date (2024-01-01),
+    /// an RFC 3339 time (2024-01-01T12:00:00Z), or an age such as 7d or 12h.
+    /// Dates without a zone are UTC. Stub times may be placeholders.
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    newer_than: Option<SystemTime>,
+
+    /// Only copy files modified before this time (same formats as --newer-than)
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    older_than: Option<SystemTime>,
+
+    /// How size limits treat stubs whose cloud size the OS does not report
+    #[arg(long, value_enum, default_value_t = StubSizeFilter::Reported)]
+    filter_stubs_by: StubSizeFilter,
+
+    /// Show a bar per stub being fetched, or one bar for the whole run
+    #[arg(long, value_enum, default_value_t = ProgressMode::PerFile)]
+    progress: ProgressMode,
+
+    /// Copy every file directly into the target root instead of recreating
+    /// the source folders. Files with the same name get free names, as set
+    /// by --rename-pattern.
+    #[arg(long)]
+    flatten: bool,
+
+    /// Place files with this extension in a target subfolder instead of
+    /// mirroring the source tree, e.g. `--route mp4=video` (repeatable). Routed
+    /// files from different folders can share a name: only the first is copied
+    /// unless combined with --on-conflict rename, and --check-conflicts lists
+    /// them up front.
+    #[arg(long = "route", value_name = "EXT=SUBDIR", value_parser = parse_route)]
+    routes: Vec<(String, PathBuf)>,
+
+    /// Place each file where this template says below the target, instead of
+    /// mirroring the source tree, e.g. `{year}/{month}/{basename}`. Tokens:
+    /// {relpath}, {dir}, {basename}, {stem}, {ext}, and {year} and {month} of
+    /// the modification time. Files can end up sharing a name: only the first
+    /// is copied unless combined with --on-conflict rename, and
+    /// --check-conflicts lists them up front.
+    #[arg(long, value_name = "TEMPLATE", conflicts_with_all = ["flatten", "routes"])]
+    dest_template: Option<DestTemplate>,
+
+    /// With --flatten, keep the source path in the file name by joining its
+    /// folders with this separator (e.g. "__" gives subdir__file.pdf)
+    #[arg(long, value_name = "SEP")]
+    flatten_separator: Option<String>,
+
+    /// Download all stubs in a first phase, many at once, before copying
+    /// anything, so network latency overlaps instead of stalling the copies
+    #[arg(long)]
+    prefetch: bool,
+
+    /// Number of stubs downloaded in parallel during --prefetch
+    #[arg(long, default_value_t = DEFAULT_PREFETCH_CONCURRENCY)]
+    prefetch_concurrency: usize,
+
+    /// Write newline-delimited JSON progress events (started, progress,
+    /// completed, error) to PATH, or to stdout when no path is given. Logs
+    /// always go to stderr.
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-")]
+    progress_json: Option<PathBuf>,
+
+    /// Every this many seconds, log files and bytes done, throughput over the
+    /// last interval and an ETA. Useful when logs are piped to a file.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    report_interval_secs: Option<u64>,
+
+    /// Serve files_copied_total, bytes_copied_total, failures_total and
+    /// retries_total for Prometheus at http://ADDR/metrics while copying,
+    /// e.g. 127.0.0.1:9090
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Testing only: treat files as cloud stubs that take this many
+    /// milliseconds to open, to exercise fetching without a Box mount
+    #[cfg(feature = "simulate-stubs")]
+    #[arg(long, hide = true, value_name = "MS")]
+    simulate_stub_latency: Option<u64>,
+
+    /// Testing only: how many times each simulated stub fails to open with
+    /// WouldBlock before it succeeds
+    #[cfg(feature = "simulate-stubs")]
+    #[arg(long, hide = true, default_value_t = 0, requires = "simulate_stub_latency")]
+    simulate_lock_failures: u32,
+
+    /// Testing only: file name globs picking the simulated stubs (every file
+    /// when not given)
+    #[cfg(feature = "simulate-stubs")]
+    #[arg(long, hide = true, value_name = "GLOB", requires = "simulate_stub_latency")]
+    simulate_stub: Vec<String>,
+
+    /// How to handle symbolic links in the source
+    #[arg(long, value_enum, default_value_t = SymlinkPolicy::Follow)]
+    symlinks: SymlinkPolicy,
+}
 
-    /// The target directory (one-drive folder)
-    #[arg(short, long)]
-    target: PathBuf,
+// Function to parse human-readable sizes such as "100MB" or "2GiB" into bytes
+fn parse_size(s: &str) -> Result<u64, String> {
+    Byte::parse_str(s, true)
+        .map(|byte| byte.as_u64())
+        .map_err(|e| e.to_string())
 }
 
-// Function to get file size
-fn get_file_size(entry: &DirEntry) -> Option<u64> {
-    entry.metadata().ok().map(|metadata| metadata.len())
+// Function to parse an absolute date or time, or an age like "7d" counted back from now
+fn parse_time(s: &str) -> Result<SystemTime, String> {
+    if let Ok(age) = humantime::parse_duration(s) {
+        return SystemTime::now().checked_sub(age).ok_or_else(|| format!("{} is too far in the past", s));
+    }
+
+    // A bare date means midnight UTC
+    let timestamp = if s.len() == 10 { format!("{}T00:00:00Z", s) } else { s.to_string() };
+    humantime::parse_rfc3339_weak(&timestamp)
+        .map_err(|_| format!("expected a date (2024-01-01), a time (2024-01-01T12:00:00Z) or an age (7d), got {:?}", s))
 }
 
-// Function to create directory structure in the target (one-drive) location
-fn create_target_directory_structure(source: &Path, target: &Path, source_root: &Path) -> io::Result<PathBuf> {
-    let relative_path = source.strip_prefix(source_root).unwrap_or(source).parent().unwrap_or(Path::new(""));
-    let target_path = target.join(relative_path);
-    
-    if !target_path.exists() {
-        fs::create_dir_all(&target_path)?;  // Create directories as needed
+// Function to parse a --route of the form EXT=SUBDIR
+fn parse_route(s: &str) -> Result<(String, PathBuf), String> {
+    let (extension, subdir) = s.split_once('=').ok_or("expected EXT=SUBDIR")?;
+    let extension = extension.trim_start_matches('.');
+    let subdir = PathBuf::from(subdir);
+    if extension.is_empty() || subdir.as_os_str().is_empty() {
+        return Err("expected EXT=SUBDIR".to_string());
     }
+    if !is_inside(&subdir) {
+        return Err(format!("{:?} must be a relative path inside the target", subdir));
+    }
+    Ok((extension.to_string(), subdir))
+}
 
-    Ok(target_path)
+// Function to parse a rate such as "50MB/s" (the "/s" is optional) into bytes per second
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match parse_size(s.strip_suffix("/s").unwrap_or(s))? {
+        0 => Err("rate must be greater than zero".to_string()),
+        rate => Ok(rate),
+    }
 }
 
-// Function to fetch the file with retries to handle file locks during download
-fn fetch_file_with_progress(entry: &DirEntry, multi_progress: Arc<MultiProgress>) -> io::Result<()> {
-    let path = entry.path();
-    let file_size = get_file_size(entry).unwrap_or(0);
-
-    // Check if the file is a stub that needs to be downloaded
-    if file_size == 0 {
-        info!("Fetching stub file: {:?}", path);
-
-        // Create a progress bar
-        let pb = multi_progress.add(ProgressBar::new(file_size));
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{wide_bar} {bytes}/{total_bytes} ({eta})")
-            .progress_chars("##-"));
-
-        // Retry loop to handle file locks
-        let mut retries = 0;
-        loop {
-            match File::open(&path) {
-                Ok(mut file) => {
-                    let mut buffer = vec![0; 8192]; // Read in 8KB chunks
-                    let mut total_read = 0;
-
-                    // Read the file in chunks to show download progress
-                    while let Ok(bytes_read) = file.read(&mut buffer) {
-                        if bytes_read == 0 { break; } // End of file
-                        total_read += bytes_read as u64;
-                        pb.set_position(total_read);
-                    }
-
-                    pb.finish_with_message("Download complete");
-                    return Ok(());
-                }
-                Err(e) => {
-                    if e.kind() == ErrorKind::PermissionDenied || e.kind() == ErrorKind::WouldBlock {
-                        // The file might still be locked due to ongoing download, so retry
-                        if retries >= 5 {
-                            error!("Failed to fetch file after multiple retries: {:?}", path);
-                            return Err(io::Error::new(io::ErrorKind::Other, "File lock timeout"));
-                        }
-                        retries += 1;
-                        warn!("File locked, retrying... (attempt {})", retries);
-                        thread::sleep(Duration::from_secs(2)); // Wait before retrying
-                    } else {
-                        error!("Error opening file: {:?}", e);
-                        return Err(e); // Propagate other errors
-                    }
-                }
-            }
-        }
-    } else {
-        // File is not a stub, no need to fetch
-        Ok(())
+// Function to parse a buffer size, which must be at least one byte
+fn parse_buffer_size(s: &str) -> Result<usize, String> {
+    match usize::try_from(parse_size(s)?) {
+        Ok(0) => Err("buffer size must be greater than zero".to_string()),
+        Ok(size) => Ok(size),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-// Function to move file to the one-drive directory, preserving folder structure
-fn move_file(entry: &DirEntry, target_root: &Path, source_root: &Path) -> io::Result<()> {
-    let source_path = entry.path();
-
-    // Create the target directory structure
-    let target_dir = create_target_directory_structure(&source_path, &target_root, source_root)?;
-    // Print the target directory path
-    println!("Target directory: {:?}", target_dir);
-
-    let target_path = target_dir.join(entry.file_name());
-
-    info!("Moving file from {:?} to {:?}", source_path, target_path);
-
-    // Check if the target file already exists
-    if target_path.exists() {
-        warn!("{:?} exists", entry.file_name());
-        Ok(())
-    } else {
-        // Move the file
-        match copy(source_path, target_path) {
-            Ok(_) => {
-                info!("Successfully moved file: {:?}", entry.file_name());
-                Ok(())
-            }
-            Err(e) => {
-                error!("Failed to move file {:?}: {:?}", entry.file_name(), e);
-                Err(e)
-            }
-        }
+// Function to parse a thread count, which must be at least one
+fn parse_worker_count(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(e) => Err(e.to_string()),
     }
 }
 
-// Function to visit directories recursively and collect files and directories
-fn visit_dirs(dir: &Path) -> io::Result<(Vec<DirEntry>, Vec<PathBuf>)> {
-    let mut files = vec![];
-    let mut dirs = vec![];
-
-    if dir.is_dir() {
-        let entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
-        
-        let (sub_files, sub_dirs): (Vec<_>, Vec<_>) = entries.into_par_iter()
-            .filter_map(|entry| {
-                let path = entry.path();
-                if path.is_file() {
-                    Some((Some(entry), None))
-                } else if path.is_dir() {
-                    let (sub_files, sub_dirs) = visit_dirs(&path).ok()?;
-                    Some((None, Some((path, sub_files, sub_dirs))))
-                } else {
-                    None
-                }
-            })
-            .unzip();
-
-        files.extend(sub_files.into_iter().flatten());
-        for (dir, sub_files, sub_dirs) in sub_dirs.into_iter().flatten() {
-            dirs.push(dir);
-            files.extend(sub_files);
-            dirs.extend(sub_dirs);
+// Function to build the run's config from the --config file (or the defaults), letting
+// every flag actually given on the command line take precedence
+fn build_config(args: Args, matches: &ArgMatches) -> Result<Config, MigrateError> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    // Flags whose config field has the same name and type
+    macro_rules! override_from_args {
+        ($($field:ident),* $(,)?) => {
+            $(if given(stringify!($field)) { config.$field = args.$field; })*
+        };
+    }
+    override_from_args!(
+        include, exclude, exclude_dir, skip_hidden, max_depth, prune_empty_dirs, concurrency, walk_threads, copy_threads, workers_per_target, hash_threads, dry_run, move_source, verify,
+        preserve_times, fail_fast, stub_max_size, manifest, checkpoint_files, resume, resume_partial, retry_failed, source_list, since_manifest, mirror, order, max_files, dedup, on_conflict, compare, min_size,
+        max_size, newer_than, older_than, on_case_collision, on_invalid_name, only, rename_pattern, progress, symlinks, preserve_permissions, preserve_xattrs, flatten,
+        flatten_separator, dest_template, prefetch, prefetch_concurrency, copy_buffer_size, copy_method, ignore_space, min_free_space,
+        progress_json, checksum_manifest, check_conflicts, max_rate,
+        list_only, stub_hydrate_only, probe, archive, list_format, copy_empty_files, verify_existing, post_copy_command, hook_fatal, hook_concurrency,
+    );
+
+    if let Some(source) = args.source {
+        config.source = source;
+    }
+    // Targets on the command line replace all of the config file's
+    let mut targets = args.target.into_iter();
+    if let Some(target) = targets.next() {
+        config.target = target;
+        config.extra_targets = targets.collect();
+    }
+    if args.include_hidden {
+        config.skip_hidden = false;
+    }
+    if args.preserve_empty_dirs {
+        config.prune_empty_dirs = false;
+    }
+    // `none` stands for an empty list, which is what the copy checks for
+    if !args.preserve_timestamps || config.preserve_times.contains(&PreservedTime::None) {
+        config.preserve_times.clear();
+    }
+    if given("routes") {
+        config.routes = args.routes.into_iter().collect();
+    }
+    if given("max_retries") {
+        config.retry.max_retries = args.max_retries;
+    }
+    if given("retry_delay_secs") {
+        config.retry.base_delay = Duration::from_secs(args.retry_delay_secs);
+    }
+    if given("filter_stubs_by") {
+        config.stub_size_filter = args.filter_stubs_by;
+    }
+    // Bars drawn into a pipe or log file leave carriage-return garbage behind
+    if args.quiet || args.no_progress || !io::stderr().is_terminal() {
+        config.show_progress = false;
+    }
+    if let Some(secs) = args.file_timeout_secs {
+        config.file_timeout = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.settle_time_secs {
+        config.settle_time = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.report_interval_secs {
+        config.report_interval = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = args.checkpoint_secs {
+        config.checkpoint_interval = Some(Duration::from_secs(secs));
+    }
+    #[cfg(feature = "metrics")]
+    if given("metrics_addr") {
+        config.metrics_addr = args.metrics_addr;
+    }
+    #[cfg(feature = "simulate-stubs")]
+    if let Some(ms) = args.simulate_stub_latency {
+        config.simulate_stubs = Some(xerox::SimulatedStubs {
+            latency: Duration::from_millis(ms),
+            lock_failures: args.simulate_lock_failures,
+            files: args.simulate_stub,
+        });
+    }
+
+    // Without --source/--target, the config file must provide them
+    if let Some(path) = &args.config {
+        for (key, value) in [("source", &config.source), ("target", &config.target)] {
+            // Listing and hydrating never write, so they have no use for a target
+            if value.as_os_str().is_empty() && !(key == "target" && (config.list_only || config.stub_hydrate_only || config.probe)) {
+                let message = format!("no {} given; set `{}` in the file or pass --{}", key, key, key);
+                return Err(MigrateError::InvalidConfig { path: path.clone(), message });
+            }
         }
-    } else {
-        error!("{} is not a dir", dir.display());
     }
 
-    // Remove duplicates
-    files.par_sort_by_key(|a| a.path());
-    files.dedup_by(|a, b| a.path() == b.path());
+    Ok(config)
+}
+
+// Function to choose the process exit code for a finished run
+fn exit_code(status: RunStatus) -> i32 {
+    match status {
+        RunStatus::Complete => 0,
+        RunStatus::Skipped => EXIT_SKIPPED,
+        RunStatus::Failed => EXIT_FAILED,
+        RunStatus::Interrupted => EXIT_INTERRUPTED,
+    }
+}
 
-    dirs.par_sort_unstable();
-    dirs.dedup();
+// Function to initialize logging to stderr and, optionally, a log file
+fn init_logging(args: &Args) -> io::Result<()> {
+    let level = if args.verbose { Level::DEBUG } else { Level::INFO };
+    let stderr_level = if args.quiet { Level::WARN } else { level };
+
+    let stderr_layer = fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_filter(LevelFilter::from_level(stderr_level));
+
+    // The file gets no ANSI colors so it stays greppable
+    let file_layer = match &args.log_file {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Some(fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .with_filter(LevelFilter::from_level(level)))
+        }
+        None => None,
+    };
 
-    // Sort files by size (largest first)
-    files.par_sort_by(|a, b| get_file_size(b).cmp(&get_file_size(a)));
+    tracing_subscriber::registry()
+        .with(stderr_layer)
+        .with(file_layer)
+        .init();
 
-    Ok((files, dirs))
+    Ok(())
 }
 
-fn main() -> io::Result<()> {
-    // Initialize the tracing subscriber for logging
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .finish();
+fn main() {
+    // Parse command-line arguments, keeping the matches to tell typed flags from defaults
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.preserve_times.len() > 1 && args.preserve_times.contains(&PreservedTime::None) {
+        Args::command().error(ErrorKind::ArgumentConflict, "--preserve-times none can't be combined with other timestamps").exit();
+    }
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Setting default subscriber failed");
+    // Initialize the tracing subscriber for logging
+    if let Err(e) = init_logging(&args) {
+        eprintln!("Failed to open log file: {}", e);
+        process::exit(EXIT_FATAL);
+    }
 
-    // Parse command-line arguments
-    let args = Args::parse();
+    let result = build_config(args, &matches).and_then(Migrator::new).and_then(|migrator| {
+        // The first Ctrl-C lets in-flight files finish; a second one quits at once
+        let shutdown = migrator.shutdown_flag();
+        let handler = ctrlc::set_handler(move || {
+            if shutdown.swap(true, Ordering::SeqCst) {
+                process::exit(EXIT_INTERRUPTED);
+            }
+            warn!("Interrupted: finishing files in flight (press Ctrl-C again to quit now)");
+        });
+        if let Err(e) = handler {
+            warn!("Failed to install Ctrl-C handler: {}", e);
+        }
 
-    let box_dir = args.source;
-    let one_drive_dir = args.target;
-    info!("Copying from {} to {}", box_dir.display(), one_drive_dir.display());
+        if migrator.config().list_only {
+            let count = migrator.list(&mut io::stdout().lock())?;
+            info!("Listed {} files", count);
+            return Ok(Summary::default());
+        }
 
-    // Get the files and directories from the box directory recursively
-    let (files, dirs) = match visit_dirs(&box_dir) {
-        Ok(result) => result,
-        Err(e) => {
-            error!("Failed to read box directory: {:?}", e);
-            return Err(e);
+        if migrator.config().stub_hydrate_only {
+            let hydration = migrator.hydrate()?;
+            hydration.report();
+            process::exit(exit_code(hydration.status()));
         }
-    };
 
-    // Create all directories in the target location
-    dirs.par_iter().try_for_each(|dir| {
-        let target_dir = create_target_directory_structure(&dir, &one_drive_dir, &box_dir)?;
-        info!("Created directory: {:?}", target_dir);
-        Ok::<(), io::Error>(())
-    })?;
-
-    // Create a MultiProgress instance
-    let multi_progress = Arc::new(MultiProgress::new());
-
-    // Iterate through the files, sorted by size
-    files.par_iter()
-        .map(|file| {
-            let multi_progress = Arc::clone(&multi_progress);
-            // Fetch the file with progress (this will trigger download if it's a stub)
-            if let Err(e) = fetch_file_with_progress(&file, multi_progress) {
-                error!("Failed to fetch file: {:?}", e);
-                return Err(e);
+        if migrator.config().probe {
+            let probe = migrator.probe()?;
+            probe.report();
+            if probe.stubs > 0 && !migrator.config().prefetch {
+                info!("Run with --prefetch to download the stubs before copying");
             }
+            return Ok(Summary::default());
+        }
 
-            // Move the file to the one-drive directory, preserving folder structure
-            if let Err(e) = move_file(&file, &one_drive_dir, &box_dir) {
-                error!("Failed to move file: {:?}", e);
-                return Err(e);
+        if migrator.config().verify_existing {
+            let audit = migrator.verify_existing()?;
+            audit.report();
+            if !audit.is_clean() {
+                process::exit(EXIT_FAILED);
             }
+            return Ok(Summary::default());
+        }
 
-            Ok(())
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+        let summary = match migrator.config().archive {
+            Some(format) => migrator.archive(format)?,
+            None => migrator.run()?,
+        };
+        summary.report(migrator.config().dry_run);
+        Ok(summary)
+    });
 
-    Ok(())
-}
\ No newline at end of file
+    match result {
+        Ok(summary) => process::exit(exit_code(summary.status())),
+        Err(e) => {
+            error!("{}", e);
+            process::exit(EXIT_FATAL);
+        }
+    }
+}
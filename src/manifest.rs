@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fs::{DirEntry, File, Metadata};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// What happened to a file during a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Copied,
+    Skipped,
+    Failed,
+}
+
+/// One line of the JSONL manifest, describing a single file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestRecord {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub size: u64,
+    /// RFC 3339 modification time of the source when it was processed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_modified: Option<String>,
+    /// Hex SHA-256 of the copied file, present when the copy was verified or checksummed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Hex SHA-256 of the source, taken while copying it, whether or not the copy
+    /// was verified. Missing when a reflink or copy_file_range copied the data
+    /// without it passing through and the copy wasn't verified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_sha256: Option<String>,
+    pub outcome: Outcome,
+    /// How many times opening a locked stub was retried before it could be fetched
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// RFC 3339 time at which the file finished processing
+    pub timestamp: String,
+}
+
+impl ManifestRecord {
+    pub(crate) fn new(entry: &DirEntry, destination: PathBuf, sha256: Option<String>, outcome: Outcome) -> Self {
+        let metadata = entry.metadata().ok();
+        ManifestRecord {
+            source: entry.path(),
+            destination,
+            size: metadata.as_ref().map_or(0, |metadata| metadata.len()),
+            source_modified: metadata.as_ref().and_then(modified_string),
+            sha256,
+            source_sha256: None,
+            outcome,
+            retries: 0,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+
+    // Function to check whether the source is unchanged since this record was written
+    pub(crate) fn matches_source(&self, entry: &DirEntry) -> bool {
+        match entry.metadata() {
+            Ok(metadata) => metadata.len() == self.size
+                && self.source_modified.is_some()
+                && modified_string(&metadata) == self.source_modified,
+            Err(_) => false,
+        }
+    }
+}
+
+// Function to leave the retry count out of records for files that needed none
+fn is_zero(retries: &u32) -> bool {
+    *retries == 0
+}
+
+// Function to format a file's modification time the way the manifest stores it
+fn modified_string(metadata: &Metadata) -> Option<String> {
+    metadata.modified().ok().map(|modified| humantime::format_rfc3339_nanos(modified).to_string())
+}
+
+// Function to read a JSONL manifest written by a previous run. A run that was killed
+// can leave its last record cut off, so a bad last line is skipped with a warning;
+// one anywhere else means the manifest is corrupt.
+pub(crate) fn read_manifest(path: &Path) -> io::Result<Vec<ManifestRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut bad_line = None;
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() { continue; }
+        if let Some((number, e)) = bad_line.take() {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("line {} of {:?}: {}", number, path, e)));
+        }
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => bad_line = Some((number + 1, e)),
+        }
+    }
+    if let Some((number, e)) = bad_line {
+        warn!("Ignoring line {} of {:?}, which looks cut off: {}", number, path, e);
+    }
+    Ok(records)
+}
+
+// Function to index the files a previous run finished, keyed by source path with a
+// record for each target it reached. When `require_hash` is set only verified copies
+// count as finished.
+pub(crate) fn completed_records(records: Vec<ManifestRecord>, require_hash: bool) -> HashMap<PathBuf, Vec<ManifestRecord>> {
+    let mut completed: HashMap<PathBuf, Vec<ManifestRecord>> = HashMap::new();
+    for record in records {
+        if record.outcome == Outcome::Copied && (!require_hash || record.sha256.is_some()) {
+            completed.entry(record.source.clone()).or_default().push(record);
+        }
+    }
+    completed
+}
+
+// Function to list the source files a previous run failed to copy
+pub(crate) fn failed_sources(records: Vec<ManifestRecord>) -> Vec<PathBuf> {
+    records.into_iter()
+        .filter(|record| record.outcome == Outcome::Failed)
+        .map(|record| record.source)
+        .collect()
+}
+
+// Writes the manifest one JSON record per line as files finish. Each record is flushed
+// to the file as soon as it's written, so a killed run loses at most a cut-off last
+// line. Records are fsynced at each checkpoint (every `checkpoint_files` records or
+// once `checkpoint_interval` has passed, whichever comes first) and when the run ends,
+// so checkpointed records also survive a crash or power loss; later ones may not.
+pub(crate) struct ManifestWriter {
+    state: Mutex<WriterState>,
+    checkpoint_files: Option<usize>,
+    checkpoint_interval: Option<Duration>,
+}
+
+struct WriterState {
+    writer: BufWriter<File>,
+    written: usize,
+    since_checkpoint: usize,
+    last_checkpoint: Instant,
+}
+
+impl WriterState {
+    // Function to get everything written so far onto the disk
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.since_checkpoint = 0;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+}
+
+impl ManifestWriter {
+    // Function to start a new manifest at `path`, replacing any existing file
+    pub(crate) fn create(path: &Path, checkpoint_files: Option<usize>, checkpoint_interval: Option<Duration>) -> io::Result<Self> {
+        Ok(ManifestWriter {
+            state: Mutex::new(WriterState {
+                writer: BufWriter::new(File::create(path)?),
+                written: 0,
+                since_checkpoint: 0,
+                last_checkpoint: Instant::now(),
+            }),
+            checkpoint_files,
+            checkpoint_interval,
+        })
+    }
+
+    // Function to add a record, checkpointing if one is due
+    pub(crate) fn append(&self, record: &ManifestRecord) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        serde_json::to_writer(&mut state.writer, record)?;
+        state.writer.write_all(b"\n")?;
+        state.writer.flush()?;
+        state.written += 1;
+        state.since_checkpoint += 1;
+
+        let due = self.checkpoint_files.is_some_and(|files| state.since_checkpoint >= files)
+            || self.checkpoint_interval.is_some_and(|interval| state.last_checkpoint.elapsed() >= interval);
+        if due {
+            state.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    // Function to write out the remaining records, returning how many were written in all
+    pub(crate) fn finish(self) -> io::Result<usize> {
+        let mut state = self.state.into_inner().unwrap();
+        state.checkpoint()?;
+        Ok(state.written)
+    }
+}
+
+// Function to write a `sha256sum -c` compatible listing of copied files, sorted by path.
+// Paths are relative to the target root with `/` separators; as in sha256sum, a line
+// whose path contains a backslash or newline is prefixed with `\` and escaped.
+pub(crate) fn write_checksums(path: &Path, checksums: &mut [(PathBuf, String)]) -> io::Result<()> {
+    checksums.sort();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (relative_path, sha256) in checksums.iter() {
+        let name = relative_path.iter().map(|component| component.to_string_lossy()).collect::<Vec<_>>().join("/");
+        if name.contains(['\\', '\n']) {
+            let escaped = name.replace('\\', "\\\\").replace('\n', "\\n");
+            writeln!(writer, "\\{}  {}", sha256, escaped)?;
+        } else {
+            writeln!(writer, "{}  {}", sha256, name)?;
+        }
+    }
+    writer.flush()
+}
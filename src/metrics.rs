@@ -0,0 +1,105 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Request, Response, Server};
+use tracing::{info, warn};
+
+use crate::progress::Counters;
+
+// Function to write the counters in the Prometheus text exposition format
+fn render(counters: &Counters) -> String {
+    let metrics = [
+        ("files_copied_total", "counter", "Files copied to a target", counters.files_copied.load(Ordering::Relaxed) as u64),
+        ("bytes_copied_total", "counter", "Bytes written to targets, leaving out hard-linked duplicates", counters.bytes_copied.load(Ordering::Relaxed)),
+        ("bytes_written", "gauge", "Bytes in the targets' files so far, counting those still being copied", counters.bytes_written.get()),
+        ("bytes_cloned", "gauge", "Bytes the targets share with the source by reflinks, which write none of them", counters.bytes_written.cloned()),
+        ("failures_total", "counter", "Files that failed to copy to a target", counters.failures.load(Ordering::Relaxed) as u64),
+        ("retries_total", "counter", "Retried attempts to fetch a stub", counters.retries.load(Ordering::Relaxed)),
+        ("files_done", "gauge", "Files finished so far, whatever their outcome", counters.files_done.load(Ordering::Relaxed) as u64),
+        ("files_total", "gauge", "Files selected for this run", counters.total_files as u64),
+        ("bytes_total", "gauge", "Estimated bytes to copy, growing as stubs turn out larger", counters.total_bytes.load(Ordering::Relaxed)),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    }
+    text
+}
+
+// Function to answer one scrape
+fn respond(request: Request, counters: &Counters) -> io::Result<()> {
+    let response = match request.url().split('?').next() {
+        Some("/metrics" | "/") => Response::from_string(render(counters)),
+        _ => Response::from_string("Not found; metrics are at /metrics\n").with_status_code(404),
+    };
+    let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+    request.respond(response.with_header(content_type))
+}
+
+// A background thread serving the run's counters over HTTP for Prometheus to scrape, until
+// stopped. Connections are read on tiny_http's own threads, so a slow scraper holds up no other.
+pub(crate) struct MetricsServer {
+    server: Arc<Server>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub(crate) fn start(listener: TcpListener, counters: Arc<Counters>) -> io::Result<Self> {
+        info!("Serving metrics at http://{}/metrics", listener.local_addr()?);
+        let server = Arc::new(Server::from_listener(listener, None).map_err(io::Error::other)?);
+
+        let handle = thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                // Ends once the server is unblocked
+                for request in server.incoming_requests() {
+                    if let Err(e) = respond(request, &counters) {
+                        warn!("Failed to answer a metrics request: {}", e);
+                    }
+                }
+            }
+        });
+        Ok(MetricsServer { server, handle })
+    }
+
+    pub(crate) fn stop(self) {
+        self.server.unblock();
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn serves_the_counters_in_prometheus_format() {
+        let counters = Arc::new(Counters::new(3, 300));
+        counters.files_copied.fetch_add(2, Ordering::Relaxed);
+        counters.bytes_copied.fetch_add(200, Ordering::Relaxed);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = MetricsServer::start(listener, Arc::clone(&counters)).unwrap();
+
+        // A scraper that connects and sends nothing holds up no other
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.stop();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("# TYPE files_copied_total counter\nfiles_copied_total 2\n"));
+        assert!(response.contains("\nbytes_copied_total 200\n"));
+        assert!(response.contains("\nfailures_total 0\n"));
+        assert!(response.contains("\nfiles_total 3\n"));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+// Function to delete every file under `target` that no source file maps to, for --mirror.
+// Symlinked directories are removed as links, never followed, so nothing outside the
+// target is touched. `protected` lists files the run itself writes, such as the manifest.
+// Returns how many files were (or, in a dry run, would be) deleted.
+pub(crate) fn delete_extraneous(target: &Path, keep: &HashSet<PathBuf>, protected: &[PathBuf], dry_run: bool) -> io::Result<usize> {
+    if !target.is_dir() {
+        return Ok(0);
+    }
+    // Output paths may be given relative or through links, so compare them canonically
+    let protected: HashSet<PathBuf> = protected.iter().filter_map(|path| fs::canonicalize(path).ok()).collect();
+
+    let mut deleted = 0;
+    let mut queue: Vec<PathBuf> = vec![target.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not check {:?} for files missing from the source: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                queue.push(path);
+                continue;
+            }
+            if keep.contains(&path) || fs::canonicalize(&path).is_ok_and(|path| protected.contains(&path)) {
+                continue;
+            }
+
+            if dry_run {
+                info!("Would delete {:?}: not in the source", path);
+            } else if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to delete {:?}: {}", path, e);
+                continue;
+            } else {
+                info!("Deleted {:?}: not in the source", path);
+            }
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+// Function to delete the copies of files removed from the source since an earlier run,
+// for --mirror with --since-manifest, without walking the target. A destination that
+// something in this run maps to again is kept. Returns how many files were (or, in a
+// dry run, would be) deleted.
+pub(crate) fn delete_removed(destinations: &[PathBuf], keep: &HashSet<PathBuf>, dry_run: bool) -> usize {
+    let mut deleted = 0;
+    for path in destinations {
+        if keep.contains(path) {
+            continue;
+        }
+        if dry_run {
+            if !path.is_file() {
+                continue;
+            }
+            info!("Would delete {:?}: removed from the source", path);
+        } else {
+            match fs::remove_file(path) {
+                Ok(()) => info!("Deleted {:?}: removed from the source", path),
+                // Already gone, e.g. tidied up by hand
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    warn!("Failed to delete {:?}: {}", path, e);
+                    continue;
+                }
+            }
+        }
+        deleted += 1;
+    }
+    deleted
+}
@@ -0,0 +1,86 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+// Characters NTFS, and so OneDrive, refuses in a name, besides control characters
+const ILLEGAL_CHARS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+// Device names Windows reserves, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Function to say why a name can't be created on a Windows filesystem, if it can't.
+// Names that aren't valid Unicode are left to the copy to report.
+pub(crate) fn invalid_reason(name: &OsStr) -> Option<&'static str> {
+    let name = name.to_str()?;
+    let stem = name.split('.').next().unwrap_or(name);
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem.trim_end())) {
+        Some("reserved device name")
+    } else if name.chars().any(|c| ILLEGAL_CHARS.contains(&c) || c.is_control()) {
+        Some("illegal character")
+    } else if name.ends_with(['.', ' ']) {
+        Some("trailing dot or space")
+    } else {
+        None
+    }
+}
+
+// Function to find the first name in a path that can't be created on a Windows filesystem
+pub(crate) fn first_invalid(path: &Path) -> Option<(&OsStr, &'static str)> {
+    path.iter().find_map(|name| invalid_reason(name).map(|reason| (name, reason)))
+}
+
+// Function to turn a name into one Windows accepts: illegal characters and trailing dots
+// or spaces become underscores, and reserved device names get an underscore after them
+fn sanitize_name(name: &OsStr) -> OsString {
+    let Some(text) = name.to_str().filter(|_| invalid_reason(name).is_some()) else {
+        return name.to_os_string();
+    };
+    let mut sanitized: String = text.chars()
+        .map(|c| if ILLEGAL_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    let kept = sanitized.trim_end_matches(['.', ' ']).len();
+    let trailing = sanitized.len() - kept;
+    sanitized.truncate(kept);
+    sanitized.push_str(&"_".repeat(trailing));
+
+    let stem_len = sanitized.find('.').unwrap_or(sanitized.len());
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(sanitized[..stem_len].trim_end())) {
+        sanitized.insert(stem_len, '_');
+    }
+    sanitized.into()
+}
+
+// Function to sanitize every name in a relative path
+pub(crate) fn sanitize_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| match component {
+            Component::Normal(name) => sanitize_name(name),
+            other => other.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_names_windows_refuses() {
+        for name in ["CON", "aux.txt", "com1.tar.gz", "Nul ", "a:b", "what?", "tab\there", "trailing.", "trailing "] {
+            assert!(invalid_reason(OsStr::new(name)).is_some(), "{:?}", name);
+        }
+        for name in ["CONSOLE", "auxiliary.txt", "com10", "report.pdf", ".hidden", "a b"] {
+            assert_eq!(invalid_reason(OsStr::new(name)), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn sanitizes_each_invalid_name_in_a_path() {
+        let sanitized = sanitize_path(Path::new("notes: 2024/CON.txt/draft.../aux"));
+        assert_eq!(sanitized, PathBuf::from("notes_ 2024/CON_.txt/draft___/aux_"));
+        assert_eq!(first_invalid(&sanitized), None);
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, DirEntry, OpenOptions};
+use std::io;
+use std::path::{self, Component, Path, PathBuf};
+use std::process;
+use indicatif::HumanBytes;
+use tracing::{error, info, warn};
+
+use crate::config::{Config, InvalidNamePolicy};
+use crate::copy::{destination_path, plain_path, unsanitized_destination, PART_SUFFIX};
+use crate::error::MigrateError;
+use crate::get_file_size;
+use crate::names::first_invalid;
+use crate::stub::{is_stub, stub_expected_size};
+
+// Function to resolve `.`, `..` and symlinks in a source or target root, so paths under it
+// map the same way however it was typed. The part of the path that doesn't exist yet (a
+// target still to be created) can't hold symlinks and is resolved by name.
+pub(crate) fn canonical_root(path: &Path) -> io::Result<PathBuf> {
+    if path.as_os_str().is_empty() {
+        return Ok(PathBuf::new());
+    }
+
+    let absolute = path::absolute(path)?;
+    let components: Vec<Component> = absolute.components().collect();
+    for existing in (1..=components.len()).rev() {
+        let prefix: PathBuf = components[..existing].iter().collect();
+        let mut resolved = match fs::canonicalize(&prefix) {
+            Ok(canonical) => plain_path(canonical),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for component in &components[existing..] {
+            match component {
+                Component::ParentDir => { resolved.pop(); }
+                Component::Normal(name) => resolved.push(name),
+                _ => {}
+            }
+        }
+        return Ok(resolved);
+    }
+    Ok(absolute)
+}
+
+// Function to check that the source is a directory we can walk
+pub(crate) fn check_source(source: &Path) -> Result<(), MigrateError> {
+    if !source.is_dir() {
+        return Err(MigrateError::SourceNotDirectory { path: source.to_path_buf() });
+    }
+    Ok(())
+}
+
+// Function to refuse a target inside the source or the other way round: the walk would
+// pick up files as they are written, and --move or --mirror could delete what was copied.
+// Both roots are canonical by now, so comparing components is enough.
+pub(crate) fn check_overlap(source: &Path, target: &Path) -> Result<(), MigrateError> {
+    if target.starts_with(source) || source.starts_with(target) {
+        return Err(MigrateError::OverlappingRoots { source_root: source.to_path_buf(), target_root: target.to_path_buf() });
+    }
+    Ok(())
+}
+
+// Function to check that the target exists (or can be created) and accepts new files,
+// so a read-only or unmounted target fails before the slow traversal
+pub(crate) fn check_target(target: &Path, config: &Config) -> Result<(), MigrateError> {
+    let not_writable = |source| MigrateError::TargetNotWritable { path: target.to_path_buf(), source };
+
+    if config.dry_run {
+        // Nothing may be written, so only reject a target that can never work
+        if target.exists() && !target.is_dir() {
+            return Err(not_writable(io::Error::other("target exists and is not a directory")));
+        }
+        return Ok(());
+    }
+
+    fs::create_dir_all(target).map_err(not_writable)?;
+
+    let probe = target.join(format!(".xerox-write-test-{}", process::id()));
+    OpenOptions::new().write(true).create_new(true).open(&probe).map_err(not_writable)?;
+    fs::remove_file(&probe).map_err(not_writable)?;
+
+    info!("Target {:?} is writable", target);
+    Ok(())
+}
+
+// Function to check that the target volume can hold every file about to be copied.
+// Stubs count at their cloud size where the OS reports it; otherwise their size is
+// unknown and they are left out of the estimate with a warning.
+pub(crate) fn check_free_space(target: &Path, files: &[DirEntry], config: &Config) -> Result<(), MigrateError> {
+    let mut needed = 0;
+    let mut unknown_stubs = 0;
+    for file in files {
+        if is_stub(file, config.stub_max_size) {
+            match stub_expected_size(file) {
+                Some(size) => needed += size,
+                None => unknown_stubs += 1,
+            }
+        } else {
+            needed += get_file_size(file).unwrap_or(0);
+        }
+    }
+
+    if unknown_stubs > 0 {
+        warn!("{} stub files have an unknown size and are not counted in the free-space check", unknown_stubs);
+    }
+    // The margin has to be left over once everything is copied
+    needed = needed.saturating_add(config.min_free_space.unwrap_or(0));
+
+    // A dry run may not have created the target yet, so ask its nearest existing ancestor
+    let volume = target.ancestors().find(|path| path.exists()).unwrap_or(target);
+    let available = fs2::available_space(volume)?;
+
+    if needed > available {
+        let error = MigrateError::InsufficientSpace { path: target.to_path_buf(), needed, available };
+        if !config.ignore_space {
+            return Err(error);
+        }
+        warn!("{}", error);
+    } else {
+        info!("Target has {} free, {} needed", HumanBytes(available), HumanBytes(needed));
+    }
+    Ok(())
+}
+
+// Function to remove temp files left in the target by a copy that was killed or crashed
+pub(crate) fn remove_partial_files(target: &Path, dry_run: bool) -> io::Result<()> {
+    if !target.is_dir() {
+        return Ok(());
+    }
+
+    let mut removed = 0;
+    let mut queue: Vec<PathBuf> = vec![target.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not check {:?} for partial copies: {}", dir, e);
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                queue.push(entry.path());
+            } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(PART_SUFFIX) {
+                if dry_run {
+                    info!("Would remove partial copy: {:?}", entry.path());
+                } else {
+                    fs::remove_file(entry.path())?;
+                    info!("Removed partial copy: {:?}", entry.path());
+                }
+                removed += 1;
+            }
+        }
+    }
+
+    if removed > 0 {
+        warn!("Found {} partial copies from an earlier run", removed);
+    }
+    Ok(())
+}
+
+// Function to tell whether the target treats names differing only in case as the same,
+// by probing it. A dry run may not write, so it assumes the platform's usual behavior.
+fn target_ignores_case(target: &Path, dry_run: bool) -> bool {
+    let platform_default = cfg!(any(windows, target_os = "macos"));
+    if dry_run || !target.is_dir() {
+        return platform_default;
+    }
+
+    let probe = target.join(format!(".xerox-case-test-{}", process::id()));
+    if OpenOptions::new().write(true).create_new(true).open(&probe).is_err() {
+        return platform_default;
+    }
+    let ignores_case = target.join(format!(".XEROX-CASE-TEST-{}", process::id())).exists();
+    let _ = fs::remove_file(&probe);
+    ignores_case
+}
+
+// Function to find the files and directories whose names in the target Windows
+// filesystems (NTFS, and so OneDrive) refuse, logging each as `on_invalid_name` says.
+// Stops before anything is written if they are an error.
+pub(crate) fn check_names(files: &[DirEntry], dirs: &[PathBuf], config: &Config) -> Result<HashSet<PathBuf>, MigrateError> {
+    let mut destinations = Vec::with_capacity(files.len() + dirs.len());
+    for file in files {
+        destinations.push((file.path(), unsanitized_destination(&file.path(), config)?));
+    }
+    // Only a mirrored tree has a place for the source's own folders
+    if !config.flatten && !config.prune_empty_dirs && config.dest_template.is_none() {
+        for dir in dirs {
+            if let Ok(relative_path) = dir.strip_prefix(&config.source) {
+                destinations.push((dir.clone(), config.target.join(relative_path)));
+            }
+        }
+    }
+
+    let mut invalid = HashSet::new();
+    for (source, destination) in destinations {
+        let relative_path = destination.strip_prefix(&config.target).unwrap_or(&destination);
+        let Some((name, reason)) = first_invalid(relative_path) else { continue };
+        match config.on_invalid_name {
+            InvalidNamePolicy::Warn => warn!("{:?} may fail to copy: {:?} is invalid on Windows filesystems ({})", source, name, reason),
+            InvalidNamePolicy::Error => error!("{:?}: {:?} is invalid on Windows filesystems ({})", source, name, reason),
+            InvalidNamePolicy::Sanitize => {
+                let sanitized = destination_path(&source, config).unwrap_or_default();
+                info!("Copying {:?} as {:?}: {:?} is invalid on Windows filesystems ({})", source, sanitized, name, reason);
+            }
+            InvalidNamePolicy::Skip => info!("Skipping {:?}: {:?} is invalid on Windows filesystems ({})", source, name, reason),
+        }
+        invalid.insert(source);
+    }
+
+    if config.on_invalid_name == InvalidNamePolicy::Error && !invalid.is_empty() {
+        return Err(MigrateError::InvalidNames { count: invalid.len() });
+    }
+    Ok(invalid)
+}
+
+// Function to find distinct source files that would land on the same destination, for
+// example through --flatten or names differing only in case on a case-insensitive target
+pub(crate) fn check_conflicts(files: &[DirEntry], config: &Config) -> Result<(), MigrateError> {
+    let ignores_case = target_ignores_case(&config.target, config.dry_run);
+
+    let mut by_destination: BTreeMap<String, Vec<(PathBuf, PathBuf)>> = BTreeMap::new();
+    for file in files {
+        let destination = destination_path(&file.path(), config)?;
+        let key = destination.to_string_lossy().into_owned();
+        let key = if ignores_case { key.to_lowercase() } else { key };
+        by_destination.entry(key).or_default().push((file.path(), destination));
+    }
+
+    let collisions: Vec<_> = by_destination.values().filter(|group| group.len() > 1).collect();
+    if collisions.is_empty() {
+        info!("No destination conflicts among {} files", files.len());
+        return Ok(());
+    }
+
+    for group in &collisions {
+        let destination = &group[0].1;
+        if group.iter().all(|(_, other)| other == destination) {
+            error!("{} files map to {:?}:", group.len(), destination);
+        } else {
+            error!("{} files map to {:?} on this case-insensitive target (see --on-case-collision):", group.len(), destination);
+        }
+        for (source, _) in group.iter() {
+            error!("  {:?}", source);
+        }
+    }
+    Err(MigrateError::DestinationConflicts { count: collisions.len() })
+}
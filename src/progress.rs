@@ -0,0 +1,369 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use tracing::info;
+
+use crate::timeout::Cancel;
+
+// Minimum gap between two byte-progress events for the same file
+const EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+// What happened to a file, in the --progress-json stream
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventKind {
+    Started,
+    Progress,
+    Completed,
+    Error,
+}
+
+// One line of the --progress-json stream
+#[derive(Debug, Serialize)]
+pub(crate) struct ProgressEvent {
+    event: EventKind,
+    path: PathBuf,
+    bytes_done: u64,
+    // Unknown for stubs whose cloud size the OS does not report
+    total: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+// Bytes in the targets' files so far, added to chunk by chunk as copies write them rather
+// than once each file is done. An attempt whose partial copy is thrown away takes its bytes
+// back, so once the copies finish the total is exactly what they left on disk. Reflinked
+// files share their source's data rather than writing any, so they are counted as cloned
+// instead.
+#[derive(Debug, Default)]
+pub(crate) struct BytesWritten {
+    written: AtomicU64,
+    cloned: AtomicU64,
+}
+
+impl BytesWritten {
+    pub(crate) fn get(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn cloned(&self) -> u64 {
+        self.cloned.load(Ordering::Relaxed)
+    }
+}
+
+// What one attempt at a transfer has added to the BytesWritten counts
+#[derive(Clone, Copy, Default)]
+struct Attempt {
+    written: u64,
+    cloned: u64,
+}
+
+// Terminal bars plus, with --progress-json, the sender feeding the event stream.
+// Workers share one sender, so events from parallel copies never interleave mid-line.
+pub(crate) struct Progress {
+    bars: Arc<MultiProgress>,
+    events: Option<Sender<ProgressEvent>>,
+    written: Arc<BytesWritten>,
+}
+
+impl Progress {
+    pub(crate) fn new(bars: MultiProgress, events: Option<Sender<ProgressEvent>>) -> Self {
+        Progress { bars: Arc::new(bars), events, written: Arc::default() }
+    }
+
+    // Function to get the count of bytes the copies tracked by this have written
+    pub(crate) fn written(&self) -> &Arc<BytesWritten> {
+        &self.written
+    }
+
+    // Function to start the thread that draws the bars. A MultiProgress only draws while it
+    // is joined, and the join returns once every bar has finished.
+    pub(crate) fn spawn_drawer(&self) -> JoinHandle<io::Result<()>> {
+        let bars = Arc::clone(&self.bars);
+        thread::spawn(move || bars.join())
+    }
+
+    // Function to send an event to the JSON stream, if there is one
+    pub(crate) fn emit(&self, event: EventKind, path: &Path, bytes_done: u64, total: Option<u64>, message: Option<String>) {
+        if let Some(events) = &self.events {
+            // The writer only stops early if its output failed, which it reports itself
+            let _ = events.send(ProgressEvent { event, path: path.to_path_buf(), bytes_done, total, message });
+        }
+    }
+
+    // Function to start tracking one file's transfer: a bar, or a spinner when its final size is unknown
+    pub(crate) fn file(&self, path: &Path, len: Option<u64>) -> FileProgress {
+        let bar = match len {
+            Some(len) => {
+                let pb = self.bars.add(ProgressBar::new(len));
+                pb.set_style(ProgressStyle::default_bar()
+                    .template("{msg} {wide_bar} {bytes}/{total_bytes} ({eta})")
+                    .progress_chars("##-"));
+                pb
+            }
+            None => {
+                let pb = self.bars.add(ProgressBar::new_spinner());
+                pb.set_style(ProgressStyle::default_spinner()
+                    .template("{spinner} {msg} {bytes} ({bytes_per_sec})"));
+                pb.enable_steady_tick(100);
+                pb
+            }
+        };
+        bar.set_message(path.file_name().unwrap_or_default().to_string_lossy().into_owned());
+
+        FileProgress {
+            bar,
+            events: self.events.clone(),
+            path: path.to_path_buf(),
+            total: len,
+            last_event: None,
+            written: Arc::clone(&self.written),
+            attempt: Arc::new(Mutex::new(Some(Attempt::default()))),
+            cancel: Cancel::default(),
+        }
+    }
+}
+
+// Progress of one file transfer: its bar and, with --progress-json, throttled byte-progress events.
+// Clones are cheap and can be moved to the thread doing the transfer.
+#[derive(Clone)]
+pub(crate) struct FileProgress {
+    pub(crate) bar: ProgressBar,
+    events: Option<Sender<ProgressEvent>>,
+    path: PathBuf,
+    total: Option<u64>,
+    last_event: Option<Instant>,
+    written: Arc<BytesWritten>,
+    // Bytes written or cloned by the current attempt, or None once it has been discarded
+    attempt: Arc<Mutex<Option<Attempt>>>,
+    // Set when the transfer has been given up on after its time limit
+    cancel: Cancel,
+}
+
+impl FileProgress {
+    // Function to start another attempt at the transfer, counting its writes apart from the last
+    pub(crate) fn next_attempt(&self) -> FileProgress {
+        FileProgress { attempt: Arc::new(Mutex::new(Some(Attempt::default()))), ..self.clone() }
+    }
+
+    // Function to stop the transfer between chunks once `cancel` is set
+    pub(crate) fn cancel_on(&mut self, cancel: &Cancel) {
+        self.cancel = cancel.clone();
+    }
+
+    // Function to fail the transfer if it has been given up on
+    pub(crate) fn check_cancelled(&self) -> io::Result<()> {
+        self.cancel.check()
+    }
+
+    // Function to count bytes just written to the target. Writes from an attempt already
+    // discarded, such as one that timed out but is still running, are not counted.
+    pub(crate) fn wrote(&self, bytes: u64) {
+        if let Some(attempt) = &mut *self.attempt.lock().unwrap() {
+            attempt.written += bytes;
+            self.written.written.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    // Function to count bytes the target now shares with the source by a reflink, which
+    // writes none of them
+    pub(crate) fn cloned(&self, bytes: u64) {
+        if let Some(attempt) = &mut *self.attempt.lock().unwrap() {
+            attempt.cloned += bytes;
+            self.written.cloned.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    // Function to take back what this attempt wrote, once its partial copy is removed
+    pub(crate) fn discard(&self) {
+        if let Some(attempt) = self.attempt.lock().unwrap().take() {
+            self.written.written.fetch_sub(attempt.written, Ordering::Relaxed);
+            self.written.cloned.fetch_sub(attempt.cloned, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set_position(&mut self, bytes_done: u64) {
+        self.bar.set_position(bytes_done);
+
+        if let Some(events) = &self.events {
+            if self.last_event.is_none_or(|last| last.elapsed() >= EVENT_INTERVAL) {
+                self.last_event = Some(Instant::now());
+                let _ = events.send(ProgressEvent {
+                    event: EventKind::Progress,
+                    path: self.path.clone(),
+                    bytes_done,
+                    total: self.total,
+                    message: None,
+                });
+            }
+        }
+    }
+}
+
+// Function to start the thread that writes progress events as JSON lines to `path`,
+// or to stdout when `path` is "-". The thread ends once every sender is dropped.
+pub(crate) fn spawn_event_writer(path: &Path) -> io::Result<(Sender<ProgressEvent>, JoinHandle<io::Result<()>>)> {
+    let mut writer: Box<dyn Write + Send> = if path == Path::new("-") {
+        Box::new(io::stdout())
+    } else {
+        Box::new(File::create(path)?)
+    };
+
+    let (sender, receiver) = mpsc::channel::<ProgressEvent>();
+    let handle = thread::spawn(move || {
+        for event in receiver {
+            serde_json::to_writer(&mut writer, &event)?;
+            writer.write_all(b"\n")?;
+            // Flush every line so a consumer reading a pipe sees events as they happen
+            writer.flush()?;
+        }
+        Ok(())
+    });
+
+    Ok((sender, handle))
+}
+
+// Files and bytes finished so far, shared by the copy workers and anything reporting on them.
+// The byte total starts from the sizes known before copying and grows as stubs of unknown
+// size turn out to be larger, so it is a running estimate rather than a fixed figure.
+// Copies, failures and retries are counted per target, as the summary counts them.
+pub(crate) struct Counters {
+    pub(crate) files_done: AtomicUsize,
+    pub(crate) bytes_done: AtomicU64,
+    pub(crate) total_files: usize,
+    pub(crate) total_bytes: AtomicU64,
+    pub(crate) files_copied: AtomicUsize,
+    pub(crate) bytes_copied: AtomicU64,
+    // Bytes written so far, files still in flight included
+    pub(crate) bytes_written: Arc<BytesWritten>,
+    pub(crate) failures: AtomicUsize,
+    pub(crate) retries: AtomicU64,
+    // Each target root, in the order targets are given
+    pub(crate) targets: Vec<TargetCounters>,
+}
+
+// What has been copied to one target, and what is waiting on its workers
+pub(crate) struct TargetCounters {
+    pub(crate) root: PathBuf,
+    pub(crate) bytes_copied: AtomicU64,
+    // Files fetched and waiting for this target's own workers, with --workers-per-target
+    pub(crate) queued: AtomicUsize,
+}
+
+impl Counters {
+    pub(crate) fn new(total_files: usize, total_bytes: u64) -> Self {
+        Counters {
+            targets: Vec::new(),
+            files_done: AtomicUsize::new(0),
+            bytes_done: AtomicU64::new(0),
+            total_files,
+            total_bytes: AtomicU64::new(total_bytes),
+            files_copied: AtomicUsize::new(0),
+            bytes_copied: AtomicU64::new(0),
+            bytes_written: Arc::default(),
+            failures: AtomicUsize::new(0),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    // Function to give the counters one entry per target root
+    pub(crate) fn with_targets<'a>(mut self, roots: impl IntoIterator<Item = &'a Path>) -> Self {
+        self.targets = roots.into_iter()
+            .map(|root| TargetCounters { root: root.to_path_buf(), bytes_copied: AtomicU64::new(0), queued: AtomicUsize::new(0) })
+            .collect();
+        self
+    }
+
+    // Function to share the count of bytes written by the copies' progress
+    pub(crate) fn with_bytes_written(mut self, written: &Arc<BytesWritten>) -> Self {
+        self.bytes_written = Arc::clone(written);
+        self
+    }
+
+    // Function to look up a target's counters by its root
+    pub(crate) fn target(&self, root: &Path) -> Option<&TargetCounters> {
+        self.targets.iter().find(|target| target.root == root)
+    }
+
+    // Function to count a finished file that was expected to be `expected` bytes and
+    // turned out to be `actual`, returning the bytes done and the total so far
+    pub(crate) fn finish_file(&self, expected: u64, actual: u64) -> (u64, u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        let total = if actual > expected {
+            self.total_bytes.fetch_add(actual - expected, Ordering::Relaxed) + actual - expected
+        } else {
+            self.total_bytes.load(Ordering::Relaxed)
+        };
+        let done = self.bytes_done.fetch_add(actual, Ordering::Relaxed) + actual;
+        (done, total.max(done))
+    }
+}
+
+// A background thread that logs throughput at a fixed interval until stopped
+pub(crate) struct Heartbeat {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Heartbeat {
+    pub(crate) fn start(counters: Arc<Counters>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut last_bytes = 0;
+            let mut last_target_bytes = vec![0; counters.targets.len()];
+            let mut last_time = Instant::now();
+            // Wake every interval until told to stop
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let files_done = counters.files_done.load(Ordering::Relaxed);
+                let bytes_done = counters.bytes_done.load(Ordering::Relaxed);
+                let elapsed = last_time.elapsed().as_secs_f64();
+                let rate = (bytes_done - last_bytes) as f64 / elapsed;
+                last_bytes = bytes_done;
+                last_time = Instant::now();
+
+                // Targets are listed separately only when there is more than one to compare
+                let mut per_target = Vec::new();
+                for (target, last) in counters.targets.iter().zip(&mut last_target_bytes) {
+                    let copied = target.bytes_copied.load(Ordering::Relaxed);
+                    let target_rate = (copied - *last) as f64 / elapsed;
+                    *last = copied;
+                    per_target.push(format!("{}: {}, {}/s, {} queued",
+                        target.root.display(), HumanBytes(copied), HumanBytes(target_rate as u64), target.queued.load(Ordering::Relaxed)));
+                }
+
+                let total_bytes = counters.total_bytes.load(Ordering::Relaxed);
+                let remaining = total_bytes.saturating_sub(bytes_done);
+                let eta = if rate > 0.0 {
+                    HumanDuration(Duration::from_secs_f64(remaining as f64 / rate)).to_string()
+                } else {
+                    "unknown".to_string()
+                };
+                let cloned = match counters.bytes_written.cloned() {
+                    0 => String::new(),
+                    cloned => format!(", {} cloned", HumanBytes(cloned)),
+                };
+                info!("Progress: {}/{} files, {} / {} ({} written{}), {}/s, ETA {}",
+                    files_done, counters.total_files, HumanBytes(bytes_done), HumanBytes(total_bytes),
+                    HumanBytes(counters.bytes_written.get()), cloned, HumanBytes(rate as u64), eta);
+                if per_target.len() > 1 {
+                    for line in per_target {
+                        info!("  {}", line);
+                    }
+                }
+            }
+        });
+        Heartbeat { stop, handle }
+    }
+
+    pub(crate) fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::DirEntry;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::MigrateError;
+use crate::summary::FileOutcome;
+
+// A queued file and the job it's part of
+type Queued<'a> = (&'a DirEntry, Arc<FileJob>);
+
+// Files waiting to be copied into one target by that target's own workers, so a slow
+// target falls behind on its own instead of holding up the others
+pub(crate) struct TargetQueue<'a> {
+    pub(crate) root: PathBuf,
+    // Taken once every file has been queued, so the workers stop when the queue runs dry
+    sender: Mutex<Option<Sender<Queued<'a>>>>,
+    receiver: Mutex<Receiver<Queued<'a>>>,
+}
+
+impl<'a> TargetQueue<'a> {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        TargetQueue { root, sender: Mutex::new(Some(sender)), receiver: Mutex::new(receiver) }
+    }
+
+    pub(crate) fn push(&self, file: &'a DirEntry, job: Arc<FileJob>) {
+        if let Some(sender) = &*self.sender.lock().unwrap() {
+            // The receiver lives as long as the queue, so this can't fail
+            let _ = sender.send((file, job));
+        }
+    }
+
+    // Function to wait for the next file, or None once the queue is closed and empty
+    pub(crate) fn pop(&self) -> Option<Queued<'a>> {
+        self.receiver.lock().unwrap().recv().ok()
+    }
+
+    pub(crate) fn close(&self) {
+        self.sender.lock().unwrap().take();
+    }
+}
+
+// One fetched file on its way into several targets. Whichever target finishes last
+// reports the file as a whole.
+pub(crate) struct FileJob {
+    pub(crate) size: Option<u64>,
+    pub(crate) retries: u32,
+    remaining: AtomicUsize,
+    outcome: Mutex<JobOutcome>,
+}
+
+// How a file went across all of its targets
+#[derive(Default)]
+pub(crate) struct JobOutcome {
+    pub(crate) bytes: u64,
+    pub(crate) first_error: Option<MigrateError>,
+    // Some target never started its copy, because the run was stopped first
+    pub(crate) not_started: bool,
+}
+
+impl FileJob {
+    pub(crate) fn new(targets: usize, size: Option<u64>, retries: u32) -> Self {
+        FileJob { size, retries, remaining: AtomicUsize::new(targets), outcome: Mutex::new(JobOutcome::default()) }
+    }
+
+    // Function to add one target's result, None if its copy never started, returning
+    // the file's outcome once this was the last target
+    pub(crate) fn finish(&self, result: Option<Result<FileOutcome, MigrateError>>) -> Option<JobOutcome> {
+        let mut outcome = self.outcome.lock().unwrap();
+        match result {
+            Some(Ok(FileOutcome::Copied { bytes, .. })) => outcome.bytes += bytes,
+            Some(Ok(FileOutcome::Skipped)) => {}
+            Some(Err(e)) => { outcome.first_error.get_or_insert(e); }
+            None => outcome.not_started = true,
+        }
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            Some(std::mem::take(&mut *outcome))
+        } else {
+            None
+        }
+    }
+}
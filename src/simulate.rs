@@ -0,0 +1,32 @@
+use std::path::Path;
+use std::time::Duration;
+use globset::Glob;
+
+/// Test mode that makes chosen files behave like slow, briefly locked cloud
+/// stubs, so the fetch retry and progress logic can be exercised without a
+/// real Box mount. Only built with the `simulate-stubs` feature.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedStubs {
+    /// Delay added to every attempt to open a simulated stub
+    pub latency: Duration,
+    /// How many attempts fail with `WouldBlock` before the file opens
+    pub lock_failures: u32,
+    /// File name globs picking the simulated stubs; every file when empty
+    pub files: Vec<String>,
+}
+
+impl SimulatedStubs {
+    // Function to check whether a file is one of the simulated stubs. Patterns are
+    // compiled on every call, which is fine for a test mode.
+    pub(crate) fn applies_to(&self, path: &Path) -> bool {
+        if self.files.is_empty() {
+            return true;
+        }
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        self.files.iter()
+            .filter_map(|pattern| Glob::new(pattern).ok())
+            .any(|glob| glob.compile_matcher().is_match(name))
+    }
+}
@@ -0,0 +1,307 @@
+use std::fs::{self, DirEntry, File};
+use std::io::{self, Read, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::thread;
+use indicatif::HumanBytes;
+use tracing::{info, error, warn};
+
+use crate::config::{Config, SymlinkPolicy};
+use crate::error::MigrateError;
+use crate::get_file_size;
+use crate::progress::Progress;
+use crate::summary::RunStatus;
+use crate::timeout::with_timeout;
+
+// Windows attributes set on cloud placeholders whose contents are not local
+#[cfg(windows)]
+const FILE_ATTRIBUTE_OFFLINE: u32 = 0x0000_1000;
+#[cfg(windows)]
+const FILE_ATTRIBUTE_RECALL_ON_OPEN: u32 = 0x0004_0000;
+#[cfg(windows)]
+const FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS: u32 = 0x0040_0000;
+
+/// Check whether a file is a cloud stub that must be downloaded before copying.
+///
+/// On Windows the placeholder file attributes decide; elsewhere any file at or
+/// below `stub_max_size` bytes is treated as a stub.
+#[cfg(windows)]
+pub fn is_stub(entry: &DirEntry, _stub_max_size: u64) -> bool {
+    use std::os::windows::fs::MetadataExt;
+
+    let stub_attributes = FILE_ATTRIBUTE_OFFLINE | FILE_ATTRIBUTE_RECALL_ON_OPEN | FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS;
+    entry.metadata().map(|metadata| metadata.file_attributes() & stub_attributes != 0).unwrap_or(false)
+}
+
+/// Check whether a file is a cloud stub that must be downloaded before copying.
+///
+/// On Windows the placeholder file attributes decide; elsewhere any file at or
+/// below `stub_max_size` bytes is treated as a stub.
+#[cfg(not(windows))]
+pub fn is_stub(entry: &DirEntry, stub_max_size: u64) -> bool {
+    get_file_size(entry).is_some_and(|size| size <= stub_max_size)
+}
+
+// Function to get the real size of a stub when the OS reports it before download
+#[cfg(windows)]
+pub(crate) fn stub_expected_size(entry: &DirEntry) -> Option<u64> {
+    // Windows placeholders report their logical (cloud) size
+    get_file_size(entry).filter(|&size| size > 0)
+}
+
+// Function to get the real size of a stub when the OS reports it before download
+#[cfg(not(windows))]
+pub(crate) fn stub_expected_size(_entry: &DirEntry) -> Option<u64> {
+    // Size-heuristic stubs only expose their placeholder size
+    None
+}
+
+// Function to get the size a file will have once copied, if it is known before any
+// download: its own size, or a stub's cloud size where the OS reports it
+pub(crate) fn expected_size(entry: &DirEntry, stub_max_size: u64) -> Option<u64> {
+    if is_stub(entry, stub_max_size) {
+        stub_expected_size(entry)
+    } else {
+        get_file_size(entry)
+    }
+}
+
+// Function to check whether a file is genuinely empty rather than an undownloaded stub.
+// With the size heuristic an empty file looks exactly like a stub until it has been
+// fetched, so this only trusts the size once `fetched` files have really been read.
+pub(crate) fn is_genuinely_empty(entry: &DirEntry, fetched: bool, config: &Config) -> bool {
+    if fetched && config.dry_run {
+        return false;
+    }
+    // Links copied as links have no contents of their own
+    if config.symlinks == SymlinkPolicy::CopyAsLink && entry.file_type().is_ok_and(|file_type| file_type.is_symlink()) {
+        return false;
+    }
+    fs::metadata(entry.path()).is_ok_and(|metadata| metadata.len() == 0)
+}
+
+// Function to check whether a file goes through the stub download path
+#[cfg(feature = "simulate-stubs")]
+fn needs_fetch(entry: &DirEntry, config: &Config) -> bool {
+    is_stub(entry, config.stub_max_size) || config.simulate_stubs.as_ref().is_some_and(|simulated| simulated.applies_to(&entry.path()))
+}
+
+// Function to check whether a file goes through the stub download path
+#[cfg(not(feature = "simulate-stubs"))]
+fn needs_fetch(entry: &DirEntry, config: &Config) -> bool {
+    is_stub(entry, config.stub_max_size)
+}
+
+// Function to open a stub for download on the given attempt (starting at 0). Simulated
+// stubs are slow to open and stay locked for their first few attempts.
+#[cfg(feature = "simulate-stubs")]
+fn open_stub(path: &Path, attempt: u32, config: &Config) -> io::Result<File> {
+    if let Some(simulated) = config.simulate_stubs.as_ref().filter(|simulated| simulated.applies_to(path)) {
+        thread::sleep(simulated.latency);
+        if attempt < simulated.lock_failures {
+            return Err(io::Error::new(ErrorKind::WouldBlock, "simulated stub lock"));
+        }
+    }
+    File::open(path)
+}
+
+// Function to open a stub for download
+#[cfg(not(feature = "simulate-stubs"))]
+fn open_stub(path: &Path, _attempt: u32, _config: &Config) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Result of downloading stubs in place with `stub_hydrate_only`
+#[derive(Debug, Default)]
+pub struct Hydration {
+    /// Stubs downloaded, or that would be in a dry run
+    pub fetched: usize,
+    /// Files whose contents were already local
+    pub local: usize,
+    /// Stubs that needed more than one attempt to open
+    pub retried: usize,
+    /// Stubs that could not be downloaded
+    pub failed: Vec<PathBuf>,
+    /// Files left alone because the run was interrupted
+    pub not_started: usize,
+}
+
+impl Hydration {
+    /// How the run went, ranked as for a copy
+    pub fn status(&self) -> RunStatus {
+        if self.not_started > 0 {
+            RunStatus::Interrupted
+        } else if !self.failed.is_empty() {
+            RunStatus::Failed
+        } else {
+            RunStatus::Complete
+        }
+    }
+
+    /// Print the final report
+    pub fn report(&self) {
+        info!("Hydration:");
+        info!("  Stubs fetched:    {}", self.fetched);
+        info!("  Already local:    {}", self.local);
+        info!("  Needed retries:   {}", self.retried);
+        info!("  Failed:           {}", self.failed.len());
+        if self.not_started > 0 {
+            info!("  Not started:      {}", self.not_started);
+        }
+
+        for path in &self.failed {
+            error!("  Could not fetch {:?}", path);
+        }
+    }
+}
+
+/// Stub and hydrated counts from `probe`, found from metadata alone
+#[derive(Debug, Default)]
+pub struct Probe {
+    /// Files whose contents are still in the cloud
+    pub stubs: usize,
+    /// Files whose contents are already local
+    pub hydrated: usize,
+    /// Combined cloud size of the stubs that report one
+    pub stub_bytes: u64,
+    /// Stubs whose size isn't known until they are downloaded
+    pub unsized_stubs: usize,
+    /// Combined size of the hydrated files
+    pub hydrated_bytes: u64,
+}
+
+impl Probe {
+    // Function to classify one file by its metadata, without opening it
+    pub(crate) fn add(&mut self, entry: &DirEntry, config: &Config) {
+        if needs_fetch(entry, config) {
+            self.stubs += 1;
+            match stub_expected_size(entry) {
+                Some(size) => self.stub_bytes += size,
+                None => self.unsized_stubs += 1,
+            }
+        } else {
+            self.hydrated += 1;
+            self.hydrated_bytes += get_file_size(entry).unwrap_or(0);
+        }
+    }
+
+    /// Print the final report
+    pub fn report(&self) {
+        info!("Probe:");
+        info!("  Stubs:            {}", self.stubs);
+        info!("  Hydrated:         {}", self.hydrated);
+        info!("  Stub bytes:       {}", HumanBytes(self.stub_bytes));
+        if self.unsized_stubs > 0 {
+            info!("  Of unknown size:  {}", self.unsized_stubs);
+        }
+        info!("  Hydrated bytes:   {}", HumanBytes(self.hydrated_bytes));
+    }
+}
+
+// What fetching a file took: whether it was a stub that needed downloading, and how
+// many times opening it was retried
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Fetch {
+    pub(crate) fetched: bool,
+    pub(crate) retries: u32,
+}
+
+// Function to fetch the file with retries to handle file locks during download
+pub(crate) fn fetch_file_with_progress(entry: &DirEntry, progress: &Progress, config: &Config) -> Result<Fetch, MigrateError> {
+    let path = entry.path();
+
+    // Links copied as links are never opened, so there is nothing to fetch
+    if config.symlinks == SymlinkPolicy::CopyAsLink && entry.file_type().is_ok_and(|file_type| file_type.is_symlink()) {
+        return Ok(Fetch::default());
+    }
+
+    // Check if the file is a stub that needs to be downloaded
+    if needs_fetch(entry, config) {
+        if config.dry_run {
+            info!("Would fetch stub file: {:?}", path);
+            return Ok(Fetch { fetched: true, retries: 0 });
+        }
+
+        info!("Fetching stub file: {:?}", path);
+
+        // Create a progress bar, or a spinner when the final size is unknown
+        let file_progress = progress.file(&path, stub_expected_size(entry));
+        let pb = file_progress.bar.clone();
+
+        // Retry loop to handle file locks
+        let mut retries = 0;
+        loop {
+            match open_stub(&path, retries, config) {
+                Ok(mut file) => {
+                    let mut reader_progress = file_progress.clone();
+                    let buffer_size = config.copy_buffer_size;
+                    let download = with_timeout(&path, config.file_timeout, move |cancel| {
+                        let mut buffer = vec![0; buffer_size];
+                        let mut total_read = 0;
+
+                        // Read the file in chunks to show download progress. A read that
+                        // fails part way, e.g. the provider dropping the download, fails the fetch.
+                        loop {
+                            cancel.check()?;
+                            let bytes_read = match file.read(&mut buffer) {
+                                Ok(0) => break, // End of file
+                                Ok(bytes_read) => bytes_read,
+                                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                                Err(e) => return Err(e),
+                            };
+                            total_read += bytes_read as u64;
+                            reader_progress.set_position(total_read);
+                        }
+                        Ok(())
+                    });
+                    if let Err(e) = download {
+                        let reason = if matches!(e, MigrateError::FileTimeout { .. }) { "timed out" } else { "failed" };
+                        pb.abandon_with_message(format!("{} {}", entry.file_name().to_string_lossy(), reason));
+                        error!("Failed to fetch file: {}", e);
+                        return Err(e);
+                    }
+
+                    pb.finish_with_message(format!("{} download complete", entry.file_name().to_string_lossy()));
+                    return Ok(Fetch { fetched: true, retries });
+                }
+                Err(e) => {
+                    if e.kind() == ErrorKind::PermissionDenied || e.kind() == ErrorKind::WouldBlock {
+                        // The file might still be locked due to ongoing download, so retry
+                        if retries >= config.retry.max_retries {
+                            error!("Failed to fetch file after multiple retries: {:?}", path);
+                            return Err(MigrateError::StubLockTimeout { path, attempts: retries + 1 });
+                        }
+                        retries += 1;
+                        let delay = config.retry.delay(retries);
+                        warn!("File locked, retrying in {:?}... (attempt {})", delay, retries);
+                        thread::sleep(delay); // Wait before retrying
+                    } else {
+                        error!("Error opening file: {:?}", e);
+                        return Err(e.into()); // Propagate other errors
+                    }
+                }
+            }
+        }
+    } else {
+        // File is not a stub, no need to fetch
+        Ok(Fetch::default())
+    }
+}
+
+// Opening a directory works on Linux but reading it fails, like a download cut off
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use indicatif::{MultiProgress, ProgressDrawTarget};
+
+    #[test]
+    fn a_failed_read_fails_the_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("stub")).unwrap();
+        let entry = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap();
+        let mut config = Config::new(dir.path(), "onedrive");
+        config.stub_max_size = u64::MAX;
+        let progress = Progress::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()), None);
+
+        assert!(matches!(fetch_file_with_progress(&entry, &progress, &config), Err(MigrateError::Io(_))));
+    }
+}
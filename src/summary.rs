@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use indicatif::HumanBytes;
+use tracing::{info, error, warn};
+
+use crate::config::ConflictPolicy;
+
+// Outcome of moving a single file to the target
+pub(crate) enum FileOutcome {
+    Copied {
+        bytes: u64,
+        sha256: Option<String>,
+        // SHA-256 of the source as it was read for the copy
+        source_sha256: Option<String>,
+        destination: PathBuf,
+        // How an existing destination was resolved, if there was one
+        conflict: Option<ConflictPolicy>,
+        // Hard linked to an identical earlier copy rather than copied
+        linked: bool,
+    },
+    Skipped,
+}
+
+/// Outcomes for one of several targets
+#[derive(Debug, Default)]
+pub struct TargetSummary {
+    pub copied: usize,
+    pub skipped: usize,
+    pub resumed: usize,
+    pub failed: usize,
+    pub bytes_transferred: u64,
+    /// Free space left on the target at the end, when it could be read
+    pub free_space: Option<u64>,
+}
+
+/// Running totals reported at the end of a run. When copying to several
+/// targets, file counts add up every target's copy of a file.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub copied: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
+    pub renamed: usize,
+    pub excluded: usize,
+    pub resumed: usize,
+    /// Files left alone by `since_manifest` because they haven't changed since that run
+    pub unchanged: usize,
+    /// Files left out by `max_files`, past the limit
+    pub over_limit: usize,
+    /// Files not copied because `only` created just the directories
+    pub left_for_later: usize,
+    pub stubs_fetched: usize,
+    /// Genuinely empty files (not stubs), whether copied or skipped
+    pub empty_files: usize,
+    pub unstable: usize,
+    pub failed: usize,
+    /// Target files removed by `mirror` because no source file maps to them
+    pub deleted: usize,
+    /// Files skipped because the run was interrupted before they started
+    pub not_started: usize,
+    /// Files not copied because the target reached its `min_free_space` margin
+    pub skipped_for_space: usize,
+    /// Files whose destination names Windows filesystems refuse, copied under valid
+    /// names or left out by `on_invalid_name`
+    pub sanitized: usize,
+    pub skipped_invalid: usize,
+    pub bytes_transferred: u64,
+    /// Files that only opened after retrying, and the most attempts any of them took
+    pub retried: usize,
+    pub max_attempts: u32,
+    /// Files hard linked to an identical copy by `dedup`, and the bytes that saved
+    pub linked: usize,
+    pub bytes_saved: u64,
+    /// Failed files, grouped by `MigrateError::category`
+    pub failures: BTreeMap<&'static str, Vec<PathBuf>>,
+    /// The same outcomes broken down by target root
+    pub targets: BTreeMap<PathBuf, TargetSummary>,
+}
+
+/// How a run went overall, from the worst outcome any file had
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// Every file was copied or already done
+    Complete,
+    /// Some files were skipped, e.g. as existing, still being written or for space, but none failed
+    Skipped,
+    /// Some files failed
+    Failed,
+    /// The run stopped before starting every file
+    Interrupted,
+}
+
+// How many paths to list under each failure category
+const LISTED_PER_CATEGORY: usize = 5;
+
+impl Summary {
+    /// The overall outcome of the run
+    pub fn status(&self) -> RunStatus {
+        if self.not_started > 0 {
+            RunStatus::Interrupted
+        } else if self.failed > 0 {
+            RunStatus::Failed
+        } else if self.skipped > 0 || self.unstable > 0 || self.skipped_for_space > 0 || self.skipped_invalid > 0 {
+            RunStatus::Skipped
+        } else {
+            RunStatus::Complete
+        }
+    }
+
+    /// Print the final report, ending with a verdict logged at the level of the
+    /// worst outcome, so it stands out in the colors of that level
+    pub fn report(&self, dry_run: bool) {
+        info!("Summary{}:", if dry_run { " (dry run)" } else { "" });
+        info!("  Files copied:        {}", self.copied);
+        info!("  Files skipped:       {}", self.skipped);
+        info!("  Files overwritten:   {}", self.overwritten);
+        info!("  Files renamed:       {}", self.renamed);
+        info!("  Skipped (excluded):  {}", self.excluded);
+        info!("  Already done:        {}", self.resumed);
+        if self.unchanged > 0 {
+            info!("  Unchanged:           {}", self.unchanged);
+        }
+        if self.over_limit > 0 {
+            info!("  Over --max-files:    {}", self.over_limit);
+        }
+        if self.left_for_later > 0 {
+            info!("  Left by --only dirs: {}", self.left_for_later);
+        }
+        info!("  Stub files fetched:  {}", self.stubs_fetched);
+        info!("  Empty files:         {}", self.empty_files);
+        info!("  Still being written: {}", self.unstable);
+        info!("  Files failed:        {}", self.failed);
+        info!("  Files deleted:       {}", self.deleted);
+        info!("  Bytes transferred:   {}", self.bytes_transferred);
+        if let (1, Some(free_space)) = (self.targets.len(), self.targets.values().next().and_then(|counts| counts.free_space)) {
+            info!("  Free space left:     {}", HumanBytes(free_space));
+        }
+        if self.retried > 0 {
+            info!("  Needed retries:      {} (max {} attempts)", self.retried, self.max_attempts);
+        }
+        if self.linked > 0 {
+            info!("  Duplicates linked:   {} ({} bytes saved)", self.linked, self.bytes_saved);
+        }
+        if self.sanitized > 0 {
+            info!("  Names sanitized:     {}", self.sanitized);
+        }
+        if self.skipped_invalid > 0 {
+            info!("  Invalid names:       {} skipped", self.skipped_invalid);
+        }
+
+        // A breakdown only says something new when there is more than one target
+        if self.targets.len() > 1 {
+            for (target, counts) in &self.targets {
+                let free_space = counts.free_space.map(|free_space| format!(", {} free", HumanBytes(free_space))).unwrap_or_default();
+                info!("  {:?}: {} copied, {} skipped, {} already done, {} failed, {} bytes{}",
+                    target, counts.copied, counts.skipped, counts.resumed, counts.failed, counts.bytes_transferred, free_space);
+            }
+        }
+
+        if self.not_started > 0 {
+            warn!("Interrupted: {} files were not started", self.not_started);
+        }
+        if self.skipped_for_space > 0 {
+            warn!("Free-space margin reached: {} files were skipped for space", self.skipped_for_space);
+        }
+
+        for (category, paths) in &self.failures {
+            error!("{} files failed ({}):", paths.len(), category);
+            for path in paths.iter().take(LISTED_PER_CATEGORY) {
+                error!("  {:?}", path);
+            }
+            if paths.len() > LISTED_PER_CATEGORY {
+                error!("  ... and {} more", paths.len() - LISTED_PER_CATEGORY);
+            }
+        }
+
+        match self.status() {
+            RunStatus::Complete => info!("Done: every file copied"),
+            RunStatus::Skipped => warn!("Done, with {} files skipped", self.skipped + self.unstable + self.skipped_for_space + self.skipped_invalid),
+            RunStatus::Failed => error!("Done, with {} files failed", self.failed),
+            // Already warned about above
+            RunStatus::Interrupted => {}
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
+
+// What a `{token}` in a destination template stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    RelPath,
+    Dir,
+    BaseName,
+    Stem,
+    Ext,
+    Year,
+    Month,
+}
+
+impl Token {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "relpath" => Token::RelPath,
+            "dir" => Token::Dir,
+            "basename" => Token::BaseName,
+            "stem" => Token::Stem,
+            "ext" => Token::Ext,
+            "year" => Token::Year,
+            "month" => Token::Month,
+            _ => return None,
+        })
+    }
+
+    // Whether the token ends in the file's own name, so it can name the destination
+    fn names_file(self) -> bool {
+        matches!(self, Token::RelPath | Token::BaseName | Token::Stem)
+    }
+}
+
+// What a `{token}` in a rename pattern stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameToken {
+    Stem,
+    Ext,
+    N,
+    Timestamp,
+}
+
+impl NameToken {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "stem" => NameToken::Stem,
+            "ext" => NameToken::Ext,
+            "n" => NameToken::N,
+            "timestamp" => NameToken::Timestamp,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece<T> {
+    Literal(String),
+    Token(T),
+}
+
+// Function to split template text into literals and the tokens `parse` recognises
+fn parse_pieces<T>(text: &str, parse: impl Fn(&str) -> Option<T>) -> Result<Vec<Piece<T>>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        match rest.find(['{', '}']) {
+            Some(0) if rest.starts_with('{') => {
+                let end = rest.find('}').ok_or_else(|| format!("unclosed `{{` in {:?}", text))?;
+                let name = &rest[1..end];
+                let token = parse(name).ok_or_else(|| format!("unknown token `{{{}}}` in {:?}", name, text))?;
+                pieces.push(Piece::Token(token));
+                rest = &rest[end + 1..];
+            }
+            Some(0) => return Err(format!("unmatched `}}` in {:?}", text)),
+            Some(start) => {
+                pieces.push(Piece::Literal(rest[..start].to_string()));
+                rest = &rest[start..];
+            }
+            None => {
+                pieces.push(Piece::Literal(rest.to_string()));
+                rest = "";
+            }
+        }
+    }
+    Ok(pieces)
+}
+
+/// Where a file lands below the target, built from its path relative to the
+/// source and its modification time, e.g. `{year}/{month}/{basename}`.
+///
+/// Tokens: `{relpath}` (e.g. `docs/2023/report.pdf`), `{dir}` (`docs/2023`,
+/// empty for files directly in the source), `{basename}` (`report.pdf`),
+/// `{stem}` (`report`), `{ext}` (`pdf`, empty without one) and `{year}` and
+/// `{month}` (`2023`, `07`) of the file's modification time in UTC. `/`
+/// separates folders and empty folders are dropped, so `{dir}/{basename}`
+/// suits files in the root too. The template is checked when it is parsed:
+/// it must be relative, stay inside the target and end in the file's name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DestTemplate {
+    text: String,
+    pieces: Vec<Piece<Token>>,
+}
+
+// Function to check whether a character separates folders in a template on this platform
+fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+impl FromStr for DestTemplate {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let pieces = parse_pieces(text, Token::parse)?;
+
+        // Literal text can't climb out of the target or make the path absolute
+        let path = Path::new(text);
+        if text.starts_with(is_separator) || path.has_root() || matches!(path.components().next(), Some(Component::Prefix(_))) {
+            return Err(format!("{:?} must be relative to the target", text));
+        }
+        let climbs_out = pieces.iter()
+            .filter_map(|piece| match piece { Piece::Literal(literal) => Some(literal), Piece::Token(_) => None })
+            .flat_map(|literal| literal.split(is_separator))
+            .any(|segment| segment == "..");
+        if climbs_out {
+            return Err(format!("{:?} must stay inside the target, without `..`", text));
+        }
+
+        // Every file needs a name of its own, or they would all land on one path
+        let names_file = pieces.iter().rev()
+            .take_while(|piece| !matches!(piece, Piece::Literal(literal) if literal.contains(is_separator)))
+            .any(|piece| matches!(piece, Piece::Token(token) if token.names_file()));
+        if !names_file {
+            return Err(format!("{:?} must end in {{relpath}}, {{basename}} or {{stem}}, so each file gets its own name", text));
+        }
+
+        Ok(DestTemplate { text: text.to_string(), pieces })
+    }
+}
+
+impl TryFrom<String> for DestTemplate {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for DestTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Function to add the parts of a path to the segments rendered so far: the first part
+// continues the current segment, and each one after it starts a new folder
+fn push_parts<'a>(segments: &mut Vec<OsString>, parts: impl IntoIterator<Item = &'a OsStr>) {
+    for (i, part) in parts.into_iter().enumerate() {
+        if i > 0 {
+            segments.push(OsString::new());
+        }
+        segments.last_mut().unwrap().push(part);
+    }
+}
+
+impl DestTemplate {
+    fn uses_date(&self) -> bool {
+        self.pieces.iter().any(|piece| matches!(piece, Piece::Token(Token::Year | Token::Month)))
+    }
+
+    /// Fill in the template for a file at `relative_path` below the source,
+    /// where the file itself is at `source`. The modification time is only
+    /// read if the template uses it.
+    pub fn render(&self, relative_path: &Path, source: &Path) -> io::Result<PathBuf> {
+        // RFC 3339 starts with the date, e.g. 2023-07-14T... Times before 1970 can't be
+        // formatted, and count as 1970.
+        let date = if self.uses_date() {
+            let modified = fs::metadata(source)?.modified()?.max(UNIX_EPOCH);
+            humantime::format_rfc3339_seconds(modified).to_string()
+        } else {
+            String::new()
+        };
+
+        // Names are built up as OsStrings, so file names that aren't valid UTF-8 survive
+        let mut segments = vec![OsString::new()];
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(literal) => push_parts(&mut segments, literal.split(is_separator).map(OsStr::new)),
+                Piece::Token(Token::RelPath) => push_parts(&mut segments, relative_path.iter()),
+                Piece::Token(Token::Dir) => push_parts(&mut segments, relative_path.parent().unwrap_or(Path::new("")).iter()),
+                Piece::Token(Token::BaseName) => push_parts(&mut segments, relative_path.file_name()),
+                Piece::Token(Token::Stem) => push_parts(&mut segments, relative_path.file_stem()),
+                Piece::Token(Token::Ext) => push_parts(&mut segments, relative_path.extension()),
+                Piece::Token(Token::Year) => push_parts(&mut segments, [OsStr::new(&date[0..4])]),
+                Piece::Token(Token::Month) => push_parts(&mut segments, [OsStr::new(&date[5..7])]),
+            }
+        }
+        Ok(segments.into_iter().filter(|segment| !segment.is_empty()).collect())
+    }
+}
+
+/// The free name a colliding file is given under `--on-conflict rename`, e.g.
+/// the default `{stem} ({n}){ext}` turns `report.pdf` into `report (1).pdf`.
+///
+/// Tokens: `{stem}` (`report`), `{ext}` (`.pdf`, with the dot, or empty),
+/// `{n}` (1, 2, ... until a name is free) and `{timestamp}` (the time of the
+/// copy in UTC, e.g. `20240101T120000Z`). The pattern must contain `{n}`, so
+/// every attempt gives a new name and the search always ends, and it names a
+/// file next to the original, so it can't contain a folder separator.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RenamePattern {
+    text: String,
+    pieces: Vec<Piece<NameToken>>,
+}
+
+impl Default for RenamePattern {
+    fn default() -> Self {
+        "{stem} ({n}){ext}".parse().unwrap()
+    }
+}
+
+impl FromStr for RenamePattern {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let pieces = parse_pieces(text, NameToken::parse)?;
+        if text.contains(['/', '\\']) {
+            return Err(format!("{:?} must name a file next to the original, without folders", text));
+        }
+        if !pieces.contains(&Piece::Token(NameToken::N)) {
+            return Err(format!("{:?} must contain {{n}}, e.g. {{stem}}_{{timestamp}}_{{n}}{{ext}}, so a free name is always found", text));
+        }
+        Ok(RenamePattern { text: text.to_string(), pieces })
+    }
+}
+
+impl TryFrom<String> for RenamePattern {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        text.parse()
+    }
+}
+
+impl fmt::Display for RenamePattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl RenamePattern {
+    /// The names to try, in order, for a file whose destination `path` is
+    /// taken, each next to it. The sequence never ends.
+    pub fn candidates<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        // One timestamp for the whole search, so only {n} changes between attempts
+        let timestamp = if self.pieces.contains(&Piece::Token(NameToken::Timestamp)) {
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string().replace(['-', ':'], "")
+        } else {
+            String::new()
+        };
+        let stem = path.file_stem().unwrap_or_default();
+        let extension = path.extension().map(|ext| {
+            let mut with_dot = OsString::from(".");
+            with_dot.push(ext);
+            with_dot
+        }).unwrap_or_default();
+
+        (1usize..).map(move |n| {
+            let mut name = OsString::new();
+            for piece in &self.pieces {
+                match piece {
+                    Piece::Literal(literal) => name.push(literal),
+                    Piece::Token(NameToken::Stem) => name.push(stem),
+                    Piece::Token(NameToken::Ext) => name.push(&extension),
+                    Piece::Token(NameToken::N) => name.push(n.to_string()),
+                    Piece::Token(NameToken::Timestamp) => name.push(&timestamp),
+                }
+            }
+            path.with_file_name(name)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::FileTime;
+
+    fn render(template: &str, relative_path: &str) -> PathBuf {
+        template.parse::<DestTemplate>().unwrap().render(Path::new(relative_path), Path::new("unused")).unwrap()
+    }
+
+    #[test]
+    fn fills_in_path_tokens() {
+        assert_eq!(render("{relpath}", "docs/2023/report.pdf"), Path::new("docs/2023/report.pdf"));
+        assert_eq!(render("by-type/{ext}/{basename}", "docs/report.pdf"), Path::new("by-type/pdf/report.pdf"));
+        assert_eq!(render("{dir}/{stem}-copy.{ext}", "docs/report.pdf"), Path::new("docs/report-copy.pdf"));
+    }
+
+    #[test]
+    fn drops_empty_folders() {
+        assert_eq!(render("{dir}/{basename}", "top.txt"), Path::new("top.txt"));
+        assert_eq!(render("{ext}/{basename}", "docs/README"), Path::new("README"));
+    }
+
+    #[test]
+    fn dates_come_from_the_modification_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("photo.jpg");
+        fs::write(&file, "").unwrap();
+        // 2023-07-14 UTC
+        filetime::set_file_mtime(&file, FileTime::from_unix_time(1_689_300_000, 0)).unwrap();
+
+        let template: DestTemplate = "{year}/{month}/{relpath}".parse().unwrap();
+        assert_eq!(template.render(Path::new("camera/photo.jpg"), &file).unwrap(), Path::new("2023/07/camera/photo.jpg"));
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        for template in ["{relpath", "{relpath}}", "{size}/{basename}", "/abs/{basename}", "../{relpath}", "{year}/{month}", "{basename}/index"] {
+            assert!(template.parse::<DestTemplate>().is_err(), "{:?} should be rejected", template);
+        }
+    }
+
+    #[test]
+    fn rename_patterns_count_up_from_one() {
+        let names = |pattern: &str, path: &str| -> Vec<PathBuf> {
+            pattern.parse::<RenamePattern>().unwrap().candidates(Path::new(path)).take(2).collect()
+        };
+        assert_eq!(names("{stem} ({n}){ext}", "docs/report.pdf"), [Path::new("docs/report (1).pdf"), Path::new("docs/report (2).pdf")]);
+        assert_eq!(names("{stem}_dup{n}{ext}", "README"), [Path::new("README_dup1"), Path::new("README_dup2")]);
+
+        let stamped = names("{stem}_{timestamp}_{n}{ext}", "photo.jpg");
+        let name = stamped[0].to_str().unwrap();
+        assert!(name.starts_with("photo_20") && name.ends_with("Z_1.jpg"), "{:?}", name);
+    }
+
+    #[test]
+    fn rejects_rename_patterns_that_could_repeat() {
+        for pattern in ["{stem}_{timestamp}{ext}", "{stem}_dup{ext}", "dups/{stem}_{n}{ext}", "{stem}_{size}{ext}"] {
+            assert!(pattern.parse::<RenamePattern>().is_err(), "{:?} should be rejected", pattern);
+        }
+    }
+}
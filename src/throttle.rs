@@ -0,0 +1,45 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A token bucket shared by every copy worker, so the limit applies to the whole run
+// rather than to each file. Holds up to one second's worth of bytes as burst.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        RateLimiter {
+            bytes_per_sec,
+            state: Mutex::new(Bucket { tokens: bytes_per_sec, last_refill: Instant::now() }),
+        }
+    }
+
+    // Function to take `bytes` from the bucket, sleeping until the rate allows them.
+    // The bucket may go into debt, which later callers wait off in turn.
+    pub(crate) fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec);
+            bucket.last_refill = now;
+
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+        };
+        // Sleep without the lock so other workers can queue behind us
+        thread::sleep(wait);
+    }
+}
@@ -0,0 +1,107 @@
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tracing::Span;
+
+use crate::error::MigrateError;
+
+// Most timed-out operations left running at once. Past this no more are started, so a
+// provider that has stopped answering can't pile up threads without bound.
+const MAX_ABANDONED: usize = 16;
+
+// Threads of operations that timed out and may still be running
+static ABANDONED: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+// Set once the caller has given up on an operation, so one that is still running stops
+// at its next chunk instead of reading the rest of a file nobody will use
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    // Function to fail with a timeout error once the operation has been given up on
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.0.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "given up on after the time limit"));
+        }
+        Ok(())
+    }
+}
+
+// Function to run a blocking file operation with an optional time limit.
+// The operation runs on its own thread. If it outlives the limit the file is reported as
+// timed out, so the worker can move on to other files, and `Cancel` tells the operation to
+// stop at its next chunk. A read that never returns can't be cancelled, though: its thread
+// leaks, holding the file open, until the read does return or the process exits. Up to
+// MAX_ABANDONED such threads are allowed; after that operations fail without starting.
+pub(crate) fn with_timeout<T, F>(path: &Path, timeout: Option<Duration>, job: F) -> Result<T, MigrateError>
+where
+    T: Send + 'static,
+    F: FnOnce(&Cancel) -> io::Result<T> + Send + 'static,
+{
+    let cancel = Cancel::default();
+    let Some(timeout) = timeout else {
+        return Ok(job(&cancel)?);
+    };
+
+    let mut abandoned = ABANDONED.lock().unwrap();
+    abandoned.retain(|handle| !handle.is_finished());
+    if abandoned.len() >= MAX_ABANDONED {
+        return Err(io::Error::other(format!("{} timed-out file operations are still stuck, not starting another", abandoned.len())).into());
+    }
+    drop(abandoned);
+
+    let (sender, receiver) = mpsc::channel();
+    // Log lines from the job belong to the same file span as the caller's
+    let span = Span::current();
+    let handle = {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            let _entered = span.enter();
+            // The receiver is gone if we already gave up on this job
+            let _ = sender.send(job(&cancel));
+        })
+    };
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Ok(result?),
+        Err(RecvTimeoutError::Timeout) => {
+            cancel.0.store(true, Ordering::Relaxed);
+            ABANDONED.lock().unwrap().push(handle);
+            Err(MigrateError::FileTimeout { path: path.to_path_buf(), timeout })
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other("file operation thread panicked").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn a_timed_out_job_stops_at_its_next_check() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let result = {
+            let stopped = Arc::clone(&stopped);
+            with_timeout(Path::new("slow.bin"), Some(Duration::from_millis(20)), move |cancel| {
+                let checked = (|| loop {
+                    cancel.check()?;
+                    thread::sleep(Duration::from_millis(1));
+                })();
+                stopped.store(true, Ordering::Relaxed);
+                checked
+            })
+        };
+        assert!(matches!(result, Err::<(), _>(MigrateError::FileTimeout { .. })));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !stopped.load(Ordering::Relaxed) {
+            assert!(Instant::now() < deadline, "the job kept running after the timeout");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, DirEntry};
+use std::io;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+use indicatif::ProgressBar;
+use rayon::prelude::*;
+use tracing::{debug, info, warn};
+
+use crate::config::{is_inside, FileOrder, SymlinkPolicy};
+use crate::error::MigrateError;
+use crate::filter::{FileFilter, IgnoreChain};
+use crate::get_file_size;
+
+// Identity of a directory, used to notice when a symlink or bind mount leads back to one already walked
+#[cfg(unix)]
+type DirKey = (u64, u64);
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+// Function to identify a directory by device and inode
+#[cfg(unix)]
+fn dir_key(path: &Path) -> io::Result<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+// Function to identify a directory by its canonical path
+#[cfg(not(unix))]
+fn dir_key(path: &Path) -> io::Result<DirKey> {
+    fs::canonicalize(path)
+}
+
+// State shared by every level of the (parallel) recursive walk
+pub(crate) struct WalkContext<'a> {
+    source_root: &'a Path,
+    filter: &'a FileFilter,
+    symlinks: SymlinkPolicy,
+    // Files dropped by the filter
+    pub(crate) excluded: AtomicUsize,
+    // Files and directories found so far, shown on the spinner while the walk runs
+    pub(crate) files_found: AtomicUsize,
+    pub(crate) dirs_found: AtomicUsize,
+    pub(crate) spinner: Option<ProgressBar>,
+    // Deepest level still read; files directly in the source are at depth 0
+    pub(crate) max_depth: Option<usize>,
+    visited: Mutex<HashSet<DirKey>>,
+}
+
+impl<'a> WalkContext<'a> {
+    pub(crate) fn new(source_root: &'a Path, filter: &'a FileFilter, symlinks: SymlinkPolicy) -> Self {
+        let ctx = WalkContext {
+            source_root,
+            filter,
+            symlinks,
+            excluded: AtomicUsize::new(0),
+            files_found: AtomicUsize::new(0),
+            dirs_found: AtomicUsize::new(0),
+            spinner: None,
+            max_depth: None,
+            visited: Mutex::new(HashSet::new()),
+        };
+        ctx.first_visit(source_root);
+        ctx
+    }
+
+    // Function to record a directory as visited, returning false if it already was.
+    // Directories that can't be identified are always walked.
+    fn first_visit(&self, dir: &Path) -> bool {
+        match dir_key(dir) {
+            Ok(key) => self.visited.lock().unwrap().insert(key),
+            Err(_) => true,
+        }
+    }
+
+    // Function to count what one directory turned up and refresh the spinner
+    fn found(&self, files: usize, dirs: usize) {
+        let files = self.files_found.fetch_add(files, Ordering::Relaxed) + files;
+        let dirs = self.dirs_found.fetch_add(dirs, Ordering::Relaxed) + dirs;
+        if let Some(spinner) = &self.spinner {
+            spinner.set_message(format!("Discovered {} files / {} dirs", files, dirs));
+        }
+    }
+}
+
+// Function to read one directory, splitting its entries into files to copy and
+// subdirectories still to be walked
+fn read_directory(dir: &Path, ignores: &IgnoreChain, ctx: &WalkContext) -> io::Result<(Vec<DirEntry>, Vec<PathBuf>, IgnoreChain)> {
+    let mut files = vec![];
+    let mut subdirs = vec![];
+    let ignores = ignores.enter(dir);
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative_path = path.strip_prefix(ctx.source_root).unwrap_or(&path);
+
+        if ignores.ignores(&path, path.is_dir()) {
+            if !path.is_dir() {
+                ctx.excluded.fetch_add(1, Ordering::Relaxed);
+            }
+            info!("Ignoring {:?}", path);
+            continue;
+        }
+
+        if ctx.filter.skips_hidden(&entry) {
+            if !path.is_dir() {
+                ctx.excluded.fetch_add(1, Ordering::Relaxed);
+            }
+            info!("Skipping hidden {:?}", path);
+            continue;
+        }
+
+        if entry.file_type().is_ok_and(|file_type| file_type.is_symlink()) {
+            match ctx.symlinks {
+                SymlinkPolicy::Skip => {
+                    info!("Skipping symlink {:?}", path);
+                    continue;
+                }
+                // The link itself is copied like a file and never descended into
+                SymlinkPolicy::CopyAsLink => {
+                    if ctx.filter.allows(relative_path, false) {
+                        files.push(entry);
+                    } else {
+                        ctx.excluded.fetch_add(1, Ordering::Relaxed);
+                        info!("Excluding {:?}", path);
+                    }
+                    continue;
+                }
+                SymlinkPolicy::Follow => {}
+            }
+        }
+
+        if path.is_dir() && ctx.filter.prunes(relative_path) {
+            debug!("Pruning directory {:?}", path);
+            continue;
+        }
+
+        if !ctx.filter.allows(relative_path, path.is_dir()) || (path.is_file() && !(ctx.filter.allows_size(&entry) && ctx.filter.allows_modified(&entry))) {
+            if path.is_file() {
+                ctx.excluded.fetch_add(1, Ordering::Relaxed);
+            }
+            info!("Excluding {:?}", path);
+        } else if path.is_file() {
+            files.push(entry);
+        } else if path.is_dir() {
+            if ctx.first_visit(&path) {
+                subdirs.push(path);
+            } else {
+                warn!("Skipping {:?}: directory already visited (symlink loop or bind mount?)", path);
+            }
+        }
+    }
+
+    ctx.found(files.len(), subdirs.len());
+    Ok((files, subdirs, ignores))
+}
+
+// Function to read a --source-list file of newline-separated paths relative to the
+// source. Blank lines are ignored; paths that would leave the source are reported
+// and dropped.
+pub(crate) fn read_source_list(path: &Path, source_root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for line in fs::read_to_string(path)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let relative_path = Path::new(line);
+        if is_inside(relative_path) {
+            paths.push(source_root.join(relative_path));
+        } else {
+            warn!("Skipping {:?} from {:?}: not a relative path inside the source", line, path);
+        }
+    }
+    Ok(paths)
+}
+
+// Function to look up the entries for specific files without walking the whole tree,
+// reading only their parent directories. Files that no longer exist, or lie outside
+// the source, are reported and left out.
+pub(crate) fn entries_for(paths: &[PathBuf], source_root: &Path) -> Vec<DirEntry> {
+    let mut by_parent: BTreeMap<&Path, HashSet<&OsStr>> = BTreeMap::new();
+    for path in paths {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if path.starts_with(source_root) => {
+                by_parent.entry(parent).or_default().insert(name);
+            }
+            _ => warn!("Skipping {:?}: not inside the source {:?}", path, source_root),
+        }
+    }
+
+    by_parent.into_par_iter()
+        .flat_map_iter(|(parent, names)| {
+            let found: Vec<DirEntry> = match fs::read_dir(parent) {
+                Ok(entries) => entries.filter_map(Result::ok)
+                    .filter(|entry| names.contains(entry.file_name().as_os_str()))
+                    .collect(),
+                Err(e) => {
+                    warn!("Failed to read directory {:?}: {}", parent, e);
+                    Vec::new()
+                }
+            };
+            if found.len() < names.len() {
+                warn!("{} requested files in {:?} do not exist", names.len() - found.len(), parent);
+            }
+            found
+        })
+        .collect()
+}
+
+// Function to sort files into the order they are processed in. Sizes and times are
+// read once per file rather than on every comparison.
+pub(crate) fn sort_files(files: &mut [DirEntry], order: FileOrder) {
+    match order {
+        FileOrder::Largest => files.par_sort_by_cached_key(|file| Reverse(get_file_size(file))),
+        FileOrder::Smallest => files.par_sort_by_cached_key(get_file_size),
+        FileOrder::Newest => files.par_sort_by_cached_key(|file| Reverse(modified(file))),
+        FileOrder::Oldest => files.par_sort_by_cached_key(modified),
+        FileOrder::Name => files.par_sort_by_key(DirEntry::path),
+    }
+}
+
+// Function to get a file's modification time, if it can be read
+fn modified(file: &DirEntry) -> Option<SystemTime> {
+    file.metadata().and_then(|metadata| metadata.modified()).ok()
+}
+
+// Function to visit directories and collect files and directories. The tree is walked
+// one level at a time with an explicit queue, so deep trees can't overflow the stack;
+// the directories at each level are read in parallel.
+// Excluded directories are not descended into; filtered-out files are tallied in `ctx.excluded`
+pub(crate) fn visit_dirs(root: &Path, ctx: &WalkContext) -> Result<(Vec<DirEntry>, Vec<PathBuf>), MigrateError> {
+    if !root.is_dir() {
+        return Err(MigrateError::SourceNotDirectory { path: root.to_path_buf() });
+    }
+
+    // The root must be readable; unreadable subdirectories are reported and skipped.
+    // Each pending directory carries the .xeroxignore files of its ancestors.
+    let (mut files, subdirs, ignores) = read_directory(root, &IgnoreChain::default(), ctx)?;
+    let mut pending: Vec<(PathBuf, IgnoreChain)> = subdirs.into_iter().map(|dir| (dir, ignores.clone())).collect();
+    let mut dirs = vec![];
+    let mut depth = 0;
+
+    while !pending.is_empty() {
+        // Each pass of the loop reads one level deeper than the last
+        depth += 1;
+        if ctx.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            info!("Not descending into {} directories below --max-depth", pending.len());
+            break;
+        }
+
+        let level: Vec<_> = pending.par_iter()
+            .map(|(dir, ignores)| read_directory(dir, ignores, ctx))
+            .collect();
+
+        let mut next = vec![];
+        for ((dir, _), result) in pending.into_iter().zip(level) {
+            match result {
+                Ok((dir_files, subdirs, ignores)) => {
+                    files.extend(dir_files);
+                    next.extend(subdirs.into_iter().map(|subdir| (subdir, ignores.clone())));
+                }
+                Err(e) => warn!("Failed to read directory {:?}: {}", dir, e),
+            }
+            dirs.push(dir);
+        }
+        pending = next;
+    }
+
+    // No path can turn up twice: a directory reached again through a followed symlink or
+    // bind mount is skipped by its dev/inode above, and its other paths are different
+    // paths. Sorting just keeps the order, and so ties under --order, the same every run.
+    files.par_sort_by_key(DirEntry::path);
+
+    dirs.par_sort_unstable();
+    dirs.dedup();
+
+    Ok((files, dirs))
+}
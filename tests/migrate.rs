@@ -0,0 +1,998 @@
+//! End-to-end runs of the migration against temporary source and target trees.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use filetime::FileTime;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use xerox::{ArchiveFormat, Config, ConflictPolicy, CopyOnly, MigrateError, Migrator, RunStatus, Summary};
+
+// A source and a (not yet created) target inside one temp directory
+struct Fixture {
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("box")).unwrap();
+        Fixture { dir }
+    }
+
+    fn source(&self) -> PathBuf {
+        self.dir.path().join("box")
+    }
+
+    fn target(&self) -> PathBuf {
+        self.dir.path().join("onedrive")
+    }
+
+    // Function to write a file below the source, creating its parents
+    fn add(&self, relative_path: &str, contents: impl AsRef<[u8]>) {
+        write(&self.source().join(relative_path), contents);
+    }
+
+    fn config(&self) -> Config {
+        quiet(Config::new(self.source(), self.target()))
+    }
+}
+
+// Function to turn off progress bars, which would only clutter test output
+fn quiet(mut config: Config) -> Config {
+    config.show_progress = false;
+    config
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+}
+
+fn run(config: Config) -> Summary {
+    Migrator::new(config).unwrap().run().unwrap()
+}
+
+// Function to read every file below `root` into a map of relative path to contents
+fn tree(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.insert(path.strip_prefix(root).unwrap().to_path_buf(), fs::read(&path).unwrap());
+            }
+        }
+    }
+    files
+}
+
+// Function to set a file's modification time to `secs` seconds before now
+fn age(path: &Path, secs: u64) {
+    let time = SystemTime::now() - Duration::from_secs(secs);
+    filetime::set_file_mtime(path, FileTime::from_system_time(time)).unwrap();
+}
+
+#[test]
+fn copies_the_whole_tree() {
+    let fixture = Fixture::new();
+    fixture.add("top.txt", "top");
+    fixture.add("a/b/c/nested.txt", "nested");
+    fixture.add("a/empty.txt", "");
+    fixture.add("big/large.bin", vec![7u8; 3 * 1024 * 1024 + 17]);
+    fixture.add("one/copy.txt", "same contents");
+    fixture.add("two/copy.txt", "same contents");
+
+    let summary = run(fixture.config());
+
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert_eq!(summary.copied, 6);
+    assert_eq!(summary.failed, 0);
+    assert_eq!(summary.status(), RunStatus::Complete);
+    assert_eq!(summary.bytes_transferred, (3 + 6 + 3 * 1024 * 1024 + 17 + 2 * 13) as u64);
+}
+
+#[test]
+fn creates_empty_directories() {
+    let fixture = Fixture::new();
+    fs::create_dir_all(fixture.source().join("empty/inner")).unwrap();
+    fixture.add("filtered/scratch.tmp", "temp");
+    let mut config = fixture.config();
+    config.exclude = vec!["*.tmp".to_string()];
+
+    run(config);
+
+    assert!(fixture.target().join("empty/inner").is_dir());
+    assert!(fixture.target().join("filtered").is_dir());
+}
+
+#[test]
+fn prunes_directories_left_empty() {
+    let fixture = Fixture::new();
+    fs::create_dir_all(fixture.source().join("empty/inner")).unwrap();
+    fixture.add("filtered/scratch.tmp", "temp");
+    fixture.add("kept/file.txt", "kept");
+    let mut config = fixture.config();
+    config.exclude = vec!["*.tmp".to_string()];
+    config.prune_empty_dirs = true;
+
+    run(config);
+
+    assert!(!fixture.target().join("empty").exists());
+    assert!(!fixture.target().join("filtered").exists());
+    assert!(fixture.target().join("kept/file.txt").is_file());
+}
+
+#[test]
+fn creates_the_skeleton_first_and_the_files_later() {
+    let fixture = Fixture::new();
+    fs::create_dir_all(fixture.source().join("empty/inner")).unwrap();
+    fixture.add("docs/a.txt", "a");
+    fixture.add("top.txt", "top");
+    let mut config = fixture.config();
+    config.only = CopyOnly::Dirs;
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.left_for_later), (0, 2));
+    assert!(fixture.target().join("empty/inner").is_dir());
+    assert!(fixture.target().join("docs").is_dir());
+    assert!(tree(&fixture.target()).is_empty());
+
+    // Copying only the files into a fresh target leaves the empty directories out
+    fs::remove_dir_all(fixture.target()).unwrap();
+    let mut config = fixture.config();
+    config.only = CopyOnly::Files;
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.left_for_later), (2, 0));
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert!(!fixture.target().join("empty").exists());
+}
+
+#[test]
+fn copies_a_deep_tree() {
+    let fixture = Fixture::new();
+    let deep: PathBuf = std::iter::repeat_n("d", 300).collect();
+    fixture.add(deep.join("bottom.txt").to_str().unwrap(), "bottom");
+
+    run(fixture.config());
+
+    assert_eq!(fs::read(fixture.target().join(&deep).join("bottom.txt")).unwrap(), b"bottom");
+}
+
+#[test]
+fn creates_shared_directories_from_many_workers() {
+    let fixture = Fixture::new();
+    for i in 0..16 {
+        for j in 0..8 {
+            fixture.add(&format!("shared/deeper/still/dir{}/file{}.txt", i, j), format!("{} {}", i, j));
+        }
+    }
+    let mut config = fixture.config();
+    config.walk_threads = Some(8);
+    config.copy_threads = Some(8);
+
+    let summary = run(config);
+
+    assert_eq!(summary.failed, 0);
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+}
+
+#[test]
+fn skips_existing_files_by_default() {
+    let fixture = Fixture::new();
+    fixture.add("report.txt", "new");
+    write(&fixture.target().join("report.txt"), "old");
+
+    let summary = run(fixture.config());
+
+    assert_eq!(fs::read(fixture.target().join("report.txt")).unwrap(), b"old");
+    assert_eq!(summary.skipped, 1);
+    assert_eq!(summary.copied, 0);
+    assert_eq!(summary.status(), RunStatus::Skipped);
+}
+
+#[test]
+fn overwrites_existing_files() {
+    let fixture = Fixture::new();
+    fixture.add("report.txt", "new");
+    write(&fixture.target().join("report.txt"), "old");
+    let mut config = fixture.config();
+    config.on_conflict = ConflictPolicy::Overwrite;
+
+    let summary = run(config);
+
+    assert_eq!(fs::read(fixture.target().join("report.txt")).unwrap(), b"new");
+    assert_eq!(summary.overwritten, 1);
+}
+
+#[test]
+fn overwrites_only_changed_files() {
+    let fixture = Fixture::new();
+    fixture.add("changed.txt", "new");
+    fixture.add("unchanged.txt", "same");
+    write(&fixture.target().join("changed.txt"), "old");
+    age(&fixture.target().join("changed.txt"), 3600);
+    // A previous copy keeps the source's size and time
+    fs::copy(fixture.source().join("unchanged.txt"), fixture.target().join("unchanged.txt")).unwrap();
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(fixture.source().join("unchanged.txt")).unwrap());
+    filetime::set_file_mtime(fixture.target().join("unchanged.txt"), mtime).unwrap();
+    let mut config = fixture.config();
+    config.on_conflict = ConflictPolicy::OverwriteIfNewer;
+
+    let summary = run(config);
+
+    assert_eq!(fs::read(fixture.target().join("changed.txt")).unwrap(), b"new");
+    assert_eq!(summary.overwritten, 1);
+    assert_eq!(summary.skipped, 1);
+}
+
+#[test]
+fn renames_around_existing_files() {
+    let fixture = Fixture::new();
+    fixture.add("report.txt", "new");
+    write(&fixture.target().join("report.txt"), "old");
+    let mut config = fixture.config();
+    config.on_conflict = ConflictPolicy::Rename;
+
+    let summary = run(config);
+
+    assert_eq!(fs::read(fixture.target().join("report.txt")).unwrap(), b"old");
+    assert_eq!(fs::read(fixture.target().join("report (1).txt")).unwrap(), b"new");
+    assert_eq!(summary.renamed, 1);
+}
+
+#[test]
+fn names_many_collisions_by_the_rename_pattern() {
+    let fixture = Fixture::new();
+    for i in 0..30 {
+        fixture.add(&format!("{}/report.txt", i), i.to_string());
+    }
+    let mut config = fixture.config();
+    config.flatten = true;
+    config.rename_pattern = "{stem}_dup{n}{ext}".parse().unwrap();
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.renamed), (30, 29));
+    let names: Vec<PathBuf> = tree(&fixture.target()).into_keys().collect();
+    assert_eq!(names.len(), 30);
+    assert!(names.contains(&PathBuf::from("report.txt")) && names.contains(&PathBuf::from("report_dup29.txt")));
+}
+
+#[test]
+fn flattening_again_leaves_earlier_copies_alone() {
+    let fixture = Fixture::new();
+    fixture.add("a/report.txt", "first");
+    fixture.add("b/report.txt", "second report");
+    fixture.add("c/notes.txt", "notes");
+    let mut config = fixture.config();
+    config.flatten = true;
+    run(config.clone());
+    let first = tree(&fixture.target());
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.skipped, summary.renamed), (0, 3, 0));
+    assert_eq!(tree(&fixture.target()), first);
+    assert_eq!(first.len(), 3);
+}
+
+#[test]
+fn leaves_out_excluded_files_and_directories() {
+    let fixture = Fixture::new();
+    fixture.add("keep.txt", "keep");
+    fixture.add("scratch.tmp", "temp");
+    fixture.add("sub/also.tmp", "temp");
+    fixture.add("node_modules/pkg/index.js", "js");
+    let mut config = fixture.config();
+    config.exclude = vec!["*.tmp".to_string()];
+    config.exclude_dir = vec!["node_modules".to_string()];
+
+    let summary = run(config);
+
+    let copied: Vec<PathBuf> = tree(&fixture.target()).into_keys().collect();
+    assert_eq!(copied, vec![PathBuf::from("keep.txt")]);
+    assert_eq!(summary.excluded, 2);
+}
+
+#[test]
+fn copies_hidden_files_unless_told_not_to() {
+    let fixture = Fixture::new();
+    fixture.add("visible.txt", "seen");
+    fixture.add(".hidden", "unseen");
+    fixture.add(".git/config", "unseen");
+
+    run(fixture.config());
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+
+    let skipping = Fixture::new();
+    skipping.add("visible.txt", "seen");
+    skipping.add(".hidden", "unseen");
+    skipping.add(".git/config", "unseen");
+    let mut config = skipping.config();
+    config.skip_hidden = true;
+
+    run(config);
+    let copied: Vec<PathBuf> = tree(&skipping.target()).into_keys().collect();
+    assert_eq!(copied, vec![PathBuf::from("visible.txt")]);
+    assert!(!skipping.target().join(".git").exists());
+}
+
+#[test]
+fn resolves_dot_dot_in_the_source_root() {
+    let fixture = Fixture::new();
+    fixture.add("sub/file.txt", "contents");
+    let roundabout = fixture.dir.path().join(".").join("box").join("..").join("box");
+    let config = quiet(Config::new(roundabout, fixture.target()));
+
+    run(config);
+
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+}
+
+#[test]
+fn places_files_by_the_destination_template() {
+    let fixture = Fixture::new();
+    fixture.add("camera/photo.jpg", "jpg");
+    fixture.add("notes.txt", "txt");
+    fs::create_dir(fixture.source().join("empty")).unwrap();
+    // 2023-07-14 UTC
+    for file in ["camera/photo.jpg", "notes.txt"] {
+        filetime::set_file_mtime(fixture.source().join(file), FileTime::from_unix_time(1_689_300_000, 0)).unwrap();
+    }
+    let mut config = fixture.config();
+    config.dest_template = Some("{year}/{ext}/{basename}".parse().unwrap());
+
+    run(config);
+
+    let copied: Vec<PathBuf> = tree(&fixture.target()).into_keys().collect();
+    assert_eq!(copied, vec![PathBuf::from("2023/jpg/photo.jpg"), PathBuf::from("2023/txt/notes.txt")]);
+    // The source's folders have no place in a templated target
+    assert!(!fixture.target().join("empty").exists());
+}
+
+// Function to fill a source with same-named photos in separate folders, which routes and
+// templates all send to one destination, and a config copying them in parallel
+fn same_named_photos(fixture: &Fixture) -> Config {
+    for i in 0..200 {
+        fixture.add(&format!("dir{}/photo.jpg", i), format!("photo {}", i));
+    }
+    let mut config = fixture.config();
+    config.copy_threads = Some(16);
+    config
+}
+
+#[test]
+fn routes_same_named_files_to_one_folder_without_losing_any() {
+    let fixture = Fixture::new();
+    let mut config = same_named_photos(&fixture);
+    config.routes = [("jpg".to_string(), PathBuf::from("images"))].into();
+
+    config.on_conflict = ConflictPolicy::Rename;
+    let summary = run(config.clone());
+    assert_eq!((summary.copied, summary.failed), (200, 0));
+    let mut copied: Vec<Vec<u8>> = tree(&fixture.target().join("images")).into_values().collect();
+    copied.sort();
+    let mut sources: Vec<Vec<u8>> = tree(&fixture.source()).into_values().collect();
+    sources.sort();
+    assert_eq!(copied, sources);
+
+    // Without renaming only the first to arrive is copied, and the rest stay in the source
+    fs::remove_dir_all(fixture.target()).unwrap();
+    config.on_conflict = ConflictPolicy::Skip;
+    config.move_source = true;
+    let summary = run(config);
+    assert_eq!((summary.copied, summary.skipped, summary.failed), (1, 199, 0));
+    assert_eq!(tree(&fixture.target()).len(), 1);
+    assert_eq!(tree(&fixture.source()).len(), 199);
+}
+
+#[test]
+fn templates_same_named_files_to_one_folder_without_losing_any() {
+    let fixture = Fixture::new();
+    let mut config = same_named_photos(&fixture);
+    config.dest_template = Some("{year}/{basename}".parse().unwrap());
+    config.on_conflict = ConflictPolicy::Rename;
+
+    let summary = run(config.clone());
+    assert_eq!((summary.copied, summary.failed), (200, 0));
+    assert_eq!(tree(&fixture.target()).len(), 200);
+
+    // Running again finds every file's earlier copy
+    let summary = run(config);
+    assert_eq!((summary.copied, summary.skipped), (0, 200));
+    assert_eq!(tree(&fixture.target()).len(), 200);
+}
+
+// Hard links share an inode, which is how the copies are told apart from links here
+#[cfg(unix)]
+#[test]
+fn copies_identical_files_once_even_in_parallel() {
+    use std::os::unix::fs::MetadataExt;
+
+    let fixture = Fixture::new();
+    for i in 0..16 {
+        fixture.add(&format!("dir{}/photo.jpg", i), vec![9u8; 256 * 1024]);
+    }
+    let mut config = fixture.config();
+    config.dedup = true;
+    config.copy_threads = Some(8);
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.linked), (16, 15));
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    let inodes: std::collections::BTreeSet<u64> = (0..16)
+        .map(|i| fs::metadata(fixture.target().join(format!("dir{}/photo.jpg", i))).unwrap().ino())
+        .collect();
+    assert_eq!(inodes.len(), 1);
+}
+
+#[test]
+fn copies_to_every_target() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("sub/b.txt", "b");
+    let second = fixture.dir.path().join("nas");
+    let mut config = fixture.config();
+    config.extra_targets = vec![second.clone()];
+
+    let summary = run(config);
+
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert_eq!(tree(&second), tree(&fixture.source()));
+    assert_eq!(summary.copied, 4);
+    assert_eq!(summary.targets.len(), 2);
+}
+
+#[test]
+fn copies_to_each_target_from_its_own_queue() {
+    let fixture = Fixture::new();
+    for i in 0..20 {
+        fixture.add(&format!("dir{}/file{}.txt", i % 3, i), "x".repeat(i * 100));
+    }
+    let second = fixture.dir.path().join("nas");
+    let mut config = fixture.config();
+    config.extra_targets = vec![second.clone()];
+    config.workers_per_target = Some(2);
+
+    let summary = run(config);
+
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert_eq!(tree(&second), tree(&fixture.source()));
+    assert_eq!(summary.copied, 40);
+    assert_eq!(summary.failed, 0);
+    let total: u64 = (0..20).map(|i| i * 100).sum();
+    for per_target in summary.targets.values() {
+        assert_eq!(per_target.copied, 20);
+        assert_eq!(per_target.bytes_transferred, total);
+    }
+}
+
+#[test]
+fn resumes_into_the_same_manifest() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("sub/b.txt", "b");
+    let manifest = fixture.dir.path().join("manifest.jsonl");
+    let mut config = fixture.config();
+    config.manifest = Some(manifest.clone());
+    config.checkpoint_files = Some(1);
+
+    run(config.clone());
+    fixture.add("c.txt", "c");
+    config.resume = Some(manifest.clone());
+    let summary = run(config);
+
+    assert_eq!(summary.resumed, 2);
+    assert_eq!(summary.copied, 1);
+    let records: Vec<xerox::ManifestRecord> = fs::read_to_string(&manifest).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|record| record.outcome == xerox::Outcome::Copied));
+}
+
+#[test]
+fn resumes_from_a_manifest_cut_off_part_way_through_a_line() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("b.txt", "b");
+    fixture.add("c.txt", "c");
+    let manifest = fixture.dir.path().join("manifest.jsonl");
+    let mut config = fixture.config();
+    config.manifest = Some(manifest.clone());
+    run(config.clone());
+
+    // Cut the last record off half way, as a killed run would leave it
+    let contents = fs::read_to_string(&manifest).unwrap();
+    let last_line = contents.trim_end().rfind('\n').unwrap() + 1;
+    fs::write(&manifest, &contents[..last_line + 20]).unwrap();
+    config.resume = Some(manifest.clone());
+    let summary = run(config.clone());
+
+    assert_eq!(summary.resumed, 2);
+    assert_eq!(summary.skipped + summary.copied, 1);
+
+    // A bad line anywhere else is still an error
+    let contents = fs::read_to_string(&manifest).unwrap();
+    fs::write(&manifest, format!("{{\n{}", contents)).unwrap();
+    assert!(Migrator::new(config).unwrap().run().is_err());
+}
+
+#[test]
+fn records_the_source_hash_taken_while_copying() {
+    let fixture = Fixture::new();
+    fixture.add("hello.txt", "hello");
+    let manifest = fixture.dir.path().join("manifest.jsonl");
+    let mut config = fixture.config();
+    config.manifest = Some(manifest.clone());
+    config.copy_method = xerox::CopyMethod::Buffered;
+
+    run(config);
+
+    let record: xerox::ManifestRecord = serde_json::from_str(fs::read_to_string(&manifest).unwrap().trim()).unwrap();
+    assert_eq!(record.source_sha256.as_deref(), Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"));
+    // Without --verify the copy itself was never read back
+    assert_eq!(record.sha256, None);
+}
+
+#[test]
+fn resumes_partial_copies_that_match_the_source() {
+    let fixture = Fixture::new();
+    let contents: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    fixture.add("big.bin", &contents);
+    fixture.add("other.bin", &contents);
+    // As an interrupted run leaves them: one partial matching its source, one not
+    // Each partial is named for its destination and a hash of its source's path
+    let part = |name: &str| {
+        let hash: String = Sha256::digest(name).iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+        fixture.target().join(format!("{}.{}.xerox.part", name, hash))
+    };
+    write(&part("big.bin"), &contents[..150_000]);
+    write(&part("other.bin"), vec![0u8; 150_000]);
+    let mut config = fixture.config();
+    config.resume_partial = true;
+    config.verify = true;
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.failed), (2, 0));
+    assert_eq!(summary.bytes_transferred, 50_000 + 200_000);
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+}
+
+#[test]
+fn syncs_only_what_changed_since_a_manifest() {
+    let fixture = Fixture::new();
+    fixture.add("same.txt", "same");
+    fixture.add("sub/edited.txt", "before");
+    fixture.add("sub/gone.txt", "gone");
+    let manifest = fixture.dir.path().join("manifest.jsonl");
+    let mut config = fixture.config();
+    config.manifest = Some(manifest.clone());
+    run(config.clone());
+
+    fixture.add("sub/edited.txt", "after, and longer");
+    fixture.add("new.txt", "new");
+    fs::remove_file(fixture.source().join("sub/gone.txt")).unwrap();
+    config.since_manifest = Some(manifest.clone());
+    config.mirror = true;
+    let summary = run(config.clone());
+
+    assert_eq!(summary.copied, 2);
+    assert_eq!(summary.unchanged, 1);
+    assert_eq!(summary.deleted, 1);
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+
+    // The manifest written over the old one still covers every file, so nothing is left to do
+    let summary = run(config);
+    assert_eq!((summary.copied, summary.unchanged, summary.deleted), (0, 3, 0));
+}
+
+#[test]
+fn preserves_the_chosen_timestamps() {
+    let fixture = Fixture::new();
+    fixture.add("file.txt", "contents");
+    let source = fixture.source().join("file.txt");
+    let (accessed, modified) = (FileTime::from_unix_time(1_500_000_000, 0), FileTime::from_unix_time(1_600_000_000, 0));
+    filetime::set_file_times(&source, accessed, modified).unwrap();
+    let mut config = fixture.config();
+    config.preserve_times = vec![xerox::PreservedTime::Mtime, xerox::PreservedTime::Atime, xerox::PreservedTime::Btime];
+
+    run(config);
+
+    let copy = fs::metadata(fixture.target().join("file.txt")).unwrap();
+    assert_eq!(FileTime::from_last_modification_time(&copy), modified);
+    assert_eq!(FileTime::from_last_access_time(&copy), accessed);
+}
+
+#[test]
+fn verifies_copies_through_a_capped_hash_pool() {
+    let fixture = Fixture::new();
+    for i in 0..8 {
+        fixture.add(&format!("sub/{}.txt", i), i.to_string().repeat(1000));
+    }
+    let checksums = fixture.dir.path().join("SHA256SUMS");
+    let mut config = fixture.config();
+    config.verify = true;
+    config.hash_threads = Some(1);
+    config.checksum_manifest = Some(checksums.clone());
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.failed), (8, 0));
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert_eq!(fs::read_to_string(&checksums).unwrap().lines().count(), 8);
+}
+
+#[test]
+fn copies_and_verifies_by_each_copy_method() {
+    let mut methods = vec![xerox::CopyMethod::Auto, xerox::CopyMethod::Buffered];
+    if cfg!(target_os = "linux") {
+        methods.push(xerox::CopyMethod::CopyFileRange);
+    }
+    for method in methods {
+        let fixture = Fixture::new();
+        fixture.add("small.txt", "small");
+        fixture.add("sub/large.bin", vec![7u8; 3 * 1024 * 1024]);
+        let mut config = fixture.config();
+        config.copy_method = method;
+        config.copy_buffer_size = 64 * 1024;
+        config.verify = true;
+
+        let summary = run(config);
+
+        assert_eq!((summary.copied, summary.failed), (2, 0), "{:?}", method);
+        assert_eq!(tree(&fixture.target()), tree(&fixture.source()), "{:?}", method);
+    }
+}
+
+#[test]
+fn moves_files_when_asked() {
+    let fixture = Fixture::new();
+    fixture.add("sub/file.txt", "contents");
+    let mut config = fixture.config();
+    config.move_source = true;
+
+    run(config);
+
+    assert_eq!(fs::read(fixture.target().join("sub/file.txt")).unwrap(), b"contents");
+    assert!(!fixture.source().join("sub/file.txt").exists());
+}
+
+#[test]
+fn fails_files_too_large_for_the_target_without_moving_them() {
+    let fixture = Fixture::new();
+    fixture.add("small.txt", "fits");
+    fixture.add("huge.bin", "");
+    // A sparse file one byte larger than the space left takes no room itself
+    let available = fs2::available_space(fixture.dir.path()).unwrap();
+    let huge = fs::OpenOptions::new().write(true).open(fixture.source().join("huge.bin")).unwrap();
+    if huge.set_len(available + 1).is_err() {
+        return;
+    }
+    let mut config = fixture.config();
+    config.ignore_space = true;
+    config.move_source = true;
+
+    let summary = run(config);
+
+    assert_eq!(summary.copied, 1);
+    assert_eq!(summary.failed, 1);
+    assert!(summary.failures.contains_key("no room on target"));
+    assert!(fixture.source().join("huge.bin").exists());
+    assert!(!fixture.target().join("huge.bin").exists());
+}
+
+#[test]
+fn stops_copying_at_the_free_space_margin() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("b.txt", "b");
+    let mut config = fixture.config();
+    // More than any disk has, so the very first copy would eat into it
+    config.min_free_space = Some(u64::MAX / 2);
+    config.ignore_space = true;
+
+    let summary = run(config);
+
+    assert_eq!((summary.copied, summary.failed, summary.skipped_for_space), (0, 0, 2));
+    assert_eq!(summary.status(), RunStatus::Skipped);
+    assert!(summary.targets.values().all(|counts| counts.free_space.is_some()));
+    assert!(tree(&fixture.target()).is_empty());
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let fixture = Fixture::new();
+    fixture.add("sub/file.txt", "contents");
+    let mut config = fixture.config();
+    config.dry_run = true;
+
+    let summary = run(config);
+
+    assert_eq!(summary.copied, 1);
+    assert!(!fixture.target().exists());
+}
+
+#[test]
+fn refuses_a_target_inside_the_source() {
+    let fixture = Fixture::new();
+    fixture.add("file.txt", "contents");
+    let config = quiet(Config::new(fixture.source(), fixture.source().join("backup")));
+
+    let result = Migrator::new(config).unwrap().run();
+
+    assert!(matches!(result, Err(MigrateError::OverlappingRoots { .. })));
+}
+
+#[test]
+fn processes_only_the_first_files_up_to_the_limit() {
+    let fixture = Fixture::new();
+    fixture.add("small.txt", "x");
+    fixture.add("sub/large.txt", "x".repeat(300));
+    fixture.add("medium.txt", "x".repeat(200));
+    fixture.add("sub/tiny.txt", "");
+    let mut config = fixture.config();
+    config.order = xerox::FileOrder::Largest;
+    config.max_files = Some(2);
+
+    let summary = run(config);
+
+    assert_eq!(summary.copied, 2);
+    assert_eq!(summary.over_limit, 2);
+    let copied: Vec<PathBuf> = tree(&fixture.target()).into_keys().collect();
+    assert_eq!(copied, [PathBuf::from("medium.txt"), PathBuf::from("sub/large.txt")]);
+}
+
+// Read back with the system's tar, which every Unix has
+#[cfg(unix)]
+#[test]
+fn packs_the_source_into_one_archive() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("sub/b.txt", "b".repeat(100_000));
+    fixture.add("empty/.keep", "");
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    filetime::set_file_mtime(fixture.source().join("a.txt"), FileTime::from_system_time(modified)).unwrap();
+
+    let archive = fixture.dir.path().join("backup.tar.gz");
+    let mut config = quiet(Config::new(fixture.source(), archive.clone()));
+    let summary = Migrator::new(config.clone()).unwrap().archive(ArchiveFormat::TarGz).unwrap();
+    assert_eq!(summary.copied, 3);
+    assert!(fs::metadata(&archive).unwrap().len() < 10_000);
+
+    let extracted = fixture.dir.path().join("extracted");
+    fs::create_dir(&extracted).unwrap();
+    let status = std::process::Command::new("tar").arg("-xzf").arg(&archive).arg("-C").arg(&extracted).status().unwrap();
+    assert!(status.success());
+    assert_eq!(tree(&extracted), tree(&fixture.source()));
+    assert_eq!(fs::metadata(extracted.join("a.txt")).unwrap().modified().unwrap(), modified);
+
+    // An existing archive is only replaced when asked to
+    let result = Migrator::new(config.clone()).unwrap().archive(ArchiveFormat::TarGz);
+    assert!(matches!(result, Err(MigrateError::TargetNotWritable { .. })));
+    config.on_conflict = ConflictPolicy::Overwrite;
+    assert_eq!(Migrator::new(config).unwrap().archive(ArchiveFormat::TarGz).unwrap().copied, 3);
+}
+
+#[test]
+fn packs_the_source_into_a_zip() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("sub/b.txt", "b".repeat(100_000));
+    fixture.add("empty/.keep", "");
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    filetime::set_file_mtime(fixture.source().join("a.txt"), FileTime::from_system_time(modified)).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(fixture.source().join("a.txt"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let archive = fixture.dir.path().join("backup.zip");
+    let config = quiet(Config::new(fixture.source(), archive.clone()));
+    let summary = Migrator::new(config).unwrap().archive(ArchiveFormat::Zip).unwrap();
+    assert_eq!(summary.copied, 3);
+
+    let mut zip = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+    let mut names: Vec<String> = zip.file_names().map(|name| name.unwrap().into_owned()).collect();
+    names.sort();
+    assert_eq!(names, ["a.txt", "empty/", "empty/.keep", "sub/", "sub/b.txt"]);
+    for (name, contents) in tree(&fixture.source()) {
+        let mut file = zip.by_name(&name.to_string_lossy().replace('\\', "/")).unwrap();
+        let mut archived = Vec::new();
+        file.read_to_end(&mut archived).unwrap();
+        assert_eq!(archived, contents);
+    }
+
+    let file = zip.by_name("a.txt").unwrap();
+    // 2020-09-13T12:26:40Z, to the two seconds MS-DOS times hold
+    let time = file.last_modified().unwrap();
+    assert_eq!((time.year(), time.month(), time.day(), time.hour(), time.minute(), time.second()), (2020, 9, 13, 12, 26, 40));
+    #[cfg(unix)]
+    assert_eq!(file.unix_mode(), Some(0o100755));
+    drop(file);
+    assert!(zip.by_name("sub/").unwrap().is_dir());
+}
+
+// Names Windows refuses can only be made in a Unix source
+#[cfg(unix)]
+#[test]
+fn handles_names_windows_filesystems_refuse() {
+    let fixture = Fixture::new();
+    fixture.add("notes: draft.txt", "notes");
+    fixture.add("CON.txt", "con");
+    fixture.add("old./a.txt", "a");
+    fixture.add("fine.txt", "fine");
+    let mut config = fixture.config();
+
+    config.on_invalid_name = xerox::InvalidNamePolicy::Error;
+    let result = Migrator::new(config.clone()).unwrap().run();
+    assert!(matches!(result, Err(MigrateError::InvalidNames { count: 4 })), "{:?}", result.err());
+    assert!(tree(&fixture.target()).is_empty());
+
+    config.on_invalid_name = xerox::InvalidNamePolicy::Skip;
+    let summary = run(config.clone());
+    assert_eq!(summary.skipped_invalid, 3);
+    assert_eq!(summary.status(), RunStatus::Skipped);
+    assert_eq!(tree(&fixture.target()).into_keys().collect::<Vec<_>>(), vec![PathBuf::from("fine.txt")]);
+
+    fs::remove_dir_all(fixture.target()).unwrap();
+    config.on_invalid_name = xerox::InvalidNamePolicy::Sanitize;
+    let summary = run(config);
+    assert_eq!(summary.sanitized, 3);
+    assert_eq!(summary.copied, 4);
+    let copied: Vec<PathBuf> = tree(&fixture.target()).into_keys().collect();
+    assert_eq!(copied, ["CON_.txt", "fine.txt", "notes_ draft.txt", "old_/a.txt"].map(PathBuf::from));
+}
+
+#[cfg(feature = "simulate-stubs")]
+#[test]
+fn retries_stubs_that_are_still_locked() {
+    let fixture = Fixture::new();
+    fixture.add("slow.bin", "downloaded");
+    fixture.add("fast.txt", "local");
+    let mut config = fixture.config();
+    config.retry.base_delay = Duration::from_millis(1);
+    config.simulate_stubs = Some(xerox::SimulatedStubs {
+        latency: Duration::from_millis(10),
+        lock_failures: 2,
+        files: vec!["*.bin".to_string()],
+    });
+
+    let summary = run(config);
+
+    assert_eq!(tree(&fixture.target()), tree(&fixture.source()));
+    assert_eq!(summary.stubs_fetched, 1);
+    assert_eq!(summary.failed, 0);
+    assert_eq!(summary.retried, 1);
+    assert_eq!(summary.max_attempts, 3);
+}
+
+#[cfg(feature = "simulate-stubs")]
+#[test]
+fn gives_up_on_stubs_that_stay_locked() {
+    let fixture = Fixture::new();
+    fixture.add("stuck.bin", "never");
+    let mut config = fixture.config();
+    config.retry.max_retries = 2;
+    config.retry.base_delay = Duration::from_millis(1);
+    config.simulate_stubs = Some(xerox::SimulatedStubs {
+        latency: Duration::ZERO,
+        lock_failures: 3,
+        files: Vec::new(),
+    });
+
+    let summary = run(config);
+
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.max_attempts, 3);
+    assert!(!fixture.target().join("stuck.bin").exists());
+}
+
+#[cfg(feature = "simulate-stubs")]
+#[test]
+fn hydrates_stubs_without_copying() {
+    let fixture = Fixture::new();
+    fixture.add("photos/a.bin", "downloaded");
+    fixture.add("photos/b.bin", "downloaded");
+    fixture.add("notes.txt", "local");
+    let mut config = fixture.config();
+    config.stub_hydrate_only = true;
+    config.retry.base_delay = Duration::from_millis(1);
+    config.simulate_stubs = Some(xerox::SimulatedStubs {
+        latency: Duration::ZERO,
+        lock_failures: 1,
+        files: vec!["*.bin".to_string()],
+    });
+
+    let hydration = Migrator::new(config).unwrap().hydrate().unwrap();
+
+    assert_eq!((hydration.fetched, hydration.local, hydration.retried), (2, 1, 2));
+    assert!(hydration.failed.is_empty());
+    assert_eq!(hydration.status(), RunStatus::Complete);
+    assert!(!fixture.target().exists());
+}
+
+// Stubs are told apart by size outside Windows, which also leaves their cloud size unknown
+#[cfg(not(windows))]
+#[test]
+fn probes_stubs_without_opening_them() {
+    let fixture = Fixture::new();
+    fixture.add("photos/a.bin", "x");
+    fixture.add("photos/b.bin", "y");
+    fixture.add("notes.txt", "hydrated");
+    let mut config = fixture.config();
+    config.probe = true;
+    config.stub_max_size = 1;
+
+    let probe = Migrator::new(config).unwrap().probe().unwrap();
+
+    assert_eq!((probe.stubs, probe.hydrated), (2, 1));
+    assert_eq!((probe.stub_bytes, probe.unsized_stubs, probe.hydrated_bytes), (0, 2, 8));
+    assert!(!fixture.target().exists());
+}
+
+// Function to write an executable shell script for --post-copy-command
+#[cfg(unix)]
+fn script(path: &Path, body: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    write(path, format!("#!/bin/sh\n{}\n", body));
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn runs_the_post_copy_command_for_each_copy() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    fixture.add("sub/b.txt", "b");
+    let hook = fixture.dir.path().join("hook.sh");
+    let log = fixture.dir.path().join("hook.log");
+    script(&hook, &format!("echo \"$XEROX_DESTINATION\" >> {:?}\ntest -f \"$2\"", log));
+    let mut config = fixture.config();
+    config.post_copy_command = Some(hook);
+
+    let summary = run(config);
+
+    assert_eq!(summary.failed, 0);
+    let mut logged: Vec<String> = fs::read_to_string(&log).unwrap().lines().map(str::to_string).collect();
+    logged.sort();
+    let expected = [fixture.target().join("a.txt"), fixture.target().join("sub/b.txt")];
+    assert_eq!(logged, expected.map(|path| path.to_string_lossy().into_owned()));
+}
+
+#[cfg(unix)]
+#[test]
+fn fails_files_whose_hook_fails_only_when_fatal() {
+    let fixture = Fixture::new();
+    fixture.add("a.txt", "a");
+    let hook = fixture.dir.path().join("hook.sh");
+    script(&hook, "exit 3");
+    let mut config = fixture.config();
+    config.post_copy_command = Some(hook);
+    config.move_source = true;
+
+    let summary = run(config.clone());
+    assert_eq!(summary.failed, 0);
+    assert!(!fixture.source().join("a.txt").exists());
+
+    fixture.add("b.txt", "b");
+    config.hook_fatal = true;
+    let summary = run(config);
+    assert_eq!(summary.failed, 1);
+    assert!(summary.failures.contains_key("post-copy command failed"));
+    assert!(fixture.source().join("b.txt").exists());
+}